@@ -0,0 +1,222 @@
+//! Benchmarks of the paths every credential request goes through. KeePassXC is stood in for by a
+//! mock server on a Unix socket, which answers get-logins with a fixed set of entries.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use git_credential_keepassxc::git::{
+    split_url_username, strip_credential_url, GitCredentialMessage,
+};
+use std::str::FromStr;
+#[cfg(unix)]
+use {
+    anyhow::{anyhow, Result},
+    crypto_box::{aead::Aead, PublicKey, SalsaBox, SecretKey},
+    git_credential_keepassxc::backend::SecretBackend,
+    git_credential_keepassxc::config::Config,
+    git_credential_keepassxc::keepassxc::messages::{
+        CipherTextRequest, GetLoginsRequest, LoginEntry,
+    },
+    git_credential_keepassxc::logins::{find_login, LookupOptions},
+    git_credential_keepassxc::session::{
+        nacl_nonce, set_socket_path, start_session, to_decrypted_json, to_encrypted_json,
+    },
+    std::io::Write,
+    std::os::unix::net::UnixListener,
+    std::path::PathBuf,
+    std::thread,
+};
+
+const GIT_REQUEST: &str = "protocol=https\nhost=git.example.com\npath=group/project.git\nusername=alice\nurl=https://alice@git.example.com/group/project.git\n\n";
+
+fn git_messages(c: &mut Criterion) {
+    c.bench_function("parse credential request", |b| {
+        b.iter(|| GitCredentialMessage::from_str(black_box(GIT_REQUEST)).unwrap())
+    });
+    let mut git_resp = GitCredentialMessage::from_str(GIT_REQUEST).unwrap();
+    git_resp.password = Some("correct horse battery staple".to_owned());
+    c.bench_function("format credential response", |b| {
+        b.iter(|| black_box(&git_resp).to_string())
+    });
+    c.bench_function("normalise request URL", |b| {
+        b.iter(|| {
+            let (url, _) =
+                split_url_username(black_box("https://alice@git.example.com/group/project.git"))
+                    .unwrap();
+            strip_credential_url(&url::Url::parse(&url).unwrap()).unwrap()
+        })
+    });
+}
+
+/// A get-logins response of `count` entries, each limited to one of 10 repository groups
+#[cfg(unix)]
+fn get_logins_response(count: usize) -> String {
+    let entries: Vec<_> = (0..count)
+        .map(|i| {
+            serde_json::json!({
+                "login": format!("user{}", i),
+                "name": format!("git.example.com ({})", i),
+                "password": "correct horse battery staple",
+                "uuid": format!("{:032x}", i),
+                "group": "Git",
+                "stringFields": [{"KPH: git-repo": format!("group{}", i % 10)}],
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "action": "get-logins",
+        "count": entries.len(),
+        "entries": entries,
+        "hash": "29234e32274a32276e25666a42",
+        "nonce": "tZvLrBzkQ9GxXq9PvKJE4iO3n/vx4o6w",
+        "success": "true",
+        "version": "2.7.6",
+    })
+    .to_string()
+}
+
+/// Starts a mock KeePassXC on a new socket, which exchanges keys and answers every get-logins
+/// request with `count` entries
+#[cfg(unix)]
+fn mock_keepassxc(count: usize) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "git-credential-keepassxc-bench-{}.socket",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let response = get_logins_response(count);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let host_key = SecretKey::generate(&mut rand::thread_rng());
+            let mut host_box = None;
+            let requests =
+                serde_json::Deserializer::from_reader(&stream).into_iter::<serde_json::Value>();
+            for request in requests {
+                let request = request.unwrap();
+                let reply = match request["action"].as_str().unwrap() {
+                    "change-public-keys" => {
+                        let mut client_key = [0u8; crypto_box::KEY_SIZE];
+                        client_key.copy_from_slice(
+                            &base64::decode(request["publicKey"].as_str().unwrap()).unwrap(),
+                        );
+                        host_box = Some(SalsaBox::new(&PublicKey::from(client_key), &host_key));
+                        serde_json::json!({
+                            "action": "change-public-keys",
+                            "publicKey": base64::encode(host_key.public_key().as_bytes()),
+                            "version": "2.7.6",
+                            "success": "true",
+                        })
+                    }
+                    "get-logins" => {
+                        let nonce = crypto_box::generate_nonce(&mut rand::thread_rng());
+                        let message = host_box
+                            .as_ref()
+                            .unwrap()
+                            .encrypt(&nonce, response.as_bytes())
+                            .unwrap();
+                        serde_json::json!({
+                            "action": "get-logins",
+                            "message": base64::encode(&message),
+                            "nonce": base64::encode(&nonce),
+                        })
+                    }
+                    action => panic!("Unexpected {} request", action),
+                };
+                writer.write_all(reply.to_string().as_bytes()).unwrap();
+            }
+        }
+    });
+    path
+}
+
+/// Looks up logins from the mock KeePassXC, as KeePassXCBackend does without the associations
+#[cfg(unix)]
+struct MockSocketBackend {
+    client_id: String,
+}
+
+#[cfg(unix)]
+impl SecretBackend for MockSocketBackend {
+    fn get_logins(&self, url: &str) -> Result<Vec<LoginEntry>> {
+        let request = GetLoginsRequest::new(url, None, None, &[("bench", "bench")]);
+        Ok(request.send(self.client_id.as_str(), false)?.entries)
+    }
+
+    fn store_login(
+        &self,
+        _url: &str,
+        _username: &str,
+        _password: &str,
+        _password_expiry_utc: Option<u64>,
+        _existing: Option<&LoginEntry>,
+    ) -> Result<()> {
+        Err(anyhow!("Not supported by the mock KeePassXC"))
+    }
+
+    fn revoke_login(&self, _url: &str, _login: &LoginEntry, _group: &str) -> Result<()> {
+        Err(anyhow!("Not supported by the mock KeePassXC"))
+    }
+
+    fn get_group_uuid(&self, _path: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn get_totp(&self, _login: &LoginEntry) -> Result<String> {
+        Err(anyhow!("Not supported by the mock KeePassXC"))
+    }
+
+    fn delete_login(&self, _login: &LoginEntry) -> Result<()> {
+        Err(anyhow!("Not supported by the mock KeePassXC"))
+    }
+}
+
+/// Encryption of messages with the session keys, and the whole get request against the mock
+/// KeePassXC
+#[cfg(unix)]
+fn keepassxc_messages(c: &mut Criterion) {
+    const LOGINS: usize = 500;
+    set_socket_path(mock_keepassxc(LOGINS));
+    let (client_id, _, _) = start_session().unwrap();
+
+    let json: serde_json::Value = serde_json::from_str(&get_logins_response(8)).unwrap();
+    let (nonce, nonce_b64) = nacl_nonce();
+    let encrypted = to_encrypted_json(&json, &nonce).unwrap();
+    c.bench_function("encrypt get-logins response", |b| {
+        b.iter(|| to_encrypted_json(black_box(&json), &nonce).unwrap())
+    });
+    c.bench_function("decrypt get-logins response", |b| {
+        b.iter(|| {
+            let json = to_decrypted_json(black_box(encrypted.as_str()), nonce_b64.as_str());
+            serde_json::from_str::<serde_json::Value>(&json.unwrap()).unwrap()
+        })
+    });
+
+    let backend = MockSocketBackend { client_id };
+    let config = Config::new();
+    let options = LookupOptions::default();
+    let request = format!(
+        "protocol=https\nhost=git.example.com\npath=group3/repo.git\nusername=user{}\n",
+        LOGINS - 7
+    );
+    c.bench_function("find login among 500 from socket", |b| {
+        b.iter(|| {
+            let git_req = GitCredentialMessage::from_str(black_box(&request)).unwrap();
+            let (git_resp, _) = find_login(
+                &config,
+                &options,
+                &backend,
+                git_req,
+                "https://git.example.com/group3/repo.git",
+                false,
+            )
+            .unwrap();
+            assert!(git_resp.password.is_some());
+        })
+    });
+}
+
+#[cfg(unix)]
+criterion_group!(benches, git_messages, keepassxc_messages);
+#[cfg(not(unix))]
+criterion_group!(benches, git_messages);
+criterion_main!(benches);
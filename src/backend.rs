@@ -0,0 +1,57 @@
+mod keepassxc;
+
+use crate::cli::UnlockOptions;
+use crate::config::Config;
+use crate::keepassxc::messages::LoginEntry;
+use anyhow::Result;
+
+pub use self::keepassxc::KeePassXCBackend;
+
+/// Storage that logins are looked up from and saved to
+///
+/// Backend-agnostic logic, e.g. KPH filtering and matching usernames, stays in the main flow, so
+/// that an implementation only needs to talk to the underlying password manager.
+pub trait SecretBackend {
+    /// Returns all unexpired logins matching the URL
+    fn get_logins(&self, url: &str) -> Result<Vec<LoginEntry>>;
+
+    /// Creates a new login, or updates `existing` which was previously returned by `get_logins`
+    ///
+    /// Updates must only change the username and password, and leave everything else of the entry
+    /// (e.g. notes, string fields, icon and additional URLs) as is.
+    ///
+    /// `password_expiry_utc` is the Unix timestamp after which the password expires, if known.
+    fn store_login(
+        &self,
+        url: &str,
+        username: &str,
+        password: &str,
+        password_expiry_utc: Option<u64>,
+        existing: Option<&LoginEntry>,
+    ) -> Result<()>;
+
+    /// Copies a rejected login into the group (created if necessary) for review
+    ///
+    /// Existing entries can't be moved, as only the username and password of an entry may be
+    /// updated, so the original is left as is and the main flow skips it instead.
+    fn revoke_login(&self, url: &str, login: &LoginEntry, group: &str) -> Result<()>;
+
+    /// Returns the UUID of the group at the path, e.g. `Git/Revoked`, or None if there's no such
+    /// group
+    fn get_group_uuid(&self, path: &str) -> Result<Option<String>>;
+
+    /// Returns the current TOTP code of the entry a login was returned from
+    fn get_totp(&self, login: &LoginEntry) -> Result<String>;
+
+    /// Deletes the entry a login was returned from, which the password manager may ask the user
+    /// to confirm
+    fn delete_login(&self, login: &LoginEntry) -> Result<()>;
+}
+
+/// Opens the backend in use, which is currently always KeePassXC
+pub fn open_backend<'a>(
+    config: &'a Config,
+    unlock_options: &'a Option<UnlockOptions>,
+) -> Result<Box<dyn SecretBackend + 'a>> {
+    Ok(Box::new(KeePassXCBackend::new(config, unlock_options)?))
+}
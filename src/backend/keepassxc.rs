@@ -0,0 +1,402 @@
+use super::SecretBackend;
+use crate::cli::UnlockOptions;
+use crate::config::Config;
+use crate::git::{get_url_host, render_url_template};
+use crate::keepassxc::{errors::KeePassError, messages::*, Group};
+use crate::otlp;
+use crate::session::{
+    associated_databases, find_group, get_root_group, require_keepassxc_version, start_session,
+    validate_group, validate_group_rule, DELETE_ENTRY_MIN_VERSION, GET_TOTP_MIN_VERSION,
+};
+use crate::utils::{explain, CandidateLimit};
+use crate::{detail, error, info, warn};
+use anyhow::{anyhow, Result};
+use std::cell::{Ref, RefCell};
+use std::collections::HashMap;
+
+/// Talks to KeePassXC via its browser integration socket
+pub struct KeePassXCBackend<'a> {
+    config: &'a Config,
+    unlock_options: &'a Option<UnlockOptions>,
+    client_id: String,
+    /// Group tree, which every get needs if revoked_group is set
+    root_group: RefCell<Option<Group>>,
+}
+
+impl<'a> KeePassXCBackend<'a> {
+    pub fn new(config: &'a Config, unlock_options: &'a Option<UnlockOptions>) -> Result<Self> {
+        // start session
+        let (client_id, _, _) = start_session()?;
+        Ok(Self {
+            config,
+            unlock_options,
+            client_id,
+            root_group: RefCell::new(None),
+        })
+    }
+
+    /// The group tree, which is only requested again after groups may have been created
+    fn root_group(&self) -> Result<Ref<'_, Group>> {
+        if self.root_group.borrow().is_none() {
+            *self.root_group.borrow_mut() = Some(get_root_group(&self.client_id)?);
+        }
+        Ok(Ref::map(self.root_group.borrow(), |group| {
+            group.as_ref().unwrap()
+        }))
+    }
+
+    fn send_set_login(&self, sl_req: SetLoginRequest) -> Result<()> {
+        let sl_resp = sl_req.send(&self.client_id, false)?;
+        if let Some(success) = sl_resp.success {
+            // wtf?!?!
+            if success.0
+                && (sl_resp.error.is_none()
+                    || sl_resp.error.as_ref().unwrap().is_empty()
+                    || sl_resp.error.as_ref().unwrap() == "success")
+            {
+                Ok(())
+            } else {
+                error!(
+                    "Failed to store login. Error: {}, Error Code: {}",
+                    sl_resp.error.unwrap_or_else(|| "N/A".to_owned()),
+                    sl_resp.error_code.unwrap_or_else(|| "N/A".to_owned())
+                );
+                Err(anyhow!("Failed to store login"))
+            }
+        } else {
+            error!("Set login request failed");
+            Err(anyhow!("Set login request failed"))
+        }
+    }
+}
+
+/// Keeps the logins of each username from a single database, i.e. the first one as databases are
+/// sorted by priority, and warns about the others
+fn dedup_databases(entries: Vec<LoginEntry>) -> Vec<LoginEntry> {
+    let mut databases: Vec<(&str, Vec<&str>)> = Vec::new();
+    for entry in &entries {
+        let database = entry.database.as_deref().unwrap_or_default();
+        match databases
+            .iter_mut()
+            .find(|(login, _)| *login == entry.login)
+        {
+            Some((_, names)) if !names.contains(&database) => names.push(database),
+            Some(_) => {}
+            None => databases.push((&entry.login, vec![database])),
+        }
+    }
+    let winners: HashMap<String, String> = databases
+        .into_iter()
+        .map(|(login, names)| {
+            if names.len() > 1 {
+                warn!(
+                    "The same login is in databases {}, using the one in {} (see database_priority in configuration file)",
+                    names.join(", "),
+                    names[0]
+                );
+                detail!("Login {} is in databases {}", login, names.join(", "));
+            }
+            (login.to_owned(), names[0].to_owned())
+        })
+        .collect();
+    entries
+        .into_iter()
+        .filter(|entry| {
+            let winner = &winners[&entry.login];
+            let keep = entry.database.as_deref().unwrap_or_default() == winner;
+            if !keep {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): also in database {} of higher priority",
+                    entry.login, entry.name, entry.uuid, winner
+                ));
+            }
+            keep
+        })
+        .collect()
+}
+
+/// Sets the group UUID of the entries whose group name is unique in the tree, as KeePassXC only
+/// tells the name, and warns if that leaves entries which may be in the revoked group
+fn set_group_uuids(entries: &mut [LoginEntry], root_group: &Group, revoked_group: &str) {
+    let mut groups = vec![root_group];
+    groups.extend(
+        root_group
+            .get_descendant_paths()
+            .into_iter()
+            .map(|(_, group)| group),
+    );
+    let revoked_uuid = root_group
+        .find_by_path(revoked_group)
+        .map(|group| group.uuid.as_str());
+    let mut ambiguous = false;
+    for entry in entries {
+        let matching: Vec<_> = groups
+            .iter()
+            .filter(|group| entry.group.as_ref() == Some(&group.name))
+            .collect();
+        entry.group_uuid = match matching.as_slice() {
+            [group] => Some(group.uuid.clone()),
+            _ => {
+                if matching
+                    .iter()
+                    .any(|group| Some(group.uuid.as_str()) == revoked_uuid)
+                {
+                    ambiguous = true;
+                    detail!(
+                        "Login {} of entry {} ({}) may be revoked",
+                        entry.login,
+                        entry.name,
+                        entry.uuid
+                    );
+                }
+                None
+            }
+        };
+    }
+    if ambiguous {
+        warn!(
+            "Other groups have the same name as {}, so logins revoked there can't be told apart and are still used, please rename them",
+            revoked_group
+        );
+    }
+}
+
+impl<'a> SecretBackend for KeePassXCBackend<'a> {
+    /// Returns all entries from KeePassXC except for expired ones (which are not returned by
+    /// KeePassXC actually, but better to be safe than sorry)
+    fn get_logins(&self, url: &str) -> Result<Vec<LoginEntry>> {
+        otlp::in_span("get-logins", || {
+            let mut databases =
+                associated_databases(self.config, &self.client_id, self.unlock_options)?;
+            // stable, so configuration order breaks ties
+            databases.sort_by_key(|d| self.config.get_database_priority(d));
+
+            // ask KeePassXC for logins, database by database as the response doesn't tell which
+            // one an entry is from
+            let mut entries = Vec::new();
+            let mut no_logins_error = None;
+            let limit = CandidateLimit::get();
+            // including the ones skipped due to the limit
+            let mut total = 0;
+            for database in &databases {
+                // lower priority databases rank below anyway, unless they have the only logins of
+                // the username
+                if limit.as_ref().is_some_and(|limit| {
+                    entries.len() >= limit.max
+                        && limit.username.as_ref().is_none_or(|username| {
+                            entries
+                                .iter()
+                                .any(|entry: &LoginEntry| entry.login == *username)
+                        })
+                }) {
+                    info!("Maximum number of candidates is reached, skipping remaining databases");
+                    break;
+                }
+                let gl_req = GetLoginsRequest::new(
+                    url,
+                    None,
+                    None,
+                    &[(database.id.as_str(), database.pkey.as_str())],
+                );
+                let gl_resp = match gl_req.send(&self.client_id, false) {
+                    Ok(gl_resp) => gl_resp,
+                    Err(e)
+                        if e.downcast_ref::<KeePassError>()
+                            .map(KeePassError::is_no_logins_found)
+                            .unwrap_or(false) =>
+                    {
+                        no_logins_error = Some(e);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let name = database.label.as_ref().unwrap_or(&database.id);
+                total += std::cmp::max(gl_resp.count, gl_resp.entries.len());
+                entries.extend(gl_resp.entries.into_iter().map(|mut entry| {
+                    entry.database = Some(name.clone());
+                    entry.database_id = Some(database.id.clone());
+                    entry
+                }));
+            }
+            match no_logins_error {
+                Some(e) if entries.is_empty() => return Err(e),
+                _ => {}
+            }
+            if total > entries.len() {
+                warn!(
+                    "KeePassXC has at least {} matching logins, only {} of them are considered, see max_candidates in configuration file",
+                    total,
+                    entries.len()
+                );
+            }
+
+            explain(format!(
+                "KeePassXC returned {} login(s) for {}",
+                entries.len(),
+                url
+            ));
+            // only needed to tell revoked logins, which costs another request
+            if let Some(revoked_group) = self.config.get_revoked_group() {
+                match self.root_group() {
+                    Ok(root_group) => set_group_uuids(&mut entries, &root_group, revoked_group),
+                    Err(e) => {
+                        warn!("Failed to look up groups of the logins, {}", e);
+                    }
+                }
+            }
+            let login_entries: Vec<_> = dedup_databases(entries)
+                .into_iter()
+                .filter(|e| {
+                    let expired = e.expired.is_some() && e.expired.as_ref().unwrap().0;
+                    if expired {
+                        explain(format!(
+                            "Dropped login {} of entry {} ({}): entry has expired",
+                            e.login, e.name, e.uuid
+                        ));
+                    }
+                    !expired
+                })
+                .collect();
+            info!("KeePassXC return {} login(s)", login_entries.len());
+            Ok(login_entries)
+        })
+    }
+
+    fn store_login(
+        &self,
+        url: &str,
+        username: &str,
+        password: &str,
+        password_expiry_utc: Option<u64>,
+        existing: Option<&LoginEntry>,
+    ) -> Result<()> {
+        otlp::in_span("store", || {
+            if let Some(expiry) = password_expiry_utc {
+                // set-login has no field for the expiry time
+                warn!(
+                    "KeePassXC doesn't allow setting expiry time via socket at the time of writing, please set it to {} (Unix timestamp) manually",
+                    expiry
+                );
+            }
+            let databases = self.config.get_databases()?;
+            let database = match existing {
+                Some(existing) => self
+                    .config
+                    .get_entry_database(&databases, existing.database_id.as_deref())?,
+                None => self.config.get_target_database(&databases)?,
+            };
+            // KeePassXC only updates the username and password of an existing entry, so only new
+            // entries need the group (which may have to be created)
+            let group = if existing.is_some() {
+                None
+            } else if let Some(rule) = self.config.get_group_rule(&get_url_host(url)) {
+                info!("Group is mapped by configuration file");
+                detail!("New login of {} goes to group {}", rule.host, rule.group);
+                Some(validate_group_rule(
+                    &self.client_id,
+                    rule,
+                    self.config.is_create_group(),
+                )?)
+            } else {
+                Some(validate_group(
+                    &self.client_id,
+                    database,
+                    self.config.is_create_group(),
+                )?)
+            };
+            // the group may have been created
+            if group.is_some() {
+                self.root_group.replace(None);
+            }
+            // only new entries get the URL, KeePassXC doesn't update it
+            let entry_url = match self.config.get_new_entry_url() {
+                Some(template) if existing.is_none() => render_url_template(template, url)?,
+                _ => url.to_owned(),
+            };
+            let sl_req = SetLoginRequest::new(
+                entry_url.as_str(),
+                entry_url.as_str(),
+                database.id.as_str(),
+                username,
+                password,
+                group.as_ref().map(|g| g.name.as_str()),
+                group.as_ref().map(|g| g.uuid.as_str()),
+                existing.map(|e| e.uuid.as_str()),
+            );
+            self.send_set_login(sl_req)
+        })
+    }
+
+    fn revoke_login(&self, url: &str, login: &LoginEntry, group: &str) -> Result<()> {
+        otlp::in_span("revoke", || {
+            let databases = self.config.get_databases()?;
+            // the copy stays in the database of the login, not where new logins go
+            let database = self
+                .config
+                .get_entry_database(&databases, login.database_id.as_deref())?;
+            let group = find_group(&self.client_id, group, true)?;
+            self.root_group.replace(None);
+            let sl_req = SetLoginRequest::new(
+                url,
+                url,
+                database.id.as_str(),
+                login.login.as_str(),
+                login.password.as_str(),
+                Some(group.name.as_str()),
+                Some(group.uuid.as_str()),
+                None,
+            );
+            self.send_set_login(sl_req)
+        })
+    }
+
+    fn get_group_uuid(&self, path: &str) -> Result<Option<String>> {
+        Ok(self
+            .root_group()?
+            .find_by_path(path)
+            .map(|group| group.uuid.clone()))
+    }
+
+    fn get_totp(&self, login: &LoginEntry) -> Result<String> {
+        otlp::in_span("get-totp", || {
+            require_keepassxc_version("TOTP", GET_TOTP_MIN_VERSION)?;
+            let gt_req = GetTotpRequest::new(login.uuid.as_str());
+            let gt_resp = gt_req.send(&self.client_id, false)?;
+            if !gt_resp.success.map(|s| s.0).unwrap_or(false) {
+                error!(
+                    "Failed to get TOTP. Error: {}, Error Code: {}",
+                    gt_resp.error.unwrap_or_else(|| "N/A".to_owned()),
+                    gt_resp.error_code.unwrap_or_else(|| "N/A".to_owned())
+                );
+                return Err(anyhow!("Get TOTP request failed"));
+            }
+            // KeePassXC responds with an empty code when TOTP isn't set up for the entry
+            match gt_resp.totp {
+                Some(totp) if !totp.is_empty() => Ok(totp),
+                _ => Err(anyhow!(
+                    "Entry {} ({}) doesn't have TOTP configured",
+                    login.name,
+                    login.uuid
+                )),
+            }
+        })
+    }
+
+    fn delete_login(&self, login: &LoginEntry) -> Result<()> {
+        otlp::in_span("delete", || {
+            require_keepassxc_version("Erasing logins", DELETE_ENTRY_MIN_VERSION)?;
+            // KeePassXC asks the user to confirm the deletion
+            let de_req = DeleteEntryRequest::new(login.uuid.as_str());
+            let de_resp = de_req.send(&self.client_id, false)?;
+            if !de_resp.success.map(|s| s.0).unwrap_or(false) {
+                error!(
+                    "Failed to delete entry. Error: {}, Error Code: {}",
+                    de_resp.error.unwrap_or_else(|| "N/A".to_owned()),
+                    de_resp.error_code.unwrap_or_else(|| "N/A".to_owned())
+                );
+                return Err(anyhow!("Delete entry request failed"));
+            }
+            Ok(())
+        })
+    }
+}
@@ -1,7 +1,10 @@
 use anyhow::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
 pub struct UnlockOptions {
     pub max_retries: usize,
     pub interval: u64,
@@ -0,0 +1,1018 @@
+//! Subcommands of `git-credential-keepassxc`, which the binary parses the command line for and
+//! dispatches to
+mod askpass;
+mod configure;
+mod credential;
+mod import;
+mod lookup;
+mod manage;
+
+use crate::cli::UnlockOptions;
+use crate::config::{Caller, Config};
+use crate::errors::{ErrorReport, HelperError};
+use crate::git::{normalize_url_host, split_url_username, GitCredentialMessage, MessageReader};
+use crate::keepassxc::errors::*;
+#[cfg(all(target_os = "linux", feature = "secret-service"))]
+use crate::secret_service;
+use crate::utils::*;
+use crate::{debug, detail, error, info, otlp, update, warn, DETAIL_LOGGER, LOGGER};
+use anyhow::{anyhow, Context, Error, Result};
+use clap::ArgMatches;
+use serde::Serialize;
+use slog::{Drain, Level, Logger};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub use self::askpass::{rclone_pass, ssh_askpass, sudo_askpass};
+pub use self::configure::{configure, decrypt, encrypt, encryption};
+pub use self::credential::{erase_login, get_logins, store_login};
+pub use self::import::{export, import};
+pub use self::lookup::{get_totp, get_url, request_autotype};
+pub use self::manage::{caller, check, config_command, db, groups, socket, status};
+
+fn read_git_request(stdin_timeout: Option<Duration>) -> Result<(GitCredentialMessage, String)> {
+    let git_req_string = MessageReader::new(io::BufReader::new(io::stdin()))
+        .read_message(stdin_timeout)?
+        .ok_or_else(|| anyhow!("No credential request received on stdin"))?;
+    parse_git_request(&git_req_string)
+}
+
+fn parse_git_request(git_req_string: &str) -> Result<(GitCredentialMessage, String)> {
+    let mut git_req = GitCredentialMessage::from_str(git_req_string)?;
+    debug!("Git credential request: {:?}", git_req);
+    let url = {
+        if let Some(ref url_string) = git_req.url {
+            // username in URL is used as if it were sent separately, see gitcredentials(7)
+            let (url_string, username) = split_url_username(url_string)?;
+            if let Some(username) = username {
+                if git_req.username.is_none() {
+                    git_req.username = Some(username);
+                }
+            }
+            url_string
+        } else if git_req.protocol.as_deref() == Some("cert") {
+            // client certificate passphrase, the path of the certificate is the only hint
+            let path = git_req
+                .path
+                .as_ref()
+                .ok_or_else(|| anyhow!("Path is required for client certificates"))?;
+            format!("cert://{}", path)
+        } else {
+            if git_req.protocol.is_none() || git_req.host.is_none() {
+                return Err(anyhow!(
+                    "Protocol and host are both required when URL is not provided"
+                ));
+            }
+            format!(
+                "{}://{}/{}",
+                git_req.protocol.clone().unwrap(),
+                git_req.host.clone().unwrap(),
+                git_req.path.clone().unwrap_or_else(|| "".to_owned())
+            )
+        }
+    };
+    let url = normalize_url_host(&url);
+    Ok((git_req, url))
+}
+
+/// Prints items as a JSON array if format is json, otherwise one item per line
+fn print_list<T, F>(items: &[T], format: Option<&str>, to_text: F) -> Result<()>
+where
+    T: Serialize,
+    F: Fn(&T) -> String,
+{
+    if format == Some("json") {
+        println!("{}", serde_json::to_string_pretty(items)?);
+    } else {
+        for item in items {
+            println!("{}", to_text(item));
+        }
+    }
+    Ok(())
+}
+
+/// Maximum number of wrapper processes between git-credential-keepassxc and its actual caller
+const MAX_WRAPPER_DEPTH: usize = 8;
+
+fn caller_matches(caller: &Caller, caller_process: &CallerProcess, canonicalize: bool) -> bool {
+    // anyone may start a set-user-ID program under the name of another, so only profiles meant for
+    // it accept the command line, and never with a hash of some file rather than the running image
+    if caller_process.by_command_line && (!caller.setuid || caller.sha256.is_some()) {
+        return false;
+    }
+    let sha256_matches = caller
+        .sha256
+        .as_ref()
+        .map(|sha256| Some(sha256.to_ascii_lowercase()) == caller_process.sha256)
+        .unwrap_or(true);
+    #[cfg(unix)]
+    let ids_match = caller
+        .uid
+        .map(|id| id == caller_process.uid)
+        .unwrap_or(true)
+        && caller
+            .gid
+            .map(|id| id == caller_process.gid)
+            .unwrap_or(true);
+    #[cfg(windows)]
+    let ids_match = true;
+    let path_matches = caller_path_matches(&caller.path, &caller_process.path)
+        || (canonicalize
+            && caller_path_matches(
+                &canonicalize_caller_path(&caller.path).to_string_lossy(),
+                canonicalize_caller_path(&caller_process.path),
+            ));
+    path_matches && ids_match && sha256_matches
+}
+
+fn verify_caller(config: &Config) -> Result<Option<(usize, PathBuf)>> {
+    otlp::in_span("caller check", || check_caller(config, get_caller_process))
+}
+
+/// Verifies the client process of a D-Bus method call the same way as callers
+#[cfg(all(target_os = "linux", feature = "secret-service"))]
+fn verify_client(config: &Config, pid: u32) -> Result<Option<(usize, PathBuf)>> {
+    otlp::in_span("client check", || {
+        check_caller(config, |hash_executable| {
+            get_client_process(pid, hash_executable)
+        })
+    })
+}
+
+/// `get_process` reads the process to verify, hashing its executable if requested
+fn check_caller<F: FnOnce(bool) -> Result<CallerProcess>>(
+    config: &Config,
+    get_process: F,
+) -> Result<Option<(usize, PathBuf)>> {
+    if config.count_callers() == 0 && (!config.is_strict_caller() || config.count_databases() == 0)
+    {
+        info!(
+            "Caller verification skipped as no caller profiles defined and strict-caller disabled"
+        );
+        return Ok(None);
+    }
+    let callers = config.get_callers()?;
+    // only hash the executable when needed
+    let hash_executable = callers.iter().any(|c| c.sha256.is_some());
+    let mut caller_process = get_process(hash_executable)?;
+    let mut depth = 0;
+    let matching_callers = loop {
+        detail!("PPID: {}", caller_process.pid);
+        let ppath = caller_process.path.to_string_lossy().into_owned();
+        detail!("Parent process path: {}", ppath);
+        let matching_callers: Vec<_> = callers
+            .iter()
+            .filter(|caller| {
+                caller_matches(caller, &caller_process, config.is_canonicalize_callers())
+            })
+            .collect();
+        if !matching_callers.iter().any(|caller| caller.wrapper) {
+            break matching_callers;
+        }
+        depth += 1;
+        if depth > MAX_WRAPPER_DEPTH {
+            warn!("Too many wrapper processes, giving up");
+            break Vec::new();
+        }
+        info!("{} is a wrapper, verifying its parent", ppath);
+        caller_process = get_parent_process(&caller_process, hash_executable)?;
+    };
+    let ppath = caller_process.path.to_string_lossy();
+    if matching_callers.is_empty() {
+        #[cfg(unix)]
+        detail!(
+            "Caller {} (UID: {}, GID: {}) doesn't match any caller profiles",
+            ppath,
+            caller_process.uid,
+            caller_process.gid
+        );
+        #[cfg(windows)]
+        detail!("Caller {} doesn't match any caller profiles", ppath);
+        Err(anyhow!("You are not allowed to use this program"))
+    } else {
+        Ok(Some((caller_process.pid, caller_process.path.clone())))
+    }
+}
+
+/// Quotes the text for POSIX shells
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+pub fn self_update(args: &ArgMatches) -> Result<()> {
+    let subcommand = args.subcommand_matches("self-update").unwrap();
+    let current_version = clap::crate_version!();
+    let release = update::get_latest_release()?;
+    let is_newer = update::is_newer(release.version(), current_version);
+    if subcommand.is_present("check") {
+        if is_newer {
+            println!(
+                "{} is available (current {})",
+                release.version(),
+                current_version
+            );
+        } else {
+            println!("{} is up to date", current_version);
+        }
+        return Ok(());
+    }
+    if !is_newer && !subcommand.is_present("force") {
+        info!("{} is up to date", current_version);
+        return Ok(());
+    }
+    let public_key = subcommand
+        .value_of("public-key")
+        .or(update::RELEASE_PUBLIC_KEY)
+        .ok_or_else(|| {
+            anyhow!("This build has no release public key to verify updates, specify one using --public-key")
+        })?;
+    let binary = update::download_verified(&release, public_key)?;
+    let executable = std::env::current_exe()?;
+    update::replace_executable(&executable, &binary)?;
+    info!(
+        "Updated {} from {} to {}",
+        executable.to_string_lossy(),
+        current_version,
+        release.version()
+    );
+    Ok(())
+}
+
+#[cfg(all(target_os = "linux", feature = "secret-service"))]
+pub fn serve<T: AsRef<Path>>(config_path: T, unlock_options: &Option<UnlockOptions>) -> Result<()> {
+    use crate::backend::open_backend;
+
+    let config = Config::read_from(config_path.as_ref())?;
+    if config.count_callers() == 0 {
+        warn!("No caller profiles defined, any process of yours can read logins via D-Bus");
+    }
+    let backend = open_backend(&config, unlock_options)?;
+    secret_service::serve(&config, backend.as_ref(), |pid| {
+        verify_client(&config, pid).map(|_| ())
+    })
+}
+
+#[cfg(not(all(target_os = "linux", feature = "secret-service")))]
+pub fn serve<T: AsRef<Path>>(
+    _config_path: T,
+    _unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    Err(anyhow!(
+        "Secret Service is only supported on Linux with secret-service feature"
+    ))
+}
+
+/// Sets up [`LOGGER`] and [`DETAIL_LOGGER`] for the verbosity and log file given on the command
+/// line
+pub fn init_logging(args: &ArgMatches) -> Result<()> {
+    let verbosity = if args.is_present("trace-protocol") {
+        // traces are logged at INFO level
+        std::cmp::max(args.occurrences_of("verbose"), 2)
+    } else {
+        args.occurrences_of("verbose")
+    };
+    let level = Level::from_usize(std::cmp::min(6, verbosity + 2) as usize).unwrap_or(Level::Error);
+    let decorator = slog_term::TermDecorator::new().build();
+    let drain = slog_term::FullFormat::new(decorator)
+        .build()
+        .filter_level(level)
+        .fuse();
+    let (logger, detail_logger) = if let Some(log_file) = args.value_of("log-file") {
+        let mut file_options = std::fs::OpenOptions::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            file_options.mode(0o600);
+        }
+        let file = file_options
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .with_context(|| format!("Failed to open log file {}", log_file))?;
+        // details are logged at INFO level, so always keep them in the log file
+        let file_level = std::cmp::max_by_key(level, Level::Info, Level::as_usize);
+        let file_decorator = slog_term::PlainSyncDecorator::new(file);
+        let file_drain = std::sync::Arc::new(slog_term::FullFormat::new(file_decorator).build());
+        let detail_logger = Logger::root(
+            file_drain.clone().filter_level(Level::Info).fuse(),
+            slog::o!(),
+        );
+        let file_drain = file_drain.filter_level(file_level).fuse();
+        let drain = std::sync::Mutex::new(slog::Duplicate::new(drain, file_drain)).fuse();
+        (Logger::root(drain, slog::o!()), Some(detail_logger))
+    } else {
+        let drain = std::sync::Mutex::new(drain).fuse();
+        let logger = Logger::root(drain, slog::o!());
+        // without a log file, details are only shown in the most verbose mode
+        let detail_logger = if level.as_usize() >= Level::Debug.as_usize() {
+            Some(logger.clone())
+        } else {
+            None
+        };
+        (logger, detail_logger)
+    };
+    LOGGER
+        .set(logger)
+        .map_err(|_| anyhow!("Failed to initialise logger"))?;
+    if let Some(detail_logger) = detail_logger {
+        DETAIL_LOGGER
+            .set(detail_logger)
+            .map_err(|_| anyhow!("Failed to initialise logger"))?;
+    }
+    Ok(())
+}
+
+/// Applies the options shared by all subcommands, and returns the unlock options to use
+///
+/// Command line arguments take precedence over defaults in the configuration file.
+pub fn set_global_options<T: AsRef<Path>>(
+    args: &ArgMatches,
+    config_path: T,
+) -> Result<Option<UnlockOptions>> {
+    install_signal_handlers();
+
+    if args.is_present("trace-protocol") {
+        info!("Protocol tracing is enabled, secrets are redacted");
+        set_trace_protocol(true);
+    }
+
+    let config = Config::read_from(config_path.as_ref()).ok();
+    if let Some(path) = args.value_of("socket") {
+        info!("Socket path is set to {} by user", path);
+        set_socket_path(expand_path(path)?);
+    } else if let Some(path) = config.as_ref().and_then(|c| c.get_socket()) {
+        info!("Socket path is set to {} in configuration file", path);
+        set_socket_path(expand_path(path)?);
+    };
+    if let Some(version) = args
+        .value_of("min-keepassxc-version")
+        .or_else(|| config.as_ref().and_then(|c| c.get_min_keepassxc_version()))
+    {
+        set_min_keepassxc_version(version.to_owned())?;
+    }
+    if let Some(events) = config.as_ref().and_then(|c| c.get_notifications()) {
+        if !events.is_empty() && cfg!(not(feature = "notification")) {
+            warn!("Notifications are configured but not enabled in this build");
+        }
+        set_notification_events(events.to_vec());
+    }
+    if args.is_present("activate") {
+        if cfg!(all(target_os = "linux", feature = "dbus-activation")) {
+            info!("KeePassXC is activated through D-Bus if it is not running");
+            set_activate_keepassxc(true);
+        } else {
+            warn!("D-Bus activation is not enabled in this build");
+        }
+    }
+
+    if args.is_present("errors-json") {
+        set_errors_json(true);
+    }
+
+    if args.is_present("explain") {
+        set_explain(true);
+    }
+    let unlock_options = {
+        if let Some(unlock_options) = args.value_of("unlock") {
+            info!("Database unlock option is given by user");
+            Some(UnlockOptions::from_str(unlock_options)?)
+        } else if let Some(unlock_options) = config.as_ref().and_then(|c| c.get_unlock()) {
+            info!("Database unlock option is set in configuration file");
+            Some(unlock_options.clone())
+        } else {
+            None
+        }
+    };
+    Ok(unlock_options)
+}
+
+/// Logs the error of a failed subcommand, also as JSON on stderr if requested, and returns the
+/// exit code
+pub fn report_error(e: &Error) -> i32 {
+    let source = e
+        .source()
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| "N/A".to_string());
+    error!("{}, Caused by: {}", e, source);
+    let (exit_code, hint) = match e
+        .downcast_ref::<KeePassError>()
+        .and_then(|e| e.get_error_code())
+    {
+        Some(error_code) => {
+            error!("Hint: {}", error_code.hint());
+            (error_code.exit_code(), Some(error_code.hint()))
+        }
+        None => (
+            e.downcast_ref::<HelperError>()
+                .map(HelperError::exit_code)
+                .unwrap_or(1),
+            None,
+        ),
+    };
+    if is_errors_json() {
+        let report = ErrorReport {
+            code: exit_code,
+            message: e.to_string(),
+            cause: e.source().map(|s| s.to_string()),
+            hint,
+        };
+        if let Ok(json) = serde_json::to_string(&report) {
+            eprintln!("{}", json);
+        }
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::askpass::{is_password_prompt, parse_ssh_key_prompt, ssh_key_matches};
+    use super::configure::helper_command;
+    use super::credential::{format_login_metadata, lookup_options};
+    use super::lookup::find_totp;
+    use super::*;
+    use crate::backend::SecretBackend;
+    use crate::config::{MarkerField, StoreConflict};
+    use crate::git::GitConfig;
+    use crate::keepassxc::messages::*;
+    use crate::logins::*;
+    use clap::App;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+
+    /// Keeps logins in memory and records what's stored
+    struct MockBackend {
+        logins: Vec<LoginEntry>,
+        stored: RefCell<Vec<(String, String, Option<String>)>>,
+        deleted: RefCell<Vec<String>>,
+    }
+
+    impl SecretBackend for MockBackend {
+        fn get_logins(&self, _url: &str) -> Result<Vec<LoginEntry>> {
+            Ok(self.logins.clone())
+        }
+
+        fn store_login(
+            &self,
+            _url: &str,
+            username: &str,
+            password: &str,
+            _password_expiry_utc: Option<u64>,
+            existing: Option<&LoginEntry>,
+        ) -> Result<()> {
+            self.stored.borrow_mut().push((
+                username.to_owned(),
+                password.to_owned(),
+                existing.map(|e| e.uuid.clone()),
+            ));
+            Ok(())
+        }
+
+        fn revoke_login(&self, _url: &str, login: &LoginEntry, group: &str) -> Result<()> {
+            self.stored.borrow_mut().push((
+                login.login.clone(),
+                login.password.clone(),
+                Some(group.to_owned()),
+            ));
+            Ok(())
+        }
+
+        /// Every group exists, with UUID `<path>-uuid`
+        fn get_group_uuid(&self, path: &str) -> Result<Option<String>> {
+            Ok(Some(format!("{}-uuid", path)))
+        }
+
+        fn get_totp(&self, login: &LoginEntry) -> Result<String> {
+            Ok(format!("{}-totp", login.uuid))
+        }
+
+        fn delete_login(&self, login: &LoginEntry) -> Result<()> {
+            self.deleted.borrow_mut().push(login.uuid.clone());
+            Ok(())
+        }
+    }
+
+    /// Login of entry `<login>-uuid` with password `bar`, in the group and with the advanced string
+    /// fields (names without `KPH: `) given
+    fn login_entry(login: &str, group: Option<&str>, fields: &[(&str, &str)]) -> LoginEntry {
+        let string_fields: HashMap<_, _> = fields
+            .iter()
+            .map(|(name, value)| (format!("KPH: {}", name), value.to_string()))
+            .collect();
+        LoginEntry {
+            login: login.to_owned(),
+            name: "mock".to_owned(),
+            password: "bar".to_owned(),
+            uuid: format!("{}-uuid", login),
+            string_fields: Some(vec![string_fields]),
+            expired: None,
+            group: group.map(str::to_owned),
+            database: None,
+            database_id: None,
+            group_uuid: None,
+        }
+    }
+
+    fn mock_backend() -> MockBackend {
+        let mut login = login_entry("foo", None, &[("notes", "maintained manually")]);
+        login.uuid = "mock-uuid".to_owned();
+        MockBackend {
+            logins: vec![login],
+            stored: RefCell::new(Vec::new()),
+            deleted: RefCell::new(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn test_00_save_login_updates_existing_entry() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=baz\n")
+                .unwrap();
+        save_login(&backend, &git_req, &url, &StoreOptions::default()).unwrap();
+        assert_eq!(
+            *backend.stored.borrow(),
+            [(
+                "foo".to_owned(),
+                "baz".to_owned(),
+                Some("mock-uuid".to_owned())
+            )]
+        );
+    }
+
+    #[test]
+    fn test_01_save_login_skips_unchanged_entry() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=bar\n")
+                .unwrap();
+        let uuid = save_login(&backend, &git_req, &url, &StoreOptions::default()).unwrap();
+        assert!(backend.stored.borrow().is_empty());
+        assert_eq!(uuid.as_deref(), Some("mock-uuid"));
+    }
+
+    #[test]
+    fn test_02_save_login_creates_entry_for_other_username() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=qux\npassword=baz\n")
+                .unwrap();
+        save_login(&backend, &git_req, &url, &StoreOptions::default()).unwrap();
+        assert_eq!(
+            *backend.stored.borrow(),
+            [("qux".to_owned(), "baz".to_owned(), None)]
+        );
+    }
+
+    #[test]
+    fn test_03_save_login_create_only() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=baz\n")
+                .unwrap();
+        save_login(
+            &backend,
+            &git_req,
+            &url,
+            &StoreOptions {
+                create_only: true,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(backend.stored.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_04_repo_prefix_matches() {
+        assert!(repo_prefix_matches("org", "/org/repo.git"));
+        assert!(repo_prefix_matches("/org/repo/", "org/repo.git"));
+        assert!(repo_prefix_matches("org/repo.git", "org/repo"));
+        assert!(!repo_prefix_matches("org", "organisation/repo.git"));
+        assert!(!repo_prefix_matches("org/repo", "org"));
+        assert!(!repo_prefix_matches("", "org/repo.git"));
+    }
+
+    #[test]
+    fn test_05_filter_repo_logins() {
+        let entries = vec![
+            login_entry("default", None, &[]),
+            login_entry("work", None, &[("git-repo", "work-org")]),
+            login_entry("project", None, &[("git-repo", "work-org/project")]),
+        ];
+        let logins = |url: &str| -> Vec<String> {
+            filter_repo_logins(entries.iter().collect(), url)
+                .iter()
+                .map(|e| e.login.clone())
+                .collect()
+        };
+        assert_eq!(logins("https://github.com/work-org/other.git"), ["work"]);
+        assert_eq!(
+            logins("https://github.com/work-org/project.git"),
+            ["project"]
+        );
+        assert_eq!(logins("https://github.com/me/dotfiles.git"), ["default"]);
+        // without credential.useHttpPath
+        assert_eq!(
+            logins("https://github.com/"),
+            ["default", "work", "project"]
+        );
+    }
+
+    #[test]
+    fn test_06_is_password_prompt() {
+        assert!(is_password_prompt("[sudo] password for alice: "));
+        assert!(is_password_prompt("Password:"));
+        assert!(is_password_prompt("[sudo] Passwort für alice: "));
+        assert!(is_password_prompt("[sudo] Mot de passe de alice : "));
+        assert!(!is_password_prompt(
+            "Are you sure you want to continue connecting (yes/no)?"
+        ));
+        assert!(!is_password_prompt(""));
+        assert!(!is_password_prompt("Type yes to continue:"));
+        assert!(!is_password_prompt("Confirm (y/n):"));
+    }
+
+    #[test]
+    fn test_07_filter_recycled_logins() {
+        let entries = vec![
+            login_entry("root", Some("Root"), &[]),
+            login_entry("deleted", Some("Recycle Bin"), &[]),
+            login_entry("legacy", None, &[]),
+        ];
+        let logins: Vec<_> = filter_recycled_logins(entries.iter().collect(), "Recycle Bin")
+            .iter()
+            .map(|e| e.login.clone())
+            .collect();
+        assert_eq!(logins, ["root", "legacy"]);
+    }
+
+    #[test]
+    fn test_08_format_login_metadata() {
+        let (mut git_resp, _) = parse_git_request("protocol=https\nhost=example.com\n").unwrap();
+        git_resp.username = Some("foo".to_owned());
+        git_resp.password = Some("bar".to_owned());
+        let mut login = mock_backend().logins.remove(0);
+        login.database = Some("work".to_owned());
+        let metadata = format_login_metadata(&git_resp, &login);
+        assert_eq!(
+            metadata,
+            "username=foo\ntitle=mock\nuuid=mock-uuid\ndatabase=work\n\n"
+        );
+        assert!(!metadata.contains("bar"));
+
+        git_resp.password_expiry_utc = Some("1700000000".to_owned());
+        assert!(format_login_metadata(&git_resp, &login)
+            .ends_with("database=work\npassword_expiry_utc=1700000000\n\n"));
+    }
+
+    #[test]
+    fn test_09_filter_allowed_logins() {
+        let entries = vec![
+            login_entry("machine", None, &[("git-allow", "bob@desktop, laptop")]),
+            login_entry("user", None, &[("git-allow", "Alice")]),
+            login_entry("other", None, &[("git-allow", "bob@desktop")]),
+            login_entry("unlabeled", None, &[]),
+        ];
+        let identities = [
+            "laptop".to_owned(),
+            "alice@laptop".to_owned(),
+            "alice".to_owned(),
+        ];
+        let logins: Vec<_> = filter_allowed_logins(entries.iter().collect(), &identities)
+            .iter()
+            .map(|e| e.login.clone())
+            .collect();
+        assert_eq!(logins, ["machine", "user"]);
+    }
+
+    #[test]
+    fn test_10_recent_entry_key() {
+        let url = "https://example.com:8443/repo.git";
+        assert_eq!(recent_entry_key(url, None), "example.com");
+        assert_eq!(recent_entry_key(url, Some("foo")), "foo@example.com");
+    }
+
+    #[test]
+    fn test_11_parse_ssh_key_prompt() {
+        assert_eq!(
+            parse_ssh_key_prompt("Enter passphrase for key '/home/alice/.ssh/id_ed25519': "),
+            Some("/home/alice/.ssh/id_ed25519")
+        );
+        assert_eq!(
+            parse_ssh_key_prompt(
+                "Enter passphrase for /home/alice/.ssh/id_rsa (will confirm each use): "
+            ),
+            Some("/home/alice/.ssh/id_rsa")
+        );
+        assert_eq!(
+            parse_ssh_key_prompt("Enter PIN for ED25519-SK key SHA256:abc+/def: "),
+            Some("SHA256:abc+/def")
+        );
+        assert_eq!(
+            parse_ssh_key_prompt("Are you sure you want to continue connecting (yes/no)?"),
+            None
+        );
+
+        let mut login = mock_backend().logins.remove(0);
+        let mut fields = HashMap::new();
+        fields.insert(
+            "KPH: ssh-key".to_owned(),
+            "/home/alice/.ssh/id_rsa, SHA256:abc+/def".to_owned(),
+        );
+        login.string_fields = Some(vec![fields]);
+        assert!(ssh_key_matches(&login, "/home/alice/.ssh/id_rsa"));
+        assert!(ssh_key_matches(&login, "SHA256:abc+/def"));
+        assert!(!ssh_key_matches(&login, "/home/alice/.ssh/id_ed25519"));
+    }
+
+    #[test]
+    fn test_14_store_conflict() {
+        let mut backend = mock_backend();
+        let mut other = backend.logins[0].clone();
+        other.uuid = "other-uuid".to_owned();
+        backend.logins.push(other);
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=baz\n")
+                .unwrap();
+        let store = |conflict| {
+            backend.stored.borrow_mut().clear();
+            save_login(
+                &backend,
+                &git_req,
+                &url,
+                &StoreOptions {
+                    conflict,
+                    ..StoreOptions::default()
+                },
+            )
+        };
+        assert_eq!(
+            store(StoreConflict::UpdateFirst).unwrap().as_deref(),
+            Some("mock-uuid")
+        );
+        assert_eq!(store(StoreConflict::CreateNew).unwrap(), None);
+        assert_eq!(
+            *backend.stored.borrow(),
+            [("foo".to_owned(), "baz".to_owned(), None)]
+        );
+        assert!(store(StoreConflict::Abort).is_err());
+        assert!(backend.stored.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_15_find_totp() {
+        let backend = mock_backend();
+        let yaml = clap::load_yaml!("cli.yml");
+        let args = App::from_yaml(yaml).get_matches_from(vec![clap::crate_name!(), "totp"]);
+        let config = Config::new();
+        let git_config = GitConfig::default();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\n").unwrap();
+        let totp = find_totp(&args, &config, &git_config, &backend, git_req, &url).unwrap();
+        assert_eq!(totp, "mock-uuid-totp");
+    }
+
+    #[test]
+    fn test_16_advanced_fields() {
+        let mut backend = mock_backend();
+        let mut fields = HashMap::new();
+        fields.insert("KPH: team".to_owned(), "core".to_owned());
+        fields.insert("KPH: unlisted".to_owned(), "ignored".to_owned());
+        fields.insert("KPH: quit".to_owned(), "1".to_owned());
+        fields.insert("KPH: authtype".to_owned(), "Bearer".to_owned());
+        fields.insert("KPH: username".to_owned(), "shadowed".to_owned());
+        fields.insert("KPH: multi".to_owned(), "line\nbreak".to_owned());
+        backend.logins[0].string_fields = Some(vec![fields]);
+        let yaml = clap::load_yaml!("cli.yml");
+        let config: Config = serde_json::from_str(
+            r#"{"advanced_field_names": ["team", "quit", "authtype", "username", "multi"]}"#,
+        )
+        .unwrap();
+        let git_config = GitConfig::default();
+        let request = "protocol=https\nhost=example.com\ncapability[]=authtype\n";
+
+        let args = App::from_yaml(yaml).get_matches_from(vec![clap::crate_name!(), "get"]);
+        let (git_req, url) = parse_git_request(request).unwrap();
+        let (git_resp, _) = find_login(
+            &config,
+            &lookup_options(&args, &config, &git_config).unwrap(),
+            &backend,
+            git_req,
+            &url,
+            false,
+        )
+        .unwrap();
+        assert!(git_resp.extra.is_empty());
+
+        let args = App::from_yaml(yaml).get_matches_from(vec![
+            clap::crate_name!(),
+            "--advanced-fields",
+            "get",
+        ]);
+        let (git_req, url) = parse_git_request(request).unwrap();
+        let (git_resp, _) = find_login(
+            &config,
+            &lookup_options(&args, &config, &git_config).unwrap(),
+            &backend,
+            git_req,
+            &url,
+            false,
+        )
+        .unwrap();
+        assert_eq!(git_resp.extra, vec![("team".to_owned(), "core".to_owned())]);
+        assert_eq!(git_resp.username.as_deref(), Some("foo"));
+    }
+
+    #[test]
+    fn test_17_shell_quote() {
+        assert_eq!(shell_quote("foo bar"), "'foo bar'");
+        assert_eq!(shell_quote("it's $HOME"), "'it'\\''s $HOME'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn test_18_delete_logins() {
+        let mut backend = mock_backend();
+        let mut hidden = backend.logins[0].clone();
+        hidden.uuid = "hidden-uuid".to_owned();
+        let mut marker_field = HashMap::new();
+        marker_field.insert("KPH: git".to_owned(), "false".to_owned());
+        hidden.string_fields = Some(vec![marker_field]);
+        backend.logins.push(hidden);
+        let mut recycled = backend.logins[0].clone();
+        recycled.uuid = "recycled-uuid".to_owned();
+        recycled.group = Some("Recycle Bin".to_owned());
+        backend.logins.push(recycled);
+        let config = Config::new();
+
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=old\n")
+                .unwrap();
+        delete_logins(&config, &backend, &git_req, &url).unwrap();
+        assert!(backend.deleted.borrow().is_empty());
+
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=bar\n")
+                .unwrap();
+        delete_logins(&config, &backend, &git_req, &url).unwrap();
+        assert_eq!(*backend.deleted.borrow(), ["mock-uuid"]);
+        assert!(backend.stored.borrow().is_empty());
+
+        let (git_req, url) = parse_git_request("protocol=https\nhost=example.com\n").unwrap();
+        assert!(delete_logins(&config, &backend, &git_req, &url).is_err());
+
+        // without the password, only a single login is erased
+        let mut other = backend.logins[0].clone();
+        other.uuid = "other-uuid".to_owned();
+        other.password = "baz".to_owned();
+        backend.logins.push(other);
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\n").unwrap();
+        assert!(delete_logins(&config, &backend, &git_req, &url).is_err());
+        assert_eq!(*backend.deleted.borrow(), ["mock-uuid"]);
+    }
+
+    #[test]
+    fn test_19_revoke_login() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=bar\n")
+                .unwrap();
+        revoke_login(&backend, &git_req, &url, "Git/Revoked").unwrap();
+        assert_eq!(
+            *backend.stored.borrow(),
+            [(
+                "foo".to_owned(),
+                "bar".to_owned(),
+                Some("Git/Revoked".to_owned())
+            )]
+        );
+
+        let mut logins = backend.logins.clone();
+        let mut copy = logins[0].clone();
+        copy.uuid = "revoked-uuid".to_owned();
+        copy.group = Some("Revoked".to_owned());
+        copy.group_uuid = Some("Git/Revoked-uuid".to_owned());
+        logins.push(copy);
+        let mut other = logins[0].clone();
+        other.password = "baz".to_owned();
+        other.uuid = "other-uuid".to_owned();
+        logins.push(other);
+        // a group of the same name elsewhere isn't the revoked one
+        let mut namesake = logins[0].clone();
+        namesake.password = "qux".to_owned();
+        namesake.uuid = "namesake-uuid".to_owned();
+        namesake.group = Some("Revoked".to_owned());
+        namesake.group_uuid = Some("Work/Revoked-uuid".to_owned());
+        logins.push(namesake);
+        let uuids: Vec<_> = filter_revoked_logins(&backend, logins, "Git/Revoked")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.uuid)
+            .collect();
+        assert_eq!(uuids, ["other-uuid", "namesake-uuid"]);
+    }
+
+    #[test]
+    fn test_20_save_login_updates_unlabeled_entry() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=baz\n")
+                .unwrap();
+        let marker = MarkerField {
+            use_unlabeled: false,
+            ..MarkerField::default()
+        };
+        save_login(
+            &backend,
+            &git_req,
+            &url,
+            &StoreOptions {
+                marker,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            *backend.stored.borrow(),
+            [(
+                "foo".to_owned(),
+                "baz".to_owned(),
+                Some("mock-uuid".to_owned())
+            )]
+        );
+    }
+
+    #[test]
+    fn test_21_helper_command() {
+        let yaml = clap::load_yaml!("cli.yml");
+        let app = App::from_yaml(yaml);
+        let args = app
+            .clone()
+            .get_matches_from(vec![clap::crate_name!(), "configure"]);
+        assert_eq!(helper_command("config.json", &args), "keepassxc");
+        let args = app.clone().get_matches_from(vec![
+            clap::crate_name!(),
+            "--profile",
+            "work",
+            "configure",
+        ]);
+        assert_eq!(
+            helper_command("config.json", &args),
+            "keepassxc --profile 'work'"
+        );
+        let args = app.get_matches_from(vec![
+            clap::crate_name!(),
+            "--config",
+            "/nonexistent/it's.json",
+            "configure",
+        ]);
+        assert_eq!(
+            helper_command("/nonexistent/it's.json", &args),
+            "keepassxc --config '/nonexistent/it'\\''s.json'"
+        );
+    }
+
+    #[test]
+    fn test_22_max_candidates() {
+        let mut backend = mock_backend();
+        backend.logins[0] = login_entry("foo", None, &[("git", "false")]);
+        backend.logins.push(login_entry("bar", None, &[]));
+        backend.logins.push(login_entry("baz", None, &[]));
+        let config = Config::new();
+        let options = LookupOptions {
+            max_candidates: Some(1),
+            ..Default::default()
+        };
+        // the hidden login doesn't count
+        let (git_req, url) = parse_git_request("protocol=https\nhost=example.com\n").unwrap();
+        let (_, login) = find_login(&config, &options, &backend, git_req, &url, false).unwrap();
+        assert_eq!(login.uuid, "bar-uuid");
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=baz\n").unwrap();
+        let (_, login) = find_login(&config, &options, &backend, git_req, &url, false).unwrap();
+        assert_eq!(login.uuid, "baz-uuid");
+    }
+
+    #[test]
+    fn test_23_save_login_refuses_not_allowed_entry() {
+        let backend = mock_backend();
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=foo\npassword=baz\n")
+                .unwrap();
+        let options = StoreOptions {
+            require_allow_marker: true,
+            ..StoreOptions::default()
+        };
+        assert!(save_login(&backend, &git_req, &url, &options).is_err());
+        assert!(backend.stored.borrow().is_empty());
+        // other usernames have got no entry that could be hidden
+        let (git_req, url) =
+            parse_git_request("protocol=https\nhost=example.com\nusername=qux\npassword=baz\n")
+                .unwrap();
+        save_login(&backend, &git_req, &url, &options).unwrap();
+        assert_eq!(backend.stored.borrow().len(), 1);
+    }
+}
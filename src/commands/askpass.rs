@@ -0,0 +1,181 @@
+//! Answering password prompts of sudo, SSH and rclone
+use super::verify_caller;
+use crate::backend::open_backend;
+use crate::cli::UnlockOptions;
+use crate::config::Config;
+use crate::keepassxc::messages::*;
+use crate::logins::*;
+use crate::utils::*;
+use crate::{detail, info, warn};
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use std::path::Path;
+
+/// Parts of questions that must not be answered with a password even if they end with a colon
+const CONFIRMATION_PROMPTS: &[&str] = &[
+    "yes/no",
+    "y/n",
+    "are you sure",
+    "continue",
+    "confirm",
+    "fingerprint",
+    "proceed",
+];
+
+/// Whether the prompt asks for a password, e.g. `[sudo] password for alice: ` or `Password:`, as
+/// opposed to other questions which sudo or other programs may pass to an askpass helper. Prompts
+/// are translated, so this goes by the trailing colon they end with in any language rather than
+/// by the wording, except for refusing known confirmations.
+pub fn is_password_prompt(prompt: &str) -> bool {
+    let lowercase = prompt.to_lowercase();
+    prompt.trim_end().ends_with(':')
+        && !CONFIRMATION_PROMPTS
+            .iter()
+            .any(|confirmation| lowercase.contains(confirmation))
+}
+
+pub fn sudo_askpass<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let prompt = args
+        .subcommand_matches("sudo-askpass")
+        .and_then(|m| m.value_of("PROMPT"))
+        .unwrap_or_default();
+    if !is_password_prompt(prompt) {
+        return Err(anyhow!("Refused to answer prompt {:?}", prompt));
+    }
+    let sudo_entry = config
+        .get_sudo_entry()
+        .ok_or_else(|| anyhow!("No sudo entry configured"))?;
+
+    let backend = open_backend(&config, unlock_options)?;
+    let login_entries = backend.get_logins(&sudo_entry.url)?;
+    let login_entries = filter_logins(&config, backend.as_ref(), login_entries, false)?;
+    let login_entry = login_entries
+        .iter()
+        .find(|entry| entry.uuid.eq_ignore_ascii_case(&sudo_entry.uuid))
+        .ok_or_else(|| {
+            anyhow!(
+                "Entry {} is not among the logins of {}",
+                sudo_entry.uuid,
+                sudo_entry.url
+            )
+        })?;
+    info!("Answering sudo password prompt");
+    println!("{}", login_entry.password);
+    Ok(())
+}
+
+/// Key path or fingerprint which an OpenSSH prompt asks the passphrase (or PIN) of, e.g.
+/// `Enter passphrase for key '/home/alice/.ssh/id_ed25519': `, `None` for other questions such as
+/// confirming host keys
+pub fn parse_ssh_key_prompt(prompt: &str) -> Option<&str> {
+    // ASCII lowercasing keeps the byte offsets of the prompt
+    let lowercase = prompt.to_ascii_lowercase();
+    let start = ["passphrase for ", "try again for ", "pin for "]
+        .iter()
+        .find_map(|phrase| lowercase.find(phrase).map(|idx| idx + phrase.len()))?;
+    if let Some(fingerprint) = prompt[start..]
+        .split_whitespace()
+        .find(|word| word.starts_with("SHA256:"))
+    {
+        return Some(fingerprint.trim_end_matches(':'));
+    }
+    let key = prompt[start..].trim_end();
+    let key = key.strip_suffix(':').unwrap_or(key).trim_end();
+    let key = key
+        .strip_suffix("(will confirm each use)")
+        .unwrap_or(key)
+        .trim_end();
+    let key = key.strip_prefix("key ").unwrap_or(key);
+    let key = key.trim_matches(['\'', '"']);
+    if key.is_empty() {
+        None
+    } else {
+        Some(key)
+    }
+}
+
+/// Whether `KPH: ssh-key` of an entry lists the key path or fingerprint, separated by commas or
+/// newlines
+pub fn ssh_key_matches(entry: &LoginEntry, key: &str) -> bool {
+    let expanded_key = expand_path(key).ok();
+    entry.get_string_field("ssh-key").is_some_and(|keys| {
+        keys.split([',', '\n'])
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .any(|value| {
+                value == key
+                    || (!value.starts_with("SHA256:")
+                        && expanded_key.is_some()
+                        && expand_path(value).ok() == expanded_key)
+            })
+    })
+}
+
+pub fn ssh_askpass<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let prompt = args
+        .subcommand_matches("ssh-askpass")
+        .and_then(|m| m.value_of("PROMPT"))
+        .unwrap_or_default();
+    let key = parse_ssh_key_prompt(prompt)
+        .ok_or_else(|| anyhow!("Refused to answer prompt {:?}", prompt))?;
+    detail!("Looking up passphrase of SSH key {}", key);
+
+    let url = config.get_ssh_key_url();
+    verify_host(&config, url)?;
+    let backend = open_backend(&config, unlock_options)?;
+    let login_entries = backend.get_logins(url)?;
+    let login_entries = filter_logins(&config, backend.as_ref(), login_entries, false)?;
+    let login_entry = login_entries
+        .into_iter()
+        .find(|entry| ssh_key_matches(entry, key))
+        .ok_or_else(|| anyhow!("No login of {} is labeled with KPH: ssh-key {}", url, key))?;
+    info!("Answering SSH key passphrase prompt");
+    println!("{}", login_entry.password);
+    Ok(())
+}
+
+pub fn rclone_pass<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let subcommand = args.subcommand_matches("rclone-pass").unwrap();
+    let remote = subcommand
+        .value_of("REMOTE")
+        .ok_or_else(|| anyhow!("Must specify remote"))?;
+    let url = format!("rclone://{}", remote);
+    verify_host(&config, &url)?;
+
+    let backend = open_backend(&config, unlock_options)?;
+    let login_entries = backend.get_logins(&url)?;
+    let login_entries = filter_logins(&config, backend.as_ref(), login_entries, false)?;
+    let login_entry = login_entries
+        .first()
+        .ok_or_else(|| anyhow!("No matching logins found"))?;
+    if login_entries.len() > 1 {
+        warn!("More than 1 matching logins found, only the first one is used");
+    }
+    if subcommand.is_present("obscure") {
+        println!("{}", rclone_obscure(&login_entry.password));
+    } else {
+        println!("{}", login_entry.password);
+    }
+    Ok(())
+}
@@ -0,0 +1,730 @@
+//! Setting up associations, encryption and caller profiles interactively or from the options
+use super::{print_list, shell_quote, verify_caller};
+use crate::cli::UnlockOptions;
+use crate::config::{Caller, Config, Database, GroupRule};
+use crate::git::set_url_helper;
+use crate::keepassxc::{errors::*, messages::*, Group};
+use crate::session::{find_group, get_root_group, start_session, validate_group};
+use crate::utils::*;
+use crate::{info, warn};
+use anyhow::{anyhow, Result};
+use clap::ArgMatches;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+fn handle_secondary_encryption(config_file: &mut Config) -> Result<()> {
+    ensure_prompt_allowed(true)?;
+    println!("There are existing encryption profile(s). If you'd like to reuse an existing encryption key, plug in the corresponding (hardware) token.");
+    print!("Press Enter to continue... ");
+    std::io::stdout().flush()?;
+    std::io::stdin().read_line(&mut String::new())?;
+    if config_file.get_encryption_key().is_err() {
+        warn!("Failed to extract encryption key from existing profiles");
+        println!("Failed to extract the encryption key! Continue to configure a new (hardware) token using a DIFFERENT encryption key.")
+    }
+    println!("Now make sure you've plugged in the (hardware) token you'd like to use.");
+    print!("Press Enter to continue... ");
+    std::io::stdout().flush()?;
+    std::io::stdin().read_line(&mut String::new())?;
+    Ok(())
+}
+
+fn default_association_name() -> String {
+    let hostname = hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "localhost".to_owned());
+    format!("{}@{}", clap::crate_name!(), hostname)
+}
+
+/// Value of credential.helper running this program with the same configuration file
+pub fn helper_command<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> String {
+    let helper = clap::crate_name!().trim_start_matches("git-credential-");
+    // Git runs helpers through the shell
+    if args.is_present("config") {
+        let config_path = config_path
+            .as_ref()
+            .canonicalize()
+            .unwrap_or_else(|_| config_path.as_ref().to_owned());
+        format!(
+            "{} --config {}",
+            helper,
+            shell_quote(&config_path.to_string_lossy())
+        )
+    } else if let Some(profile) = args.value_of("profile") {
+        format!("{} --profile {}", helper, shell_quote(profile))
+    } else {
+        helper.to_owned()
+    }
+}
+
+pub fn configure<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    if args
+        .subcommand_matches("configure")
+        .map(|m| m.is_present("interactive"))
+        .unwrap_or(false)
+    {
+        return configure_interactive(config_path, args);
+    }
+    if args
+        .subcommand_matches("configure")
+        .map(|m| m.is_present("renew"))
+        .unwrap_or(false)
+    {
+        return configure_renew(config_path, args.is_present("create-group"));
+    }
+
+    // start session
+    let (client_id, session_seckey, _) = start_session()?;
+    let session_pubkey = session_seckey.public_key();
+
+    // generate permanent client key for future authentication
+    let id_seckey = generate_secret_key();
+    let id_pubkey = id_seckey.public_key();
+
+    // KeePassXC doesn't allow clients to name the association, so suggest one to the user
+    let association_name = match args
+        .subcommand_matches("configure")
+        .and_then(|m| m.value_of("name"))
+    {
+        Some(name) => name.to_owned(),
+        None => default_association_name(),
+    };
+    println!(
+        "KeePassXC is going to ask you to name the new association, suggested name: {}",
+        association_name
+    );
+
+    // validate before associating
+    let database_unlock = args
+        .subcommand_matches("configure")
+        .and_then(|m| m.value_of("database-unlock"))
+        .map(UnlockOptions::from_str)
+        .transpose()?;
+
+    let aso_req = AssociateRequest::new(&session_pubkey, &id_pubkey);
+    let aso_resp = aso_req.send(&client_id, false)?;
+    let database_id = aso_resp.id.ok_or_else(|| anyhow!("Association failed"))?;
+    if database_id != association_name {
+        info!(
+            "Association is named {} in KeePassXC, {} is kept as the label",
+            database_id, association_name
+        );
+    }
+
+    // reuse the group if it already exists, otherwise ask KeePassXC to create it, which a group
+    // given explicitly has to ask for
+    let configure_args = args.subcommand_matches("configure");
+    let group_name = configure_args
+        .and_then(|m| m.value_of("group"))
+        .expect("Group name not specified (there's a default one though, bug?)");
+    let create_group = args.is_present("create-group")
+        || configure_args.is_none_or(|m| m.occurrences_of("group") == 0);
+    let group = find_group(&client_id, group_name, create_group)?;
+
+    // read existing or create new config
+    let mut config_file = if let Ok(config_file) = Config::read_from(&config_path) {
+        verify_caller(&config_file)?;
+        config_file
+    } else {
+        Config::new()
+    };
+
+    let encryption = args
+        .subcommand_matches("configure")
+        .and_then(|m| m.value_of("encrypt"));
+    if let Some(encryption) = encryption {
+        if config_file.count_encryptions() > 0 && !encryption.is_empty() {
+            handle_secondary_encryption(&mut config_file)?;
+        }
+        // this will error if an existing encryption profile has already been configured for the
+        // underlying hardware/etc
+        // in this case user should decrypt the configuration first
+        config_file.add_encryption(encryption)?;
+    }
+
+    // save new config
+    info!(
+        "Saving configuration to {}",
+        config_path.as_ref().to_string_lossy()
+    );
+    let mut database = Database::new(database_id, id_seckey, group);
+    database.label = Some(association_name);
+    database.unlock = database_unlock;
+    config_file.add_database(database, encryption.is_some())?;
+    config_file.write_to(&config_path)?;
+
+    let helper_hosts = args
+        .subcommand_matches("configure")
+        .and_then(|m| m.values_of("set-helper-for"));
+    for host in helper_hosts.into_iter().flatten() {
+        let url = if host.contains("://") {
+            host.to_owned()
+        } else {
+            format!("https://{}", host)
+        };
+        set_url_helper(&url, helper_command(&config_path, args))?;
+        println!("Set credential.{}.helper in global Git configuration", url);
+    }
+
+    Ok(())
+}
+
+/// Finds database profiles of which the association is no longer recognised by KeePassXC, then
+/// associates again and replaces the keys of the profile in place
+fn configure_renew<T: AsRef<Path>>(config_path: T, create_group: bool) -> Result<()> {
+    let mut config_file = Config::read_from(&config_path)?;
+    verify_caller(&config_file)?;
+
+    let (client_id, session_seckey, _) = start_session()?;
+    let session_pubkey = session_seckey.public_key();
+
+    let mut revoked_databases = Vec::new();
+    for database in config_file.get_databases()? {
+        let taso_req = TestAssociateRequest::new(database.id.as_str(), database.pkey.as_str());
+        match taso_req.send(&client_id, false) {
+            Ok(taso_resp) if taso_resp.success.clone().map(Into::into).unwrap_or(false) => {
+                info!("Association of database {} is valid", database.id);
+            }
+            Ok(_) => revoked_databases.push(database),
+            Err(e) => match e.downcast_ref::<KeePassError>() {
+                Some(keepass_error) if keepass_error.is_association_revoked() => {
+                    revoked_databases.push(database)
+                }
+                Some(keepass_error) if keepass_error.is_database_locked() => {
+                    return Err(anyhow!(
+                        "Unlock the database in KeePassXC before renewing associations"
+                    ));
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+    if revoked_databases.is_empty() {
+        println!("All associations are valid, nothing to renew");
+        return Ok(());
+    }
+    for database in &revoked_databases {
+        println!(
+            "Association {} has been removed from KeePassXC",
+            database.label.as_ref().unwrap_or(&database.id)
+        );
+    }
+
+    // only the currently opened database can be associated, hence the one revoked profile that
+    // shares the name the user chooses in KeePassXC is renewed
+    let suggested_name = revoked_databases[0].id.clone();
+    println!(
+        "KeePassXC is going to ask you to name the new association, to renew another database open it in KeePassXC and use its name instead, suggested name: {}",
+        suggested_name
+    );
+    let id_seckey = generate_secret_key();
+    let id_pubkey = id_seckey.public_key();
+    let aso_req = AssociateRequest::new(&session_pubkey, &id_pubkey);
+    let aso_resp = aso_req.send(&client_id, false)?;
+    let database_id = aso_resp.id.ok_or_else(|| anyhow!("Association failed"))?;
+
+    let revoked_database = if let Some(database) =
+        revoked_databases.iter().find(|db| db.id == database_id)
+    {
+        database
+    } else if revoked_databases.len() == 1 {
+        warn!(
+            "Association is named {} in KeePassXC, replacing {}",
+            database_id, revoked_databases[0].id
+        );
+        &revoked_databases[0]
+    } else {
+        return Err(anyhow!(
+            "Association {} doesn't match any removed ones, name it after the database profile to renew",
+            database_id
+        ));
+    };
+
+    // keep the preferences of the old profile, but make sure the group is still there
+    let group = validate_group(
+        &client_id,
+        revoked_database,
+        create_group || config_file.is_create_group(),
+    )?;
+    let mut database = Database::new(database_id, id_seckey, group);
+    database.label = revoked_database.label.clone();
+    database.unlock = revoked_database.unlock.clone();
+    config_file.replace_database(&revoked_database.id, database)?;
+    info!(
+        "Saving configuration to {}",
+        config_path.as_ref().to_string_lossy()
+    );
+    config_file.write_to(&config_path)?;
+    println!(
+        "Renewed association {}",
+        revoked_database
+            .label
+            .as_ref()
+            .unwrap_or(&revoked_database.id)
+    );
+
+    Ok(())
+}
+
+/// Asks user for a line of input, returns the default one if the input is empty
+fn prompt<T: AsRef<str>>(question: T, default: &str) -> Result<String> {
+    ensure_prompt_allowed(true)?;
+    if default.is_empty() {
+        print!("{}: ", question.as_ref());
+    } else {
+        print!("{} [{}]: ", question.as_ref(), default);
+    }
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        return Err(anyhow!("Unexpected end of input"));
+    }
+    let answer = answer.trim();
+    if answer.is_empty() {
+        Ok(default.to_owned())
+    } else {
+        Ok(answer.to_owned())
+    }
+}
+
+fn prompt_yes_no<T: AsRef<str>>(question: T, default: bool) -> Result<bool> {
+    let options = if default { "Y/n" } else { "y/N" };
+    loop {
+        let answer = prompt(format!("{} ({})", question.as_ref(), options), "")?;
+        match answer.to_lowercase().as_str() {
+            "" => return Ok(default),
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => println!("Please answer y or n"),
+        }
+    }
+}
+
+/// Lets user pick a socket, returns whether it differs from the one detected automatically
+fn choose_socket_path() -> Result<bool> {
+    let mut paths = get_candidate_socket_paths()?;
+    let current_path = get_socket_path()?;
+    if !paths.contains(&current_path) {
+        paths.insert(0, current_path.clone());
+    }
+    let detected_path = get_candidate_socket_paths()?
+        .into_iter()
+        .find(|path| path.exists());
+
+    let session_pubkey = generate_secret_key().public_key();
+    let (_, client_id) = nacl_nonce();
+    let mut default_choice = None;
+    for (idx, path) in paths.iter().enumerate() {
+        let status = if !path.exists() {
+            "not found"
+        } else {
+            set_socket_path(path.clone());
+            if exchange_keys(&client_id, &session_pubkey).is_ok() {
+                default_choice = default_choice.or(Some(idx + 1));
+                "KeePassXC is listening"
+            } else {
+                "exists, but key exchange failed"
+            }
+        };
+        println!("  {}) {}: {}", idx + 1, path.to_string_lossy(), status);
+    }
+    if default_choice.is_none() {
+        println!("{}", BROWSER_INTEGRATION_HINT);
+    }
+
+    let answer = prompt(
+        "Socket to use, enter a number or a path",
+        &default_choice.map(|c| c.to_string()).unwrap_or_default(),
+    )?;
+    let path = match usize::from_str(&answer) {
+        Ok(choice) if choice >= 1 && choice <= paths.len() => paths[choice - 1].clone(),
+        Ok(_) => return Err(anyhow!("Invalid choice {}", answer)),
+        Err(_) if answer.is_empty() => return Err(anyhow!("No socket selected")),
+        Err(_) => expand_path(&answer)?,
+    };
+    set_socket_path(path.clone());
+    Ok(detected_path.as_ref() != Some(&path))
+}
+
+/// Lets user pick an existing group or enter the path of a new one, returns its path along with
+/// the group
+fn choose_group<T: AsRef<str>>(client_id: T, question: &str) -> Result<(String, Group)> {
+    let root_group = get_root_group(client_id.as_ref())?;
+    let paths = root_group.get_descendant_paths();
+    for (idx, (path, _)) in paths.iter().enumerate() {
+        println!("  {}) {}", idx + 1, path);
+    }
+    let answer = prompt(
+        format!("{}, enter a number or a (new) path", question),
+        "Git",
+    )?;
+    match usize::from_str(&answer) {
+        Ok(choice) if choice >= 1 && choice <= paths.len() => {
+            let (path, group) = &paths[choice - 1];
+            Ok((
+                path.clone(),
+                Group::new(group.name.clone(), group.uuid.clone()),
+            ))
+        }
+        Ok(_) => Err(anyhow!("Invalid choice {}", answer)),
+        Err(_) => Ok((answer.clone(), find_group(client_id, &answer, true)?)),
+    }
+}
+
+/// Executables that typically request credentials, i.e. Git itself and its HTTP(S) helpers
+fn get_common_caller_paths() -> Vec<PathBuf> {
+    let git = if cfg!(windows) { "git.exe" } else { "git" };
+    let mut paths: Vec<_> = std::env::var_os("PATH")
+        .and_then(|path| {
+            std::env::split_paths(&path)
+                .map(|dir| dir.join(git))
+                .find(|path| path.exists())
+        })
+        .into_iter()
+        .collect();
+    for dir in &["/usr/lib/git-core", "/usr/libexec/git-core"] {
+        for helper in &["git-remote-http", "git-remote-https"] {
+            paths.push(Path::new(dir).join(helper));
+        }
+    }
+    resolve_caller_paths(paths)
+}
+
+/// Resolves symbolic links as caller paths are compared against the real executables, dropping
+/// the ones that don't exist and duplicates
+fn resolve_caller_paths(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut resolved_paths = Vec::new();
+    for path in paths {
+        if let Ok(path) = std::fs::canonicalize(path) {
+            if !resolved_paths.contains(&path) {
+                resolved_paths.push(path);
+            }
+        }
+    }
+    resolved_paths
+}
+
+/// The Git executable and the ones in its exec path which may run credential helpers
+fn get_git_installation_paths<T: AsRef<Path>>(git: T) -> Vec<PathBuf> {
+    let mut paths = vec![git.as_ref().to_owned()];
+    let exec_path = std::process::Command::new(git.as_ref())
+        .arg("--exec-path")
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| PathBuf::from(String::from_utf8_lossy(&output.stdout).trim()));
+    if let Some(exec_path) = exec_path {
+        for helper in &["git", "git-remote-http", "git-remote-https"] {
+            paths.push(exec_path.join(format!("{}{}", helper, std::env::consts::EXE_SUFFIX)));
+        }
+    }
+    paths
+}
+
+/// Executables that request credentials on behalf of well-known tools. IDEs run Git (whichever
+/// one is found, unless configured otherwise), which in turn runs the helper.
+pub fn get_preset_caller_paths(preset: &str) -> Result<Vec<PathBuf>> {
+    let git = if cfg!(windows) { "git.exe" } else { "git" };
+    let gits: Vec<PathBuf> = match preset {
+        "vscode" | "jetbrains" => {
+            let mut gits: Vec<_> = std::env::var_os("PATH")
+                .map(|path| {
+                    std::env::split_paths(&path)
+                        .map(|dir| dir.join(git))
+                        .filter(|path| path.exists())
+                        .collect()
+                })
+                .unwrap_or_default();
+            if cfg!(target_os = "macos") {
+                gits.push(PathBuf::from(
+                    "/Library/Developer/CommandLineTools/usr/bin/git",
+                ));
+                gits.push(PathBuf::from(
+                    "/Applications/Xcode.app/Contents/Developer/usr/bin/git",
+                ));
+            }
+            gits
+        }
+        "git-for-windows" => {
+            let mut dirs: Vec<PathBuf> = ["ProgramFiles", "ProgramW6432"]
+                .iter()
+                .filter_map(std::env::var_os)
+                .map(|dir| PathBuf::from(dir).join("Git"))
+                .collect();
+            if let Some(dir) = std::env::var_os("LOCALAPPDATA") {
+                dirs.push(PathBuf::from(dir).join("Programs").join("Git"));
+            }
+            dirs.iter()
+                .flat_map(|dir| {
+                    vec![
+                        dir.join("cmd").join("git.exe"),
+                        dir.join("bin").join("git.exe"),
+                        dir.join("mingw64").join("bin").join("git.exe"),
+                    ]
+                })
+                .collect()
+        }
+        "homebrew-git" => [
+            "/opt/homebrew/bin/git",
+            "/usr/local/bin/git",
+            "/home/linuxbrew/.linuxbrew/bin/git",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        // /usr/local/bin may also be used by other installations
+        .filter(|path| {
+            std::fs::canonicalize(path)
+                .map(|path| path.to_string_lossy().contains("/Cellar/"))
+                .unwrap_or(false)
+        })
+        .collect(),
+        _ => return Err(anyhow!("Unknown caller preset {}", preset)),
+    };
+    let paths = resolve_caller_paths(
+        gits.iter()
+            .filter(|git| git.exists())
+            .flat_map(get_git_installation_paths)
+            .collect(),
+    );
+    if paths.is_empty() {
+        return Err(anyhow!("No executables of caller preset {} found", preset));
+    }
+    Ok(paths)
+}
+
+fn configure_interactive<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    // fail before doing anything if there's nobody to answer the questions
+    ensure_prompt_allowed(true)?;
+    // read existing or create new config
+    let mut config_file = if let Ok(config_file) = Config::read_from(&config_path) {
+        verify_caller(&config_file)?;
+        config_file
+    } else {
+        Config::new()
+    };
+
+    println!("[1/6] Looking for KeePassXC");
+    if choose_socket_path()? {
+        let socket_path = get_socket_path()?;
+        if prompt_yes_no(
+            format!(
+                "Save {} in configuration file?",
+                socket_path.to_string_lossy()
+            ),
+            true,
+        )? {
+            config_file.set_socket(Some(socket_path.to_string_lossy().into_owned()));
+        }
+    }
+
+    println!("[2/6] Associating with KeePassXC");
+    let (client_id, session_seckey, _) = start_session()?;
+    let session_pubkey = session_seckey.public_key();
+    let id_seckey = generate_secret_key();
+    let id_pubkey = id_seckey.public_key();
+    let association_name = prompt("Name of the association", &default_association_name())?;
+    println!(
+        "KeePassXC is going to ask you to name the new association, suggested name: {}",
+        association_name
+    );
+    let aso_req = AssociateRequest::new(&session_pubkey, &id_pubkey);
+    let aso_resp = aso_req.send(&client_id, false)?;
+    let database_id = aso_resp.id.ok_or_else(|| anyhow!("Association failed"))?;
+
+    println!("[3/6] Choosing group");
+    let (_, group) = choose_group(&client_id, "Group to store new logins in")?;
+
+    println!("[4/6] Mapping hosts to groups");
+    let mut group_rules = Vec::new();
+    loop {
+        let host = prompt(
+            "Host whose new logins go to another group, e.g. github.com or *.example.com (leave empty to skip)",
+            "",
+        )?;
+        if host.is_empty() {
+            break;
+        }
+        let (path, group) = choose_group(&client_id, &format!("Group for {}", host))?;
+        group_rules.push(GroupRule {
+            host,
+            group: path,
+            group_uuid: group.uuid,
+        });
+    }
+
+    println!("[5/6] Limiting callers");
+    #[cfg(unix)]
+    let (uid, gid) = {
+        let (uid, gid) = get_current_user();
+        (Some(uid), Some(gid))
+    };
+    #[cfg(windows)]
+    let (uid, gid) = (None, None);
+    let mut callers = Vec::new();
+    for path in get_common_caller_paths() {
+        let path = normalize_caller_path(path).to_string_lossy().into_owned();
+        if prompt_yes_no(format!("Allow {} to request credentials?", path), true)? {
+            callers.push(Caller {
+                path,
+                uid,
+                gid,
+                sha256: None,
+                wrapper: false,
+                setuid: false,
+            });
+        }
+    }
+    if callers.is_empty() && config_file.is_strict_caller() {
+        warn!(
+            "strict-caller is enabled but no callers are allowed, add them later using caller add"
+        );
+    }
+
+    println!("[6/6] Encrypting keys");
+    // like configure --encrypt "", an empty answer reuses the existing encryption profile
+    let has_encryptions = config_file.count_encryptions() > 0;
+    let encryption = prompt(
+        if has_encryptions {
+            "Encryption profile, e.g. challenge-response or piv (leave empty to use the existing one)"
+        } else {
+            "Encryption profile, e.g. challenge-response or piv (leave empty to skip)"
+        },
+        "",
+    )?;
+    let encrypted = has_encryptions || !encryption.is_empty();
+    if encrypted {
+        if has_encryptions && !encryption.is_empty() {
+            handle_secondary_encryption(&mut config_file)?;
+        }
+        config_file.add_encryption(&encryption)?;
+    }
+
+    // save new config
+    info!(
+        "Saving configuration to {}",
+        config_path.as_ref().to_string_lossy()
+    );
+    for caller in callers {
+        config_file.add_caller(caller, encrypted)?;
+    }
+    for rule in group_rules {
+        config_file.add_group_rule(rule);
+    }
+    let mut database = Database::new(database_id, id_seckey, group);
+    database.label = Some(association_name);
+    config_file.add_database(database, encrypted)?;
+    config_file.write_to(&config_path)?;
+    let helper = helper_command(&config_path, args);
+    println!(
+        "Done! Now run: git config --global credential.helper {}",
+        if helper.contains(' ') {
+            shell_quote(&helper)
+        } else {
+            helper
+        }
+    );
+
+    Ok(())
+}
+
+pub fn encrypt<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    let mut config_file = Config::read_from(&config_path)?;
+    verify_caller(&config_file)?;
+
+    let encryption = args
+        .subcommand_matches("encrypt")
+        .and_then(|m| m.value_of("ENCRYPTION_PROFILE"));
+
+    let count_databases_to_encrypt =
+        config_file.count_databases() - config_file.count_encrypted_databases();
+    let count_callers_to_encrypt =
+        config_file.count_callers() - config_file.count_encrypted_callers();
+    if count_databases_to_encrypt == 0
+        && count_callers_to_encrypt == 0
+        && encryption.map(|m| m.is_empty()).unwrap_or_else(|| true)
+    {
+        warn!("Database and callers profiles have already been encrypted");
+        return Ok(());
+    }
+    info!(
+        "{} database profile(s) to encrypt",
+        count_databases_to_encrypt
+    );
+    info!(
+        "{} caller profile(s) to encrypt",
+        count_databases_to_encrypt
+    );
+
+    if let Some(encryption) = encryption {
+        if config_file.count_encryptions() > 0 && !encryption.is_empty() {
+            handle_secondary_encryption(&mut config_file)?;
+        }
+        // this will error if an existing encryption profile has already been configured for the
+        // underlying hardware/etc
+        // in this case user should decrypt the configuration first
+        config_file.add_encryption(encryption)?;
+    }
+
+    let count_databases_encrypted = config_file.encrypt_databases()?;
+    let count_callers_encrypted = config_file.encrypt_callers()?;
+    info!(
+        "{} database profile(s) encrypted",
+        count_databases_encrypted
+    );
+    info!("{} caller profile(s) encrypted", count_callers_encrypted);
+
+    config_file.write_to(config_path)?;
+
+    Ok(())
+}
+
+pub fn decrypt<T: AsRef<Path>>(config_path: T) -> Result<()> {
+    let mut config_file = Config::read_from(&config_path)?;
+    verify_caller(&config_file)?;
+
+    let count_databases_to_decrypt = config_file.count_encrypted_databases();
+    let count_callers_to_decrypt = config_file.count_encrypted_callers();
+    if count_databases_to_decrypt == 0 && count_callers_to_decrypt == 0 {
+        warn!("Database and callers profiles have already been decrypted");
+        return Ok(());
+    }
+    info!(
+        "{} database profile(s) to decrypt",
+        count_databases_to_decrypt
+    );
+    info!("{} caller profile(s) to decrypt", count_callers_to_decrypt);
+
+    config_file.decrypt_databases()?;
+    config_file.decrypt_callers()?;
+    if config_file.count_encrypted_databases() == 0 && config_file.count_encrypted_callers() == 0 {
+        config_file.clear_encryptions();
+    }
+
+    config_file.write_to(config_path)?;
+
+    Ok(())
+}
+
+pub fn encryption<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let subcommand = args.subcommand_matches("encryption").unwrap();
+    match subcommand.subcommand() {
+        ("list", Some(list_args)) => {
+            let encryptions = config.list_encryptions();
+            print_list(&encryptions, list_args.value_of("format"), |encryption| {
+                let mut line = format!("{}, slot: {}", encryption.method, encryption.slot);
+                if let Some(serial) = encryption.serial {
+                    line.push_str(&format!(", serial: {}", serial));
+                }
+                if let Some(ref reader) = encryption.reader {
+                    line.push_str(&format!(", reader: {}", reader));
+                }
+                line
+            })
+        }
+        _ => Err(anyhow!("No subcommand selected")),
+    }
+}
@@ -0,0 +1,473 @@
+//! The Git credential helper operations: get, store and erase
+use super::{parse_git_request, read_git_request, verify_caller};
+use crate::backend::open_backend;
+use crate::cli::UnlockOptions;
+use crate::config::Config;
+use crate::errors::{is_no_match, HelperError};
+use crate::git::{get_url_host, run_helper, GitConfig, GitCredentialMessage, MessageReader};
+use crate::keepassxc::messages::*;
+use crate::logins::*;
+use crate::utils::*;
+use crate::{detail, error, info, toast, warn};
+use anyhow::{anyhow, Context, Error, Result};
+use clap::ArgMatches;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+/// Applies socket and unlock settings from Git configuration, unless they're given on the command
+/// line, and returns the unlock options to use
+pub fn apply_git_config(
+    args: &ArgMatches,
+    git_config: &GitConfig,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<Option<UnlockOptions>> {
+    if let (None, Some(path)) = (args.value_of("socket"), git_config.get("socket")) {
+        info!("Socket path is set in Git configuration");
+        detail!("Socket path is set to {} in Git configuration", path);
+        set_socket_path(expand_path(path)?);
+    }
+    match (args.value_of("unlock"), git_config.get("unlock")) {
+        (None, Some(git_unlock_options)) => {
+            info!("Database unlock option is set in Git configuration");
+            Ok(Some(
+                UnlockOptions::from_str(git_unlock_options).with_context(|| {
+                    format!("Invalid credential.keepassxcUnlock {}", git_unlock_options)
+                })?,
+            ))
+        }
+        _ => Ok(unlock_options.clone()),
+    }
+}
+
+pub fn get_logins<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let batch = args
+        .subcommand_matches("get")
+        .map(|m| m.is_present("batch"))
+        .unwrap_or(false);
+    let no_secrets = args
+        .subcommand_matches("get")
+        .map(|m| m.is_present("no-secrets"))
+        .unwrap_or(false);
+    let config = Config::read_from(config_path.as_ref())?;
+    let caller = verify_caller(&config)?;
+    let format_response = |(git_resp, login): (GitCredentialMessage, LoginEntry)| {
+        if no_secrets {
+            format_login_metadata(&git_resp, &login)
+        } else {
+            git_resp.to_string()
+        }
+    };
+    // the fallback helper responds with the password
+    let fallback = |git_req_string: &str, e| {
+        if no_secrets {
+            Err(e)
+        } else {
+            ask_fallback_helper(&config, git_req_string, e)
+        }
+    };
+    if !batch {
+        // read credential request
+        let (git_req, url) = read_git_request(config.get_stdin_timeout())?;
+        let git_req_string = git_req.to_string();
+        let git_config = GitConfig::read(&url);
+        let unlock_options = &apply_git_config(args, &git_config, unlock_options)?;
+        let (git_req, url, is_cert) = prepare_get_request(&config, git_req, url)?;
+        // parallel requests for the same URL (e.g. by git-lfs) would prompt the user once each
+        let _lock = lock_request(&url, REQUEST_LOCK_TIMEOUT);
+        check_interrupted()?;
+        // after waiting for the lock, so that the user isn't asked by each of them at once
+        approve_request(&config, &caller, &url)?;
+        let options = lookup_options(args, &config, &git_config)?;
+        let variant = no_match_cache_variant(&config, &options, &git_req);
+        let git_resp = with_no_match_cache(&config, &config_path, &url, &variant, || {
+            let backend = open_backend(&config, unlock_options)?;
+            find_login(&config, &options, backend.as_ref(), git_req, &url, is_cert)
+        })
+        .map(format_response)
+        .or_else(|e| fallback(&git_req_string, e))?;
+        io::stdout().write_all(git_resp.as_bytes())?;
+        return Ok(());
+    }
+
+    // the session is shared by all requests, so socket and unlock settings in Git configuration,
+    // which may differ by URL, are not applied in batches
+    let backend = open_backend(&config, unlock_options)?;
+    let reader = MessageReader::new(io::BufReader::new(io::stdin()));
+    let mut stdout = io::stdout();
+    let mut count = 0;
+    // the whole batch is approved (or denied) by the first request, instead of asking each time
+    let mut approved = None;
+    while let Some(git_req_string) = reader.read_message(config.get_stdin_timeout())? {
+        count += 1;
+        let git_resp = parse_git_request(&git_req_string).and_then(|(git_req, url)| {
+            let git_config = GitConfig::read(&url);
+            let (git_req, url, is_cert) = prepare_get_request(&config, git_req, url)?;
+            match approved {
+                Some(true) => {}
+                Some(false) => return Err(anyhow!("Credential request has been denied")),
+                None => {
+                    let approval = approve_request(&config, &caller, &url);
+                    approved = Some(approval.is_ok());
+                    approval?;
+                }
+            }
+            let options = lookup_options(args, &config, &git_config)?;
+            let variant = no_match_cache_variant(&config, &options, &git_req);
+            with_no_match_cache(&config, &config_path, &url, &variant, || {
+                find_login(&config, &options, backend.as_ref(), git_req, &url, is_cert)
+            })
+            .map(format_response)
+            .or_else(|e| fallback(&git_req_string, e))
+        });
+        match git_resp {
+            Ok(git_resp) => stdout.write_all(git_resp.as_bytes())?,
+            Err(e) => {
+                // an empty response tells the failure apart while keeping the rest in order
+                error!("Failed to answer request #{} in batch, {}", count, e);
+                stdout.write_all(b"\n")?;
+            }
+        }
+        // callers may wait for each response before sending the next request
+        stdout.flush()?;
+    }
+    info!("Answered {} request(s) in batch", count);
+    Ok(())
+}
+
+/// Key of the URL in the no-match cache, which is separate for each configuration file (profile)
+fn no_match_cache_key<T: AsRef<Path>>(config_path: T, url: &str) -> String {
+    format!("{}\n{}", config_path.as_ref().to_string_lossy(), url)
+}
+
+/// What else than the URL the lookup depends on, so that e.g. a lookup with another username or
+/// `--include-recycled` isn't answered by the no-match cache of a different one
+fn no_match_cache_variant(
+    config: &Config,
+    options: &LookupOptions,
+    git_req: &GitCredentialMessage,
+) -> String {
+    format!(
+        "username={}\ninclude_recycled={}\npinned_entry={}\ntarget_database={}\n",
+        git_req.username.as_deref().unwrap_or_default(),
+        options.include_recycled,
+        std::env::var(ENTRY_OVERRIDE_ENV).unwrap_or_default(),
+        config.get_target_database_name().unwrap_or_default()
+    )
+}
+
+/// Fails straight away if the URL had no matching logins within the TTL of the no-match cache, so
+/// that e.g. a rebase doesn't bring up KeePassXC over and over, and otherwise caches the result of
+/// the lookup if there are still none
+fn with_no_match_cache<T, P, F>(
+    config: &Config,
+    config_path: P,
+    url: &str,
+    variant: &str,
+    find: F,
+) -> Result<T>
+where
+    P: AsRef<Path>,
+    F: FnOnce() -> Result<T>,
+{
+    let ttl = match config.get_no_match_cache() {
+        Some(ttl) => ttl,
+        None => return find(),
+    };
+    let key = no_match_cache_key(config_path, url);
+    if is_no_match_cached(&key, variant, ttl) {
+        info!(
+            "No matching logins found within the last {}s",
+            ttl.as_secs()
+        );
+        explain(format!(
+            "No logins looked up for {}: none matched within the last {}s (no_match_cache)",
+            url,
+            ttl.as_secs()
+        ));
+        return Err(HelperError::NoMatchingLogins.into());
+    }
+    let result = find();
+    if let Err(ref e) = result {
+        if is_no_match(e) {
+            if let Err(e) = cache_no_match(&key, variant) {
+                warn!("Failed to cache the lookup without matching logins, {}", e);
+            }
+        }
+    }
+    result
+}
+
+/// Environment variable set for the fallback helper, so that it doesn't fall back again if it's
+/// git-credential-keepassxc itself (e.g. with another profile)
+const FALLBACK_HELPER_ENV: &str = "GIT_CREDENTIAL_KEEPASSXC_FALLBACK";
+
+/// Passes the request through to the fallback helper if there are no matching logins, otherwise
+/// returns the error as is
+fn ask_fallback_helper(config: &Config, git_req_string: &str, error: Error) -> Result<String> {
+    let no_match = is_no_match(&error);
+    let helper = match config.get_fallback_helper() {
+        Some(helper) if no_match && std::env::var_os(FALLBACK_HELPER_ENV).is_none() => helper,
+        _ => return Err(error),
+    };
+    info!(
+        "No matching logins found, asking fallback helper {}",
+        helper
+    );
+    let git_resp = run_helper(helper, "get", git_req_string, &[(FALLBACK_HELPER_ENV, "1")])?;
+    let git_resp = git_resp.trim_end();
+    if git_resp.is_empty() {
+        return Err(anyhow!("Fallback helper {} found no credential", helper));
+    }
+    // end with a blank line like our own responses, which batches rely on
+    Ok(format!("{}\n\n", git_resp))
+}
+
+/// URL to look up logins of instead of the requested one for `get`, on the same host
+const URL_OVERRIDE_ENV: &str = "GIT_CREDENTIAL_KEEPASSXC_URL";
+/// How long to wait for credential requests to be approved before denying them
+const APPROVAL_TIMEOUT_MS: u32 = 30000;
+
+/// Maps client certificates to entry URLs, applies host and username rules of the configuration
+/// file, and returns the request along with the URL to look up and whether it's a certificate
+pub fn prepare_get_request(
+    config: &Config,
+    mut git_req: GitCredentialMessage,
+    url: String,
+) -> Result<(GitCredentialMessage, String, bool)> {
+    let is_cert = git_req.protocol.as_deref() == Some("cert");
+    let url = if is_cert {
+        let path = git_req.path.as_deref().unwrap_or_default();
+        let cert_url = config
+            .get_certificate_url(path)
+            .ok_or_else(|| anyhow!("No entry URL configured for client certificate {}", path))?;
+        info!("Looking up passphrase of client certificate");
+        detail!(
+            "Looking up passphrase of client certificate {} using {}",
+            path,
+            cert_url
+        );
+        cert_url.to_owned()
+    } else {
+        match std::env::var(URL_OVERRIDE_ENV) {
+            Ok(override_url) if !override_url.is_empty() => {
+                info!("URL is overridden by environment variable");
+                detail!(
+                    "URL {} is overridden by environment variable: {}",
+                    url,
+                    override_url
+                );
+                Url::parse(&override_url).with_context(|| {
+                    format!("Invalid URL {} in {}", override_url, URL_OVERRIDE_ENV)
+                })?;
+                // otherwise a repository could have logins of any host sent to its server
+                if !get_url_host(&override_url).eq_ignore_ascii_case(&get_url_host(&url)) {
+                    return Err(anyhow!(
+                        "URL {} in {} is not on the requested host {}",
+                        override_url,
+                        URL_OVERRIDE_ENV,
+                        get_url_host(&url)
+                    ));
+                }
+                override_url
+            }
+            _ => url,
+        }
+    };
+    verify_host(config, &url)?;
+    if let Some(username) = config.get_username_override(&get_url_host(&url)) {
+        info!("Username is overridden by configuration file");
+        detail!("Username is overridden by configuration file: {}", username);
+        git_req.username = Some(username.to_owned());
+    }
+    Ok((git_req, url, is_cert))
+}
+
+/// Waits for the credential request to be approved if the configuration file asks for it, and
+/// otherwise notifies of requests by known callers
+pub fn approve_request(
+    config: &Config,
+    caller: &Option<(usize, PathBuf)>,
+    url: &str,
+) -> Result<()> {
+    let request_body = match caller {
+        Some((ppid, ppath)) => format!(
+            "{} ({}) has requested credential for {}",
+            ppath.file_name().unwrap_or_default().to_string_lossy(),
+            ppid,
+            url
+        ),
+        None => format!("Credential for {} has been requested", url),
+    };
+    if config.is_approve_requests() {
+        info!("Waiting for credential request to be approved");
+        if !toast::request_approval("Credential request", &request_body, APPROVAL_TIMEOUT_MS)? {
+            return Err(anyhow!("Credential request has been denied"));
+        }
+        info!("Credential request has been approved");
+    } else if caller.is_some() {
+        show_notification(
+            NotificationEvent::CredentialRequest,
+            "Credential request",
+            &request_body,
+            6000,
+        );
+    }
+    Ok(())
+}
+
+/// Describes the login that would be returned, without the password or token
+pub fn format_login_metadata(git_resp: &GitCredentialMessage, login: &LoginEntry) -> String {
+    let mut metadata = String::new();
+    if let Some(ref username) = git_resp.username {
+        metadata.push_str(&format!("username={}\n", username));
+    }
+    metadata.push_str(&format!("title={}\nuuid={}\n", login.name, login.uuid));
+    if let Some(ref database) = login.database {
+        metadata.push_str(&format!("database={}\n", database));
+    }
+    if let Some(ref expiry) = git_resp.password_expiry_utc {
+        metadata.push_str(&format!("password_expiry_utc={}\n", expiry));
+    }
+    metadata.push('\n');
+    metadata
+}
+
+/// Options of looking up logins for `get` and the like from command line and Git configuration
+pub fn lookup_options(
+    args: &ArgMatches,
+    config: &Config,
+    git_config: &GitConfig,
+) -> Result<LookupOptions> {
+    let subcommand = args.subcommand_matches("get");
+    let parent_domain_depth = args
+        .value_of("parent-domain-depth")
+        .or_else(|| git_config.get("parentDomainDepth"))
+        .map(|depth| {
+            u8::from_str(depth).with_context(|| format!("Invalid parent domain depth {}", depth))
+        })
+        .transpose()?;
+    Ok(LookupOptions {
+        include_recycled: subcommand.is_some_and(|m| m.is_present("include-recycled")),
+        // Git appends the action to credential.helper, so these have to be global options
+        no_filter_username: args.is_present("no-filter-username"),
+        prefer_token: args.is_present("prefer-token")
+            || git_config.get_bool("preferToken")?.unwrap_or(false),
+        advanced_fields: is_advanced_fields(args, config, git_config)?,
+        parent_domain_depth,
+        max_candidates: config.get_max_candidates(),
+    })
+}
+
+fn is_advanced_fields(args: &ArgMatches, config: &Config, git_config: &GitConfig) -> Result<bool> {
+    // Git appends the action to credential.helper, so this has to be a global option
+    Ok(args.is_present("advanced-fields")
+        || git_config
+            .get_bool("advancedFields")?
+            .unwrap_or_else(|| config.is_advanced_fields()))
+}
+
+/// Overrides `target_database` of the configuration file with the one from command line or Git
+/// config
+fn apply_target_database(args: &ArgMatches, config: &mut Config, git_config: &GitConfig) {
+    // Git appends the action to credential.helper, so this has to be a global option
+    if let Some(target_database) = args
+        .value_of("target-database")
+        .or_else(|| git_config.get("targetDatabase"))
+    {
+        config.set_target_database_name(Some(target_database.to_owned()));
+    }
+}
+
+pub fn store_login<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let mut config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+    // read credential request
+    let (git_req, url) = read_git_request(config.get_stdin_timeout())?;
+    let git_config = GitConfig::read(&url);
+    let unlock_options = &apply_git_config(args, &git_config, unlock_options)?;
+    apply_target_database(args, &mut config, &git_config);
+    // Git appends the action to credential.helper, so this has to be a global option
+    let create_only = args.is_present("create-only")
+        || git_config
+            .get_bool("createOnly")?
+            .unwrap_or_else(|| config.is_create_only());
+    let create_group = args.is_present("create-group")
+        || git_config
+            .get_bool("createGroup")?
+            .unwrap_or_else(|| config.is_create_group());
+    config.set_create_group(create_group);
+    if git_req.protocol.as_deref() == Some("cert") {
+        info!("Storing passphrases of client certificates is not supported, skipped");
+        return Ok(());
+    }
+    verify_host(&config, &url)?;
+    let backend = open_backend(&config, unlock_options)?;
+    let options = StoreOptions {
+        create_only,
+        conflict: config.get_store_conflict(),
+        marker: config.get_marker_field(),
+        require_allow_marker: config.is_require_allow_marker(),
+    };
+    let used_uuid = save_login(backend.as_ref(), &git_req, &url, &options)?;
+    clear_no_match(&no_match_cache_key(&config_path, &url));
+    // Git only stores logins which worked, so this is the one to prefer next time
+    if let Some(uuid) = used_uuid {
+        for username in &[None, git_req.username.as_deref()] {
+            let key = recent_entry_key(&url, *username);
+            if let Err(e) = save_recent_entry(&key, &uuid) {
+                warn!("Failed to remember the login used for {}: {}", key, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn erase_login<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let mut config = Config::read_from(config_path.as_ref()).unwrap_or_default();
+    let git_request = read_git_request(config.get_stdin_timeout());
+    let git_config = git_request
+        .as_ref()
+        .map(|(_, url)| GitConfig::read(url))
+        .unwrap_or_default();
+    // Git appends the action to credential.helper, so this has to be a global option
+    let allow_erase = args.is_present("allow-erase")
+        || git_config
+            .get_bool("allowErase")?
+            .unwrap_or_else(|| config.is_allow_erase());
+    let revoked_group = config.get_revoked_group().map(str::to_owned);
+    if !allow_erase && revoked_group.is_none() {
+        // Don't treat this as error as when server rejects a login Git may try to erase it.
+        // This is not desirable since sometimes it's merely a configuration issue, e.g. a lot
+        // of Git servers reject logins over HTTP(S) when SSH keys have been uploaded
+        info!(
+            "Erasing logins is disabled, see --allow-erase and revoked_group in configuration file"
+        );
+        return Ok(());
+    }
+    let (git_req, url) = git_request?;
+    verify_caller(&config)?;
+    let unlock_options = &apply_git_config(args, &git_config, unlock_options)?;
+    apply_target_database(args, &mut config, &git_config);
+    verify_host(&config, &url)?;
+    let backend = open_backend(&config, unlock_options)?;
+    match revoked_group {
+        Some(revoked_group) if !allow_erase => {
+            revoke_login(backend.as_ref(), &git_req, &url, &revoked_group)
+        }
+        _ => delete_logins(&config, backend.as_ref(), &git_req, &url),
+    }
+}
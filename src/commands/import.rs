@@ -0,0 +1,214 @@
+//! Importing logins from and exporting them to git-credential-store files
+use super::verify_caller;
+use crate::backend::open_backend;
+use crate::cli::UnlockOptions;
+use crate::config::Config;
+use crate::git::{strip_credential_url, StoredCredential};
+use crate::logins::*;
+use crate::utils::*;
+use crate::{error, info, warn};
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use url::Url;
+
+/// Default paths of git-credential-store files, see git-credential-store(1)
+fn get_git_credentials_paths() -> Result<Vec<PathBuf>> {
+    let base_dirs = directories_next::BaseDirs::new()
+        .ok_or_else(|| anyhow!("Failed to initialise base_dirs"))?;
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| base_dirs.home_dir().join(".config"));
+    Ok(vec![
+        base_dirs.home_dir().join(".git-credentials"),
+        config_home.join("git").join("credentials"),
+    ])
+}
+
+/// Overwrites the file before removing it, so that the plain text passwords are less likely to be
+/// recovered
+fn shred_file<T: AsRef<Path>>(path: T) -> Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(path.as_ref())?;
+    let length = file.metadata()?.len() as usize;
+    file.write_all(&vec![0u8; length])?;
+    file.sync_all()?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+fn import_git_credentials<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let path = match args.value_of("PATH") {
+        Some(path) => expand_path(path)?,
+        None => get_git_credentials_paths()?
+            .into_iter()
+            .find(|path| path.exists())
+            .ok_or_else(|| anyhow!("Failed to find git-credential-store file"))?,
+    };
+    info!("Importing credentials from {}", path.to_string_lossy());
+    let credentials = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.to_string_lossy()))?;
+
+    let backend = open_backend(&config, unlock_options)?;
+    let (mut imported, mut skipped, mut failed) = (0u32, 0u32, 0u32);
+    for (idx, line) in credentials.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let credential = match StoredCredential::from_str(line) {
+            Ok(credential) => credential,
+            Err(e) => {
+                // don't log the line itself as it contains the password
+                error!("Failed to parse line {}, {}", idx + 1, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let existing = backend.get_logins(&credential.url).unwrap_or_default();
+        if let Some(entry) = existing
+            .iter()
+            .find(|entry| entry.login == credential.username)
+        {
+            if entry.password != credential.password {
+                warn!(
+                    "Login {} for {} already exists with a different password, skipping",
+                    credential.username, credential.url
+                );
+            } else {
+                info!(
+                    "Login {} for {} already exists, skipping",
+                    credential.username, credential.url
+                );
+            }
+            skipped += 1;
+            continue;
+        }
+        match backend.store_login(
+            &credential.url,
+            &credential.username,
+            &credential.password,
+            None,
+            None,
+        ) {
+            Ok(_) => {
+                info!(
+                    "Imported login {} for {}",
+                    credential.username, credential.url
+                );
+                imported += 1;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to import login {} for {}, {}",
+                    credential.username, credential.url, e
+                );
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "Imported: {}, skipped (already exist): {}, failed: {}",
+        imported, skipped, failed
+    );
+
+    if args.is_present("shred") {
+        if failed > 0 {
+            return Err(anyhow!(
+                "Not shredding {} as some credentials failed to import",
+                path.to_string_lossy()
+            ));
+        }
+        shred_file(&path)?;
+        println!("Shredded {}", path.to_string_lossy());
+    }
+    Ok(())
+}
+
+pub fn export<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let export_args = args.subcommand_matches("export").unwrap();
+    if !export_args.is_present("i-understand-the-risk") {
+        return Err(anyhow!(
+            "Exporting writes passwords in plain text, pass --i-understand-the-risk to proceed"
+        ));
+    }
+    let config = Config::read_from(config_path.as_ref())?;
+    // the caller may well be one of the allowed ones, but then it'd be able to dump everything
+    if config.count_callers() > 0 || config.is_strict_caller() {
+        return Err(anyhow!(
+            "Exporting is not allowed when callers are limited, run caller clear first"
+        ));
+    }
+    let format = export_args.value_of("format").unwrap_or_default();
+    if format != "git-credentials" {
+        return Err(anyhow!("Unknown export format {}", format));
+    }
+    let urls: Vec<_> = export_args
+        .values_of("URL")
+        .ok_or_else(|| anyhow!("Must specify URL"))?
+        .collect();
+
+    eprintln!("WARNING: Passwords are going to be exported in PLAIN TEXT. Anyone who can read the output can use them. Remove the output as soon as it's no longer needed.");
+
+    let backend = open_backend(&config, unlock_options)?;
+    let marker = config.get_marker_field();
+    let mut credentials = Vec::new();
+    for url in urls {
+        let stripped_url = strip_credential_url(
+            &Url::parse(url).with_context(|| format!("Invalid URL {}", url))?,
+        )?;
+        let login_entries = backend.get_logins(&stripped_url)?;
+        let (kph_false, login_entries) = filter_kph_logins(&login_entries, &marker);
+        if kph_false > 0 {
+            info!("{} login(s) were hidden by KPH: {}", kph_false, marker.name);
+        }
+        let login_entries = if config.is_require_allow_marker() {
+            filter_allowed_logins(login_entries, &allow_identities())
+        } else {
+            login_entries
+        };
+        warn!(
+            "Exporting {} login(s) for {}",
+            login_entries.len(),
+            stripped_url
+        );
+        for entry in login_entries {
+            credentials.push(StoredCredential {
+                url: stripped_url.clone(),
+                username: entry.login.clone(),
+                password: entry.password.clone(),
+            });
+        }
+    }
+    for credential in credentials {
+        println!("{}", credential);
+    }
+    Ok(())
+}
+
+pub fn import<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let subcommand = args.subcommand_matches("import").unwrap();
+    match subcommand.subcommand() {
+        ("git-credentials", Some(import_args)) => {
+            import_git_credentials(config_path, import_args, unlock_options)
+        }
+        _ => Err(anyhow!("No subcommand selected")),
+    }
+}
@@ -0,0 +1,160 @@
+//! Looking up URLs, TOTPs and Auto-Type outside of Git requests
+use super::credential::{apply_git_config, approve_request, lookup_options, prepare_get_request};
+use super::{parse_git_request, read_git_request, shell_quote, verify_caller};
+use crate::backend::{open_backend, SecretBackend};
+use crate::cli::UnlockOptions;
+use crate::config::Config;
+use crate::git::{GitConfig, GitCredentialMessage};
+use crate::keepassxc::messages::*;
+use crate::logins::*;
+use crate::session::{associated_databases, start_session};
+use crate::{detail, info};
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use url::Url;
+
+#[derive(Serialize)]
+struct UrlLogin<'a> {
+    url: &'a str,
+    username: &'a str,
+    password: &'a str,
+    title: &'a str,
+    uuid: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    database: Option<&'a str>,
+}
+
+/// Prints the login of the URL given on the command line, which is looked up like `get` does
+pub fn get_url<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    let caller = verify_caller(&config)?;
+
+    let subcommand = args.subcommand_matches("get-url").unwrap();
+    let url = subcommand
+        .value_of("URL")
+        .ok_or_else(|| anyhow!("Must specify URL"))?;
+    let username = subcommand.value_of("username");
+    if url.contains('\n') || username.is_some_and(|u| u.contains('\n')) {
+        return Err(anyhow!("URL and username must not contain line breaks"));
+    }
+    // same as a request from Git, so that logins are filtered the same way
+    let mut git_req_string = format!("url={}\n", url);
+    if let Some(username) = username {
+        git_req_string.push_str(&format!("username={}\n", username));
+    }
+    let (git_req, url) = parse_git_request(&git_req_string)?;
+    let git_config = GitConfig::read(&url);
+    let unlock_options = &apply_git_config(args, &git_config, unlock_options)?;
+    let (git_req, url, is_cert) = prepare_get_request(&config, git_req, url)?;
+    approve_request(&config, &caller, &url)?;
+    let backend = open_backend(&config, unlock_options)?;
+    let (git_resp, login) = find_login(
+        &config,
+        &lookup_options(args, &config, &git_config)?,
+        backend.as_ref(),
+        git_req,
+        &url,
+        is_cert,
+    )?;
+
+    let username = git_resp.username.as_deref().unwrap_or_default();
+    let password = git_resp.password.as_deref().unwrap_or_default();
+    let output = match subcommand.value_of("format") {
+        Some("json") => serde_json::to_string_pretty(&UrlLogin {
+            url: &url,
+            username,
+            password,
+            title: &login.name,
+            uuid: &login.uuid,
+            database: login.database.as_deref(),
+        })?,
+        Some("shell") => format!(
+            "USERNAME={}\nPASSWORD={}",
+            shell_quote(username),
+            shell_quote(password)
+        ),
+        _ => password.to_owned(),
+    };
+    println!("{}", output);
+    Ok(())
+}
+
+/// Prints the current TOTP code of the login that `get` would return for the request on stdin
+pub fn get_totp<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    let caller = verify_caller(&config)?;
+
+    let (git_req, url) = read_git_request(config.get_stdin_timeout())?;
+    let git_config = GitConfig::read(&url);
+    let unlock_options = &apply_git_config(args, &git_config, unlock_options)?;
+    let (git_req, url, _) = prepare_get_request(&config, git_req, url)?;
+    approve_request(&config, &caller, &url)?;
+    let backend = open_backend(&config, unlock_options)?;
+    let totp = find_totp(args, &config, &git_config, backend.as_ref(), git_req, &url)?;
+    io::stdout().write_all(format!("{}\n", totp).as_bytes())?;
+    Ok(())
+}
+
+pub fn find_totp(
+    args: &ArgMatches,
+    config: &Config,
+    git_config: &GitConfig,
+    backend: &dyn SecretBackend,
+    git_req: GitCredentialMessage,
+    url: &str,
+) -> Result<String> {
+    let (_, login) = find_login(
+        config,
+        &lookup_options(args, config, git_config)?,
+        backend,
+        git_req,
+        url,
+        false,
+    )?;
+    info!("Requesting TOTP of the matching entry");
+    detail!("Requesting TOTP of entry {} ({})", login.name, login.uuid);
+    backend.get_totp(&login)
+}
+
+pub fn request_autotype<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let url = args
+        .subcommand_matches("request-autotype")
+        .and_then(|m| m.value_of("URL"))
+        .ok_or_else(|| anyhow!("Must specify URL"))?;
+    let url = Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+    let search = url
+        .host_str()
+        .ok_or_else(|| anyhow!("URL {} doesn't contain a host", url))?;
+
+    // start session
+    let (client_id, _, _) = start_session()?;
+    // make sure we're talking to an associated database before triggering Auto-Type
+    associated_databases(&config, &client_id, unlock_options)?;
+
+    info!("Requesting Auto-Type for {}", search);
+    let rat_req = RequestAutotypeRequest::new(search);
+    let rat_resp = rat_req.send(&client_id, false)?;
+    if rat_resp.success.map(|s| s.0).unwrap_or(false) {
+        Ok(())
+    } else {
+        Err(anyhow!("Auto-Type request failed"))
+    }
+}
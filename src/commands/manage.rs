@@ -0,0 +1,495 @@
+//! Managing caller profiles and database associations in the configuration file, and checking them
+use super::configure::get_preset_caller_paths;
+use super::{print_list, verify_caller};
+use crate::cli::UnlockOptions;
+use crate::config::{Caller, Config, Database};
+use crate::errors::HelperError;
+use crate::keepassxc::{errors::*, messages::*};
+use crate::session::{associated_databases, find_group, get_root_group, start_session};
+use crate::utils::*;
+use crate::{detail, info, warn};
+use anyhow::{anyhow, Context, Result};
+use clap::ArgMatches;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub fn caller<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    // read existing or create new config
+    let mut config_file = if let Ok(config_file) = Config::read_from(&config_path) {
+        verify_caller(&config_file)?;
+        config_file
+    } else {
+        Config::new()
+    };
+
+    let subcommand = args.subcommand_matches("caller").unwrap();
+    match subcommand.subcommand() {
+        ("add", Some(add_args)) => {
+            let paths = match (add_args.value_of("PATH"), add_args.value_of("preset")) {
+                (_, Some(preset)) => get_preset_caller_paths(preset)?
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .collect(),
+                (Some(path), None) => {
+                    let resolved = match resolve_executable(path) {
+                        Ok(resolved) => resolved,
+                        Err(e) if add_args.is_present("allow-missing") => {
+                            warn!("{}, adding it anyway", e);
+                            absolute_path(path)?
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    let resolved = resolved.to_string_lossy().into_owned();
+                    if resolved != path {
+                        info!("Caller {} is resolved to {}", path, resolved);
+                    }
+                    vec![resolved]
+                }
+                (None, None) => return Err(anyhow!("Must specify path")),
+            };
+            let uid = if let Some(id) = add_args.value_of("uid") {
+                Some(u32::from_str(id).map_err(|_| anyhow!("Invalid UID"))?)
+            } else {
+                None
+            };
+            let gid = if let Some(id) = add_args.value_of("gid") {
+                Some(u32::from_str(id).map_err(|_| anyhow!("Invalid GID"))?)
+            } else {
+                None
+            };
+            let mut callers = Vec::new();
+            for path in paths {
+                let path = {
+                    let normalized = normalize_caller_path(&path).to_string_lossy().into_owned();
+                    if normalized != path {
+                        info!("Caller {} is resolved to {}", path, normalized);
+                    }
+                    normalized
+                };
+                #[cfg(windows)]
+                if add_args.is_present("hash") {
+                    return Err(anyhow!(CALLER_HASH_UNSUPPORTED));
+                }
+                let sha256 = if add_args.is_present("hash") {
+                    let file = std::fs::File::open(&path)
+                        .with_context(|| format!("Failed to read {}", path))?;
+                    Some(sha256_hex(file)?)
+                } else {
+                    None
+                };
+                callers.push(Caller {
+                    path,
+                    uid,
+                    gid,
+                    sha256,
+                    wrapper: add_args.is_present("wrapper"),
+                    setuid: add_args.is_present("setuid"),
+                });
+            }
+            let encryption = subcommand
+                .subcommand_matches("add")
+                .and_then(|m| m.value_of("encrypt"));
+            if let Some(encryption) = encryption {
+                // this will error if an existing encryption profile has already been configured for the
+                // underlying hardware/etc
+                // in this case user should decrypt the configuration first
+                config_file.add_encryption(encryption)?;
+            }
+            let describe_id =
+                |id: Option<u32>| id.map_or_else(|| "any".to_owned(), |id| id.to_string());
+            for caller in callers {
+                if cfg!(windows) {
+                    println!("Adding caller {}", caller.path);
+                } else {
+                    println!(
+                        "Adding caller {} (UID: {}, GID: {})",
+                        caller.path,
+                        describe_id(caller.uid),
+                        describe_id(caller.gid)
+                    );
+                }
+                config_file.add_caller(caller, encryption.is_some())?;
+            }
+            config_file.write_to(config_path)
+        }
+        ("list", Some(list_args)) => {
+            let callers = config_file.get_callers()?;
+            print_list(&callers, list_args.value_of("format"), |caller| {
+                let mut line = caller.path.clone();
+                if let Some(uid) = caller.uid {
+                    line.push_str(&format!(", UID: {}", uid));
+                }
+                if let Some(gid) = caller.gid {
+                    line.push_str(&format!(", GID: {}", gid));
+                }
+                if let Some(ref sha256) = caller.sha256 {
+                    line.push_str(&format!(", SHA-256: {}", sha256));
+                }
+                if caller.wrapper {
+                    line.push_str(", wrapper");
+                }
+                if caller.setuid {
+                    line.push_str(", set-user-ID");
+                }
+                line
+            })
+        }
+        ("clear", _) => {
+            config_file.clear_callers();
+            config_file.write_to(config_path)
+        }
+        _ => Err(anyhow!("No subcommand selected")),
+    }
+}
+
+pub fn db<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    let mut config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+
+    let subcommand = args.subcommand_matches("db").unwrap();
+    match subcommand.subcommand() {
+        ("list", Some(list_args)) => {
+            let databases = config.list_databases()?;
+            print_list(&databases, list_args.value_of("format"), |database| {
+                let mut line = database.id.clone();
+                if let Some(ref label) = database.label {
+                    line.push_str(&format!(" ({})", label));
+                }
+                line.push_str(&format!(
+                    ", group: {} ({})",
+                    database.group, database.group_uuid
+                ));
+                if let Some(ref unlock) = database.unlock {
+                    line.push_str(&format!(
+                        ", unlock: {},{}",
+                        unlock.max_retries, unlock.interval
+                    ));
+                }
+                if database.encrypted {
+                    line.push_str(", encrypted");
+                }
+                line
+            })
+        }
+        ("remove", Some(remove_args)) => {
+            let database = config.find_database(remove_args.value_of("ID").unwrap())?;
+            config.remove_database(&database.id)?;
+            config.write_to(config_path)?;
+            println!(
+                "Removed {}",
+                database.label.as_ref().unwrap_or(&database.id)
+            );
+            warn!("The removed association is still stored in KeePassXC, remove it in Database -> Database Settings -> Browser Integration");
+            Ok(())
+        }
+        ("set-group", Some(set_group_args)) => {
+            let mut database = config.find_database(set_group_args.value_of("ID").unwrap())?;
+            let name = database
+                .label
+                .clone()
+                .unwrap_or_else(|| database.id.clone());
+            let (client_id, _, _) = start_session()?;
+            // groups are looked up and created in the currently opened database
+            let hash = get_current_database_hash(&client_id)?;
+            if !is_current_database(&client_id, &database, &hash) {
+                return Err(anyhow!(
+                    "Database {} isn't the one currently opened in KeePassXC, open and unlock it first",
+                    name
+                ));
+            }
+            let group_name = set_group_args.value_of("GROUP").unwrap();
+            let create_group = args.is_present("create-group") || config.is_create_group();
+            let group = find_group(&client_id, group_name, create_group)?;
+            database.group = group.name;
+            database.group_uuid = group.uuid;
+            config.replace_database(&database.id.clone(), database)?;
+            config.write_to(config_path)?;
+            println!("New logins of {} are stored in group {}", name, group_name);
+            Ok(())
+        }
+        ("dedupe", Some(dedupe_args)) => {
+            let (client_id, _, _) = start_session()?;
+            let hash = get_current_database_hash(&client_id)?;
+            let associated: Vec<_> = config
+                .get_databases()?
+                .into_iter()
+                .filter(|database| is_current_database(&client_id, database, &hash))
+                .collect();
+            if associated.len() < 2 {
+                println!("No redundant associations with the current database found");
+                return Ok(());
+            }
+            // profiles without a creation time predate the ones with, and encrypted ones are
+            // listed after plain ones regardless of age, so the order only breaks ties
+            let (kept_idx, kept) = associated
+                .iter()
+                .enumerate()
+                .max_by_key(|(idx, database)| (database.created, *idx))
+                .unwrap();
+            let kept_name = kept.label.as_ref().unwrap_or(&kept.id);
+            let redundant = associated
+                .iter()
+                .enumerate()
+                .filter(|(idx, _)| *idx != kept_idx)
+                .map(|(_, database)| database);
+            for database in redundant {
+                let name = database.label.as_ref().unwrap_or(&database.id);
+                if dedupe_args.is_present("dry-run") {
+                    println!("Would remove {} (keeping {})", name, kept_name);
+                } else {
+                    config.remove_database(&database.id)?;
+                    println!("Removed {} (keeping {})", name, kept_name);
+                }
+            }
+            if !dedupe_args.is_present("dry-run") {
+                config.write_to(config_path)?;
+                warn!("The removed associations are still stored in KeePassXC, remove them in Database -> Database Settings -> Browser Integration");
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!("No subcommand selected")),
+    }
+}
+
+/// Hash of the database currently opened in KeePassXC
+fn get_current_database_hash(client_id: &str) -> Result<String> {
+    let hash = GetDatabaseHashRequest::new()
+        .send(client_id, false)?
+        .hash
+        .ok_or_else(|| anyhow!("KeePassXC didn't return the database hash"))?;
+    detail!("Database hash: {}", hash);
+    Ok(hash)
+}
+
+/// Whether the association of the database profile is with the currently opened database of the
+/// given hash
+fn is_current_database(client_id: &str, database: &Database, hash: &str) -> bool {
+    // test-associate only succeeds for associations with the currently opened database
+    TestAssociateRequest::new(database.id.as_str(), database.pkey.as_str())
+        .send(client_id, false)
+        .ok()
+        .filter(|taso_resp| taso_resp.hash.as_deref() == Some(hash))
+        .and_then(|taso_resp| taso_resp.success)
+        .map(Into::into)
+        .unwrap_or(false)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum AssociationCheckResult {
+    Ok,
+    /// The association has been removed from KeePassXC
+    Revoked,
+    Locked,
+    Error,
+}
+
+#[derive(Serialize)]
+struct AssociationCheck {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    result: AssociationCheckResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+/// Sends test-associate for every database profile and reports the results
+pub fn check<T: AsRef<Path>>(
+    config_path: T,
+    args: &ArgMatches,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let subcommand_args = args
+        .subcommand_matches("check")
+        .ok_or_else(|| anyhow!("No subcommand selected"))?;
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+    let (client_id, _, _) = start_session()?;
+
+    let mut checks = Vec::new();
+    for database in config.get_databases()? {
+        let unlock_options = database.unlock.as_ref().or(unlock_options.as_ref());
+        let taso_req = TestAssociateRequest::new(database.id.as_str(), database.pkey.as_str());
+        let (result, message) = match taso_req.send(&client_id, unlock_options.is_some()) {
+            Ok(taso_resp) if taso_resp.success.clone().map(Into::into).unwrap_or(false) => {
+                (AssociationCheckResult::Ok, None)
+            }
+            Ok(_) => (
+                AssociationCheckResult::Revoked,
+                Some("KeePassXC doesn't recognise the association".to_owned()),
+            ),
+            Err(e) => match e.downcast_ref::<KeePassError>() {
+                Some(keepass_error) if keepass_error.is_association_revoked() => (
+                    AssociationCheckResult::Revoked,
+                    Some("Association has been removed from KeePassXC".to_owned()),
+                ),
+                Some(keepass_error) if keepass_error.is_database_locked() => (
+                    AssociationCheckResult::Locked,
+                    Some("Database is locked".to_owned()),
+                ),
+                _ => (AssociationCheckResult::Error, Some(e.to_string())),
+            },
+        };
+        checks.push(AssociationCheck {
+            id: database.id.clone(),
+            label: database.label.clone(),
+            result,
+            message,
+        });
+    }
+
+    if subcommand_args.value_of("format") == Some("json") {
+        println!("{}", serde_json::to_string_pretty(&checks)?);
+    } else {
+        if checks.is_empty() {
+            println!("No databases configured");
+        }
+        for check in &checks {
+            let name = check.label.as_ref().unwrap_or(&check.id);
+            match check.message {
+                Some(ref message) => println!("{}: FAILED ({})", name, message),
+                None => println!("{}: OK", name),
+            }
+        }
+    }
+
+    let failed = checks
+        .iter()
+        .filter(|check| !matches!(check.result, AssociationCheckResult::Ok))
+        .count();
+    if failed > 0 && subcommand_args.is_present("exit-code") {
+        return Err(HelperError::AssociationCheckFailed(failed).into());
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Status {
+    version: String,
+    config_path: PathBuf,
+    config_exists: bool,
+    socket_path: Option<PathBuf>,
+    databases: usize,
+    encrypted_databases: usize,
+    callers: usize,
+    encrypted_callers: usize,
+    encryptions: usize,
+    strict_caller: bool,
+}
+
+/// Summarises configuration without decrypting anything, so no caller verification either
+pub fn status<T: AsRef<Path>>(config_path: T, args: &ArgMatches) -> Result<()> {
+    let config_exists = config_path.as_ref().exists();
+    let config = if config_exists {
+        Config::read_from(config_path.as_ref())?
+    } else {
+        Config::new()
+    };
+    let status = Status {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        config_path: config_path.as_ref().to_owned(),
+        config_exists,
+        socket_path: get_socket_path().ok(),
+        databases: config.count_databases(),
+        encrypted_databases: config.count_encrypted_databases(),
+        callers: config.count_callers(),
+        encrypted_callers: config.count_encrypted_callers(),
+        encryptions: config.count_encryptions(),
+        strict_caller: config.is_strict_caller(),
+    };
+
+    let format = args
+        .subcommand_matches("status")
+        .and_then(|m| m.value_of("format"));
+    if format == Some("json") {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        println!("Version: {}", status.version);
+        println!(
+            "Configuration file: {}{}",
+            status.config_path.to_string_lossy(),
+            if status.config_exists {
+                ""
+            } else {
+                " (not found)"
+            }
+        );
+        match status.socket_path {
+            Some(ref socket_path) => println!("Socket: {}", socket_path.to_string_lossy()),
+            None => println!("Socket: (failed to locate)"),
+        }
+        println!(
+            "Databases: {} ({} encrypted)",
+            status.databases, status.encrypted_databases
+        );
+        println!(
+            "Callers: {} ({} encrypted)",
+            status.callers, status.encrypted_callers
+        );
+        println!("Encryption profiles: {}", status.encryptions);
+        println!("Strict caller: {}", status.strict_caller);
+    }
+    Ok(())
+}
+
+pub fn groups<T: AsRef<Path>>(
+    config_path: T,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<()> {
+    let config = Config::read_from(config_path.as_ref())?;
+    verify_caller(&config)?;
+    // start session
+    let (client_id, _, _) = start_session()?;
+    associated_databases(&config, &client_id, unlock_options)?;
+
+    let root_group = get_root_group(&client_id)?;
+    io::stdout().write_all(root_group.to_tree_string().as_bytes())?;
+
+    Ok(())
+}
+
+fn discover_sockets() -> Result<()> {
+    let mut paths = get_candidate_socket_paths()?;
+    // the one specified by user, or the one that would be used by default
+    let current_path = get_socket_path()?;
+    if !paths.contains(&current_path) {
+        paths.insert(0, current_path);
+    }
+    let session_pubkey = generate_secret_key().public_key();
+    let (_, client_id) = nacl_nonce();
+    for path in paths {
+        let status = if !path.exists() {
+            "not found".to_owned()
+        } else {
+            set_socket_path(path.clone());
+            match exchange_keys(&client_id, &session_pubkey) {
+                Ok(_) => "exists, key exchange succeeded".to_owned(),
+                Err(e) => format!("exists, key exchange failed: {}", e),
+            }
+        };
+        println!("{}: {}", path.to_string_lossy(), status);
+    }
+    Ok(())
+}
+
+pub fn config_command(args: &ArgMatches) -> Result<()> {
+    let subcommand = args.subcommand_matches("config").unwrap();
+    match subcommand.subcommand() {
+        ("schema", _) => {
+            print!("{}", Config::json_schema()?);
+            Ok(())
+        }
+        _ => Err(anyhow!("No subcommand selected")),
+    }
+}
+
+pub fn socket(args: &ArgMatches) -> Result<()> {
+    let subcommand = args.subcommand_matches("socket").unwrap();
+    match subcommand.subcommand() {
+        ("discover", _) => discover_sockets(),
+        _ => Err(anyhow!("No subcommand selected")),
+    }
+}
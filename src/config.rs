@@ -1,18 +1,33 @@
+use crate::cli::UnlockOptions;
+use crate::utils::expand_path;
+#[cfg(any(feature = "yubikey", feature = "yubikey-ccid"))]
+use crate::utils::show_notification;
+#[cfg(feature = "encryption")]
+use crate::utils::{cache_secret, read_cached_secret, sleep_interruptible};
+use crate::utils::{check_interrupted, InterruptGuard, NotificationEvent};
 #[allow(unused_imports)]
-use crate::{debug, error, info, warn};
+use crate::{debug, detail, error, info, warn};
 use aes_gcm::aead::generic_array::{typenum, GenericArray};
 use anyhow::{anyhow, Context, Result};
 #[cfg(test)]
 use mockall::automock;
+use schemars::JsonSchema;
 use serde::{de, Deserialize, Serialize};
 use std::cell::RefCell;
 use std::fs;
 use std::io::prelude::*;
 #[cfg(unix)]
 use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 
+#[cfg(feature = "piv")]
+use {
+    crate::utils::save_terminal_state,
+    p256::elliptic_curve::sec1::ToEncodedPoint,
+    sha2::{Digest, Sha256},
+    std::ffi::CString,
+};
 #[cfg(feature = "encryption")]
 use {
     aes_gcm::aead::{Aead, NewAead},
@@ -22,28 +37,131 @@ use {
     std::str::FromStr,
 };
 #[cfg(feature = "yubikey")]
-use {
-    yubico_manager::config as yubico_config, yubico_manager::yubicoerror::YubicoError,
-    yubico_manager::Yubico,
-};
+use {yubico_manager::config as yubico_config, yubico_manager::Yubico};
 
 #[cfg(unix)]
 const DEFAULT_CONFIG_MODE: u32 = 0o600;
+/// Seconds to wait for a (hardware) token to be plugged in
+#[cfg(feature = "encryption")]
+const DEFAULT_TOKEN_TIMEOUT: u64 = 30;
+/// Seconds to wait for Git to send the credential request
+const DEFAULT_STDIN_TIMEOUT: u64 = 30;
 
 #[cfg(any(feature = "encryption", feature = "yubikey"))]
 const HMAC_SHA1_CHALLENGE_LENGTH: usize = 64usize;
-#[cfg(all(feature = "encryption", feature = "yubikey"))]
+#[cfg(all(
+    feature = "encryption",
+    any(feature = "yubikey", feature = "yubikey-ccid")
+))]
 const HMAC_SHA1_RESPONSE_LENGTH: usize = 20usize;
 #[cfg(feature = "encryption")]
 const AES_KEY_LENGTH: usize = 32usize;
 #[cfg(feature = "encryption")]
 const AES_NONCE_LENGTH: usize = 12usize;
+/// SELECT the YubiKey OTP application
+#[cfg(feature = "yubikey-ccid")]
+const YUBIKEY_OTP_SELECT_APDU: [u8; 12] = [
+    0x00, 0xa4, 0x04, 0x00, 0x07, 0xa0, 0x00, 0x00, 0x05, 0x27, 0x20, 0x01,
+];
+#[cfg(feature = "yubikey-ccid")]
+const YUBIKEY_CMD_DEVICE_SERIAL: u8 = 0x10;
+#[cfg(feature = "yubikey-ccid")]
+const YUBIKEY_CMD_CHALLENGE_HMAC_1: u8 = 0x30;
+#[cfg(feature = "yubikey-ccid")]
+const YUBIKEY_CMD_CHALLENGE_HMAC_2: u8 = 0x38;
+/// Key management slot
+#[cfg(feature = "piv")]
+const PIV_DEFAULT_SLOT: u8 = 0x9d;
+#[cfg(feature = "piv")]
+const PIV_ALGORITHM_ECC_P256: u8 = 0x11;
+#[cfg(feature = "piv")]
+const PIV_SELECT_APDU: [u8; 15] = [
+    0x00, 0xa4, 0x04, 0x00, 0x09, 0xa0, 0x00, 0x00, 0x03, 0x08, 0x00, 0x00, 0x10, 0x00, 0x00,
+];
 
 type AesKey = GenericArray<u8, typenum::U32>;
 type AesNonce = GenericArray<u8, typenum::U12>;
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+/// Configuration file of git-credential-keepassxc
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug)]
+// unknown keys are only warned about unless strict_config is set, but the schema rejects them, also
+// in nested objects, so that typos are caught before deploying the configuration
+#[schemars(deny_unknown_fields)]
 pub struct Config {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strict_config: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    socket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unlock: Option<UnlockOptions>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unlock_dialog: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strict_caller: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    canonicalize_callers: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notifications: Option<Vec<NotificationEvent>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    approve_requests: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_domain_depth: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    public_suffix_list: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    new_entry_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback_helper: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recycle_bin_group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revoked_group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    marker_field: Option<MarkerField>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    require_allow_marker: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    create_only: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    create_group: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allow_erase: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    advanced_fields: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    advanced_field_names: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    store_conflict: Option<StoreConflict>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token_cache: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_keepassxc_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    no_match_cache: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_candidates: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stdin_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_hosts: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    usernames: Vec<UsernameRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    certificates: Vec<CertificateRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pinned_entries: Vec<PinnedEntryRule>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    group_rules: Vec<GroupRule>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sudo_entry: Option<SudoEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ssh_key_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    database_priority: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target_database: Option<String>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     databases: Vec<Database>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
@@ -76,21 +194,80 @@ impl Config {
                 config_path.as_ref().to_string_lossy()
             )
         })?;
-        let config: Config = serde_json::from_str(&json).with_context(|| {
+        let (config, unknown_keys) = Self::from_json(&json).with_context(|| {
             format!(
                 "Invalid configuration file {}",
                 config_path.as_ref().to_string_lossy()
             )
         })?;
+        if !unknown_keys.is_empty() {
+            if config.is_strict_config() {
+                return Err(anyhow!(
+                    "Unknown key(s) in configuration file {}: {}",
+                    config_path.as_ref().to_string_lossy(),
+                    unknown_keys.join(", ")
+                ));
+            }
+            for key in &unknown_keys {
+                warn!("Unknown key {} in configuration file is ignored", key);
+            }
+        }
         Ok(config)
     }
 
+    /// Parses the configuration along with the paths of keys it doesn't know, e.g. typos like
+    /// `calers`, which would otherwise silently leave the option at its default
+    fn from_json(json: &str) -> Result<(Self, Vec<String>)> {
+        let mut unknown_keys = Vec::new();
+        let config =
+            serde_ignored::deserialize(&mut serde_json::Deserializer::from_str(json), |path| {
+                unknown_keys.push(path.to_string())
+            })?;
+        Ok((config, unknown_keys))
+    }
+
+    /// Pretty-printed JSON with keys sorted alphabetically and a trailing newline, so that the
+    /// file only changes along with the configuration, e.g. when it's kept in a dotfiles repository
+    pub fn to_canonical_json(&self) -> Result<String> {
+        // objects of serde_json::Value are sorted maps, unlike the declaration order of structs
+        let value = serde_json::to_value(self)?;
+        let mut json = serde_json::to_string_pretty(&value)?;
+        json.push('\n');
+        Ok(json)
+    }
+
+    /// JSON Schema (draft-07) of the configuration file, for validating it elsewhere, e.g. in CI
+    pub fn json_schema() -> Result<String> {
+        let schema = schemars::schema_for!(Config);
+        let mut json = serde_json::to_string_pretty(&schema)?;
+        json.push('\n');
+        Ok(json)
+    }
+
     pub fn write_to<T: AsRef<Path>>(&self, config_path: T) -> Result<()> {
+        let json = self.to_canonical_json()?;
+        if fs::read_to_string(config_path.as_ref()).ok().as_deref() == Some(json.as_str()) {
+            debug!(
+                "Configuration in {} is unchanged, not writing it",
+                config_path.as_ref().to_string_lossy()
+            );
+            return Ok(());
+        }
         info!(
             "Writing configuration to {}",
             config_path.as_ref().to_string_lossy()
         );
-        let json = serde_json::to_string_pretty(self)?;
+        // the configuration is replaced by a complete temporary file so that it's never left
+        // half-written, and SIGINT or SIGTERM is only handled after that
+        let _guard = InterruptGuard::new();
+        // write through symbolic links, e.g. to a dotfiles repository
+        let target_path =
+            fs::canonicalize(config_path.as_ref()).unwrap_or_else(|_| config_path.as_ref().into());
+        let temp_path = {
+            let mut temp_path = target_path.clone().into_os_string();
+            temp_path.push(".tmp");
+            PathBuf::from(temp_path)
+        };
         let mut file_options = fs::OpenOptions::new();
         #[cfg(unix)]
         file_options.mode(DEFAULT_CONFIG_MODE);
@@ -98,21 +275,323 @@ impl Config {
             .create(true)
             .write(true)
             .truncate(true)
-            .open(config_path.as_ref())
+            .open(&temp_path)
             .with_context(|| {
                 format!(
                     "Failed to open configuration to {}",
-                    config_path.as_ref().to_string_lossy()
+                    temp_path.to_string_lossy()
                 )
             })?;
 
-        file.write_all(&json.as_bytes()).with_context(|| {
+        // keep the permissions of an existing configuration file
+        let written = fs::metadata(&target_path)
+            .map_or(Ok(()), |metadata| {
+                file.set_permissions(metadata.permissions())
+            })
+            .and_then(|_| file.write_all(json.as_bytes()))
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&temp_path, &target_path));
+        if written.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        written.with_context(|| {
             format!(
                 "Failed to write configuration to {}",
                 config_path.as_ref().to_string_lossy()
             )
         })?;
-        Ok(())
+        check_interrupted()
+    }
+
+    pub fn get_socket(&self) -> Option<&str> {
+        self.socket.as_deref()
+    }
+
+    pub fn set_socket(&mut self, socket: Option<String>) {
+        self.socket = socket;
+    }
+
+    pub fn get_unlock(&self) -> Option<&UnlockOptions> {
+        self.unlock.as_ref()
+    }
+
+    /// Whether a system dialog asks to unlock a locked database when there's no terminal, e.g. for
+    /// fetches started by IDEs, disabled by default
+    pub fn is_unlock_dialog(&self) -> bool {
+        self.unlock_dialog.unwrap_or(false)
+    }
+
+    /// Events to show desktop notifications for, all by default
+    pub fn get_notifications(&self) -> Option<&[NotificationEvent]> {
+        self.notifications.as_deref()
+    }
+
+    /// Whether credential requests wait for approval via a toast notification (Windows only),
+    /// disabled by default
+    pub fn is_approve_requests(&self) -> bool {
+        self.approve_requests.unwrap_or(false)
+    }
+
+    /// Whether caller profiles are required when there are databases, defaults to whether
+    /// strict-caller feature is enabled
+    pub fn is_strict_caller(&self) -> bool {
+        self.strict_caller
+            .unwrap_or(cfg!(feature = "strict-caller"))
+    }
+
+    /// Whether unknown keys in the configuration file are errors rather than warnings, disabled by
+    /// default
+    pub fn is_strict_config(&self) -> bool {
+        self.strict_config.unwrap_or(false)
+    }
+
+    /// Whether symbolic links in caller paths are resolved before comparison, enabled by default
+    pub fn is_canonicalize_callers(&self) -> bool {
+        self.canonicalize_callers.unwrap_or(true)
+    }
+
+    /// How many levels of parent domains to try when there are no matching logins, none by default
+    pub fn get_parent_domain_depth(&self) -> u8 {
+        self.parent_domain_depth.unwrap_or(0)
+    }
+
+    /// Path of the public suffix list which parent domains stop at, the bundled copy is used if
+    /// `None`
+    pub fn get_public_suffix_list(&self) -> Result<Option<PathBuf>> {
+        self.public_suffix_list
+            .as_deref()
+            .map(expand_path)
+            .transpose()
+    }
+
+    /// Oldest KeePassXC version to talk to, e.g. 2.7.0
+    pub fn get_min_keepassxc_version(&self) -> Option<&str> {
+        self.min_keepassxc_version.as_deref()
+    }
+
+    /// Name of the recycle bin group, whose entries are skipped, "Recycle Bin" by default
+    pub fn get_recycle_bin_group(&self) -> &str {
+        self.recycle_bin_group.as_deref().unwrap_or("Recycle Bin")
+    }
+
+    /// String field which hides entries from Git, `KPH: git` == `false` by default
+    pub fn get_marker_field(&self) -> MarkerField {
+        self.marker_field.clone().unwrap_or_default()
+    }
+
+    /// Whether only entries whose `KPH: git-allow` names this user or machine are used, for shared
+    /// databases, disabled by default
+    pub fn is_require_allow_marker(&self) -> bool {
+        self.require_allow_marker.unwrap_or(false)
+    }
+
+    /// Path of the group which logins rejected by servers are copied to on erase, none by default
+    pub fn get_revoked_group(&self) -> Option<&str> {
+        self.revoked_group.as_deref()
+    }
+
+    /// Credential helper to ask when KeePassXC has no matching logins, see git::run_helper
+    pub fn get_fallback_helper(&self) -> Option<&str> {
+        self.fallback_helper.as_deref()
+    }
+
+    /// Template of the URL of new entries, see git::render_url_template
+    pub fn get_new_entry_url(&self) -> Option<&str> {
+        self.new_entry_url.as_deref()
+    }
+
+    /// How long to wait for the credential request on stdin, `None` (configured as 0) to wait forever
+    pub fn get_stdin_timeout(&self) -> Option<std::time::Duration> {
+        match self.stdin_timeout.unwrap_or(DEFAULT_STDIN_TIMEOUT) {
+            0 => None,
+            timeout => Some(std::time::Duration::from_secs(timeout)),
+        }
+    }
+
+    /// How long to remember that a URL has no matching logins, disabled (0) by default
+    pub fn get_no_match_cache(&self) -> Option<std::time::Duration> {
+        match self.no_match_cache.unwrap_or(0) {
+            0 => None,
+            ttl => Some(std::time::Duration::from_secs(ttl)),
+        }
+    }
+
+    /// How many logins of a get-logins response, and of those left after filtering, are considered
+    /// at most, all by default. KeePassXC ranks them, so only the worst matching ones are left out
+    pub fn get_max_candidates(&self) -> Option<usize> {
+        match self.max_candidates.unwrap_or(0) {
+            0 => None,
+            max => Some(max),
+        }
+    }
+
+    /// Seconds to wait for a (hardware) token to be plugged in when decrypting, 0 to not wait
+    #[cfg(feature = "encryption")]
+    pub fn get_token_timeout(&self) -> u64 {
+        self.token_timeout.unwrap_or(DEFAULT_TOKEN_TIMEOUT)
+    }
+
+    /// Seconds to cache the encryption key derived from (hardware) tokens, 0 (default) to disable
+    #[cfg(feature = "encryption")]
+    pub fn get_token_cache(&self) -> u64 {
+        self.token_cache.unwrap_or(0)
+    }
+
+    /// Whether store should only create new logins and never update existing ones
+    pub fn is_create_only(&self) -> bool {
+        self.create_only.unwrap_or(false)
+    }
+
+    /// Whether a group of new logins that no longer exists should be created instead of failing
+    pub fn is_create_group(&self) -> bool {
+        self.create_group.unwrap_or(false)
+    }
+
+    pub fn set_create_group(&mut self, create_group: bool) {
+        self.create_group = Some(create_group);
+    }
+
+    /// Whether erase should delete the matching logins from the database
+    pub fn is_allow_erase(&self) -> bool {
+        self.allow_erase.unwrap_or(false)
+    }
+
+    /// Whether advanced string fields of entries are mapped to extra Git credential attributes
+    pub fn is_advanced_fields(&self) -> bool {
+        self.advanced_fields.unwrap_or(false)
+    }
+
+    /// Names of the advanced string fields (without `KPH: ` prefix) which may be passed to Git, none
+    /// by default
+    pub fn get_advanced_field_names(&self) -> &[String] {
+        self.advanced_field_names.as_deref().unwrap_or_default()
+    }
+
+    /// What store does when several existing logins have the username, asking by default
+    pub fn get_store_conflict(&self) -> StoreConflict {
+        self.store_conflict.unwrap_or(StoreConflict::Ask)
+    }
+
+    /// Whether credentials can be requested for the host, all hosts are allowed unless there's an
+    /// allowlist, in which `*.example.com` matches subdomains of example.com
+    pub fn is_host_allowed(&self, host: &str) -> bool {
+        let allowed_hosts = match self.allowed_hosts {
+            Some(ref allowed_hosts) => allowed_hosts,
+            None => return true,
+        };
+        allowed_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, host))
+    }
+
+    /// Username to use for the host regardless of the one sent by Git, from the first matching rule
+    pub fn get_username_override(&self, host: &str) -> Option<&str> {
+        self.usernames
+            .iter()
+            .find(|rule| host_matches(&rule.host, host))
+            .map(|rule| rule.username.as_str())
+    }
+
+    /// UUID of the entry that is always used for the host, from the first matching rule
+    pub fn get_pinned_entry(&self, host: &str) -> Option<&str> {
+        self.pinned_entries
+            .iter()
+            .find(|rule| host_matches(&rule.host, host))
+            .map(|rule| rule.uuid.as_str())
+    }
+
+    /// Group to store new logins of the host in, from the first matching rule
+    pub fn get_group_rule(&self, host: &str) -> Option<&GroupRule> {
+        self.group_rules
+            .iter()
+            .find(|rule| host_matches(&rule.host, host))
+    }
+
+    /// Adds a group rule, replacing the existing one of the same host
+    pub fn add_group_rule(&mut self, rule: GroupRule) {
+        self.group_rules
+            .retain(|existing| !existing.host.eq_ignore_ascii_case(&rule.host));
+        self.group_rules.push(rule);
+    }
+
+    /// The entry holding the password for sudo-askpass
+    pub fn get_sudo_entry(&self) -> Option<&SudoEntry> {
+        self.sudo_entry.as_ref()
+    }
+
+    /// URL of the entries holding SSH key passphrases, which are told apart by `KPH: ssh-key`,
+    /// ssh-key://localhost by default
+    pub fn get_ssh_key_url(&self) -> &str {
+        self.ssh_key_url.as_deref().unwrap_or("ssh-key://localhost")
+    }
+
+    /// URL of the entry which holds the passphrase of the client certificate
+    pub fn get_certificate_url<T: AsRef<Path>>(&self, path: T) -> Option<&str> {
+        self.certificates
+            .iter()
+            .find(|rule| {
+                expand_path(&rule.path)
+                    .map(|p| p == path.as_ref())
+                    .unwrap_or(false)
+            })
+            .map(|rule| rule.url.as_str())
+    }
+
+    /// Position of the database (by ID or label) in `database_priority`, which decides whose
+    /// login wins if the same one is in multiple databases. Unlisted ones come last.
+    pub fn get_database_priority(&self, database: &Database) -> usize {
+        self.database_priority
+            .iter()
+            .position(|name| database.is_named(name))
+            .unwrap_or(self.database_priority.len())
+    }
+
+    /// Database (by ID or label) new logins are created in
+    pub fn get_target_database_name(&self) -> Option<&str> {
+        self.target_database.as_deref()
+    }
+
+    pub fn set_target_database_name(&mut self, target_database: Option<String>) {
+        self.target_database = target_database;
+    }
+
+    /// Picks the database new logins are created in from `databases`, which is `target_database`
+    /// if set, otherwise the first one
+    pub fn get_target_database<'a>(&self, databases: &'a [Database]) -> Result<&'a Database> {
+        if let Some(name) = self.target_database.as_deref() {
+            return databases
+                .iter()
+                .find(|database| database.is_named(name))
+                .ok_or_else(|| anyhow!("Target database {} is not configured", name));
+        }
+        if databases.len() > 1 {
+            warn!(
+                "More than 1 databases configured, gonna save the new login in the first database, see target_database in configuration file"
+            );
+        }
+        databases
+            .first()
+            .ok_or_else(|| anyhow!("No database is configured"))
+    }
+
+    /// Picks the database of ID `database_id`, which an existing login was found in, from
+    /// `databases`
+    pub fn get_entry_database<'a>(
+        &self,
+        databases: &'a [Database],
+        database_id: Option<&str>,
+    ) -> Result<&'a Database> {
+        match database_id {
+            Some(id) => databases
+                .iter()
+                .find(|database| database.id == id)
+                .ok_or_else(|| anyhow!("Database {} of the login is not configured", id)),
+            // a single database is unambiguous even if the login didn't come from get-logins
+            None if databases.len() == 1 => Ok(&databases[0]),
+            None => Err(anyhow!(
+                "Failed to tell which of the configured databases the login is from"
+            )),
+        }
     }
 
     pub fn get_databases(&self) -> Result<Vec<Database>> {
@@ -123,7 +602,8 @@ impl Config {
             if let Ok(database_json) = database_json {
                 databases.push(serde_json::from_str(database_json.as_str())?);
             } else {
-                warn!(
+                warn!("Failed to decrypt a database profile");
+                detail!(
                     "Failed to decrypt database profile {}.. (omitted)",
                     &encrypted_database.data[..8]
                 );
@@ -132,6 +612,51 @@ impl Config {
         Ok(databases)
     }
 
+    /// Describes database profiles without any keys
+    pub fn list_databases(&self) -> Result<Vec<DatabaseInfo>> {
+        // get_databases appends the encrypted profiles to the plain ones
+        let plain = self.databases.len();
+        Ok(self
+            .get_databases()?
+            .into_iter()
+            .enumerate()
+            .map(|(idx, database)| DatabaseInfo {
+                id: database.id,
+                label: database.label,
+                group: database.group,
+                group_uuid: database.group_uuid,
+                unlock: database.unlock,
+                encrypted: idx >= plain,
+            })
+            .collect())
+    }
+
+    /// Returns the database profile of the given ID or label, which must not be shared by several
+    /// profiles
+    pub fn find_database(&self, name: &str) -> Result<Database> {
+        let databases = self.get_databases()?;
+        if let Some(database) = databases.iter().find(|database| database.id == name) {
+            return Ok(database.clone());
+        }
+        let mut labeled: Vec<_> = databases
+            .into_iter()
+            .filter(|database| database.label.as_deref() == Some(name))
+            .collect();
+        match labeled.len() {
+            0 => Err(anyhow!("Database {} not found", name)),
+            1 => Ok(labeled.remove(0)),
+            _ => Err(anyhow!(
+                "Databases {} are all labeled {}, use the ID instead",
+                labeled
+                    .iter()
+                    .map(|database| database.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                name
+            )),
+        }
+    }
+
     pub fn count_databases(&self) -> usize {
         self.databases.len() + self.encrypted_databases.len()
     }
@@ -154,6 +679,75 @@ impl Config {
         Ok(())
     }
 
+    /// Index of the encrypted database profile of the given ID, skipping the ones that can't be
+    /// decrypted with the current key
+    fn find_encrypted_database(&self, id: &str) -> Result<Option<usize>> {
+        for (idx, encrypted_database) in self.encrypted_databases.iter().enumerate() {
+            if let Ok(json) =
+                self.base64_decrypt(&encrypted_database.data, &encrypted_database.nonce)
+            {
+                let existing: Database = serde_json::from_str(&json)?;
+                if existing.id == id {
+                    return Ok(Some(idx));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Replaces the database profile of the given ID in place, keeping it encrypted (with the
+    /// same description) if it was
+    pub fn replace_database(&mut self, id: &str, database: Database) -> Result<()> {
+        if let Some(existing) = self.databases.iter_mut().find(|db| db.id == id) {
+            *existing = database;
+            return Ok(());
+        }
+        let idx = self
+            .find_encrypted_database(id)?
+            .ok_or_else(|| anyhow!("Database {} not found", id))?;
+        let (data, nonce) = self.base64_encrypt(&serde_json::to_string(&database)?)?;
+        let encrypted_database = &mut self.encrypted_databases[idx];
+        encrypted_database.data = data;
+        encrypted_database.nonce = nonce;
+        Ok(())
+    }
+
+    /// Removes the database profile of the given ID, whether it's encrypted or not, along with
+    /// target_database and the entries of database_priority that only referred to it
+    pub fn remove_database(&mut self, id: &str) -> Result<()> {
+        let database = self
+            .get_databases()?
+            .into_iter()
+            .find(|database| database.id == id)
+            .ok_or_else(|| anyhow!("Database {} not found", id))?;
+        if let Some(idx) = self.databases.iter().position(|db| db.id == id) {
+            self.databases.remove(idx);
+        } else {
+            let idx = self
+                .find_encrypted_database(id)?
+                .ok_or_else(|| anyhow!("Database {} not found", id))?;
+            self.encrypted_databases.remove(idx);
+        }
+        // a label may still be shared by the remaining profiles
+        let remaining = self.get_databases()?;
+        let is_dangling =
+            |name: &str| database.is_named(name) && !remaining.iter().any(|db| db.is_named(name));
+        if self.target_database.as_deref().is_some_and(is_dangling) {
+            warn!(
+                "Target database {} is removed, new logins are created in the first database from now on",
+                self.target_database.take().unwrap()
+            );
+        }
+        self.database_priority.retain(|name| {
+            let dangling = is_dangling(name);
+            if dangling {
+                warn!("Database {} is removed from database_priority", name);
+            }
+            !dangling
+        });
+        Ok(())
+    }
+
     pub fn encrypt_databases(&mut self) -> Result<usize> {
         let result = self.databases.len();
         for database in &self.databases {
@@ -181,7 +775,8 @@ impl Config {
                     continue;
                 }
             }
-            warn!(
+            warn!("Failed to decrypt a database profile");
+            detail!(
                 "Failed to decrypt database profile {}.. (omitted)",
                 &encrypted_database.data[..8]
             );
@@ -197,9 +792,17 @@ impl Config {
         let mut callers: Vec<_> = self.callers.clone();
         for encrypted_caller in &self.encrypted_callers {
             // must decrypt all encrypted callers
-            callers.push(serde_json::from_str(
-                &self.base64_decrypt(&encrypted_caller.data, &encrypted_caller.nonce)?,
-            )?);
+            let caller_json = self
+                .base64_decrypt(&encrypted_caller.data, &encrypted_caller.nonce)
+                .map_err(|e| {
+                    detail!(
+                        "Failed to decrypt caller profile {}.. (omitted), {}",
+                        &encrypted_caller.data[..8],
+                        e
+                    );
+                    anyhow!("Failed to decrypt caller profiles")
+                })?;
+            callers.push(serde_json::from_str(&caller_json)?);
         }
         Ok(callers)
     }
@@ -264,7 +867,8 @@ impl Config {
                     continue;
                 }
             }
-            warn!(
+            warn!("Failed to decrypt a caller profile");
+            detail!(
                 "Failed to decrypt caller profile {}.. (omitted)",
                 &encrypted_caller.data[..8]
             );
@@ -335,23 +939,39 @@ impl Config {
         }
         let mut strict_match = false;
         let mut profile: &Encryption = &self.encryptions[0];
-        let curr_serial = read_yubikey_serial();
-        match curr_serial {
-            Ok(curr_serial) => {
-                for encryption in &self.encryptions {
-                    match encryption {
-                        Encryption::ChallengeResponse { serial, .. } => {
-                            if *serial.as_ref().unwrap() == curr_serial {
-                                strict_match = true;
-                                profile = encryption;
-                            }
-                        }
-                    }
-                }
-            }
-            Err(_) => {
+        let has_method = |method: &str| self.encryptions.iter().any(|e| e.method() == method);
+        let curr_serial = if has_method("challenge-response") {
+            let curr_serial = read_yubikey_serial();
+            if curr_serial.is_err() {
                 warn!("Failed to read YubiKey serial number");
             }
+            curr_serial.ok()
+        } else {
+            None
+        };
+        let curr_readers = if has_method("piv") {
+            let curr_readers = read_piv_readers();
+            if curr_readers.is_err() {
+                warn!("Failed to list smart card readers");
+            }
+            curr_readers.unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        for encryption in &self.encryptions {
+            let matched = match encryption {
+                Encryption::ChallengeResponse { serial, .. } => {
+                    serial.is_some() && *serial == curr_serial
+                }
+                Encryption::Piv { reader, .. } => reader
+                    .as_ref()
+                    .map(|reader| curr_readers.contains(reader))
+                    .unwrap_or(false),
+            };
+            if matched {
+                strict_match = true;
+                profile = encryption;
+            }
         }
 
         if strict && !strict_match {
@@ -362,10 +982,72 @@ impl Config {
         Ok(profile)
     }
 
+    /// Waits for one of the (hardware) tokens to be plugged in if they can all be identified,
+    /// otherwise falls back to the first profile right away
+    #[cfg(feature = "encryption")]
+    fn wait_for_encryption(&self) -> Result<&Encryption> {
+        let identifiable = self.encryptions.iter().all(|encryption| match encryption {
+            Encryption::ChallengeResponse { serial, .. } => serial.is_some(),
+            Encryption::Piv { reader, .. } => reader.is_some(),
+        });
+        let timeout = std::time::Duration::from_secs(self.get_token_timeout());
+        if self.encryptions.is_empty() || !identifiable || timeout.as_secs() == 0 {
+            return self.get_encryption(false);
+        }
+        let start = std::time::Instant::now();
+        let mut notified = false;
+        loop {
+            if let Ok(encryption) = self.get_encryption(true) {
+                return Ok(encryption);
+            }
+            if start.elapsed() >= timeout {
+                return Err(anyhow!(
+                    "Timed out after {}s waiting for a (hardware) token to decrypt configuration",
+                    timeout.as_secs()
+                ));
+            }
+            if !notified {
+                let tokens: Vec<_> = self
+                    .encryptions
+                    .iter()
+                    .map(Encryption::token_description)
+                    .collect();
+                eprintln!(
+                    "Insert {} or press Ctrl-C to cancel (waiting up to {}s)",
+                    tokens.join(" or "),
+                    timeout.as_secs()
+                );
+                notified = true;
+            }
+            sleep_interruptible(std::time::Duration::from_millis(500))?;
+        }
+    }
+
     pub fn count_encryptions(&self) -> usize {
         self.encryptions.len()
     }
 
+    /// Describes encryption profiles without any keys
+    pub fn list_encryptions(&self) -> Vec<EncryptionInfo> {
+        self.encryptions
+            .iter()
+            .map(|encryption| match encryption {
+                Encryption::ChallengeResponse { serial, slot, .. } => EncryptionInfo {
+                    method: encryption.method(),
+                    slot: slot.to_string(),
+                    serial: *serial,
+                    reader: None,
+                },
+                Encryption::Piv { reader, slot, .. } => EncryptionInfo {
+                    method: encryption.method(),
+                    slot: format!("{:x}", slot),
+                    serial: None,
+                    reader: reader.clone(),
+                },
+            })
+            .collect()
+    }
+
     #[cfg(not(feature = "encryption"))]
     pub fn add_encryption(&mut self, _profile: &str) -> Result<()> {
         error!("Enable encryption to use this feature");
@@ -392,7 +1074,8 @@ impl Config {
                 // no existing profiles
                 let profile = Encryption::from_str(profile)?;
                 match &profile {
-                    Encryption::ChallengeResponse { key, nonce, .. } => {
+                    Encryption::ChallengeResponse { key, nonce, .. }
+                    | Encryption::Piv { key, nonce, .. } => {
                         // extract key from an existing profile
                         *key.borrow_mut() = {
                             let encryption_key =
@@ -433,16 +1116,45 @@ impl Config {
         if self.encryption_key.borrow().is_some() {
             return Ok(self.encryption_key.borrow());
         }
-        let encryption = self.get_encryption(false)?;
+        // the encrypted key is unique to this configuration and not a secret itself
+        let cache_description = self.encryptions.first().map(|encryption| match encryption {
+            Encryption::ChallengeResponse { key, .. } | Encryption::Piv { key, .. } => format!(
+                "{}:{}",
+                clap::crate_name!(),
+                key.borrow().chars().take(16).collect::<String>()
+            ),
+        });
+        let token_cache = self.get_token_cache();
+        if let (Some(description), true) = (&cache_description, token_cache > 0) {
+            if let Some(cached_key) = read_cached_secret(description) {
+                if cached_key.len() == AES_KEY_LENGTH {
+                    info!("Using cached encryption key");
+                    *self.encryption_key.borrow_mut() = Some(AesKey::clone_from_slice(&cached_key));
+                    return Ok(self.encryption_key.borrow());
+                }
+            }
+        }
+        let encryption = self.wait_for_encryption()?;
         match encryption {
-            Encryption::ChallengeResponse { key, nonce, .. } => {
+            Encryption::ChallengeResponse { key, nonce, .. }
+            | Encryption::Piv { key, nonce, .. } => {
                 let response = encryption.get_response()?;
-                *self.encryption_key.borrow_mut() =
-                    Some(AesKey::clone_from_slice(&Self::base64_decrypt_with(
-                        key.borrow().as_str(),
-                        response.as_ref().unwrap(),
-                        nonce,
-                    )?));
+                let encryption_key = AesKey::clone_from_slice(&Self::base64_decrypt_with(
+                    key.borrow().as_str(),
+                    response.as_ref().unwrap(),
+                    nonce,
+                )?);
+                if let (Some(description), true) = (&cache_description, token_cache > 0) {
+                    match cache_secret(description, &encryption_key, token_cache) {
+                        Ok(_) => {
+                            info!("Encryption key is cached for {}s", token_cache);
+                        }
+                        Err(e) => {
+                            warn!("Failed to cache encryption key, {}", e);
+                        }
+                    }
+                }
+                *self.encryption_key.borrow_mut() = Some(encryption_key);
                 Ok(self.encryption_key.borrow())
             }
         }
@@ -486,15 +1198,15 @@ where
 
 #[cfg(feature = "encryption")]
 fn read_yubikey_serial() -> Result<u32> {
-    #[cfg(not(feature = "yubikey"))]
+    #[cfg(not(any(feature = "yubikey", feature = "yubikey-ccid")))]
     {
         error!("YubiKey is not enabled in this build");
         Err(anyhow!("YubiKey is not enabled in this build"))
     }
-    #[cfg(feature = "yubikey")]
+    #[cfg(any(feature = "yubikey", feature = "yubikey-ccid"))]
     {
         #[cfg(not(test))]
-        let mut yubikey = YubiKey::new()?;
+        let mut yubikey = open_yubikey()?;
         #[cfg(test)]
         let mut yubikey = MockYubiKeyTrait::new_mock();
         yubikey
@@ -503,56 +1215,257 @@ fn read_yubikey_serial() -> Result<u32> {
     }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[cfg(feature = "encryption")]
+fn read_piv_readers() -> Result<Vec<String>> {
+    #[cfg(not(feature = "piv"))]
+    {
+        error!("PIV is not enabled in this build");
+        Err(anyhow!("PIV is not enabled in this build"))
+    }
+    #[cfg(feature = "piv")]
+    {
+        #[cfg(not(test))]
+        let mut card = PivCard::new()?;
+        #[cfg(test)]
+        let mut card = MockPivCardTrait::new_mock();
+        card.list_readers()
+    }
+}
+
+/// Generates the ephemeral P-256 public key which a PIV card performs ECDH with
+#[cfg(feature = "piv")]
+fn piv_peer_key() -> String {
+    let mut rng = thread_rng();
+    let secret_key = loop {
+        let mut bytes = [0u8; 32];
+        rng.fill(&mut bytes);
+        if let Ok(secret_key) = p256::SecretKey::from_slice(&bytes) {
+            break secret_key;
+        }
+    };
+    base64::encode(secret_key.public_key().to_encoded_point(false).as_bytes())
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Default, Debug)]
+#[schemars(deny_unknown_fields)]
 struct EncryptedProfile {
     data: String,
     #[serde(
         serialize_with = "aes_nonce_serialize",
         deserialize_with = "aes_nonce_deserialize"
     )]
+    #[schemars(with = "String")]
     nonce: AesNonce,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     description: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Database {
-    pub id: String,
-    pub key: String,
-    pub pkey: String,
-    pub group: String,
-    pub group_uuid: String,
+/// Matches a host against either a host name or `*.example.com` for subdomains of example.com
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_lowercase();
+    let pattern = pattern.trim_end_matches('.').to_lowercase();
+    if let Some(suffix) = pattern.strip_prefix("*.") {
+        host.ends_with(&format!(".{}", suffix))
+    } else {
+        host == pattern
+    }
 }
 
-impl Database {
-    pub fn new(
-        id: String,
-        id_seckey: crypto_box::SecretKey,
-        group: crate::keepassxc::Group,
-    ) -> Self {
-        let id_seckey_b64 = base64::encode(id_seckey.to_bytes());
-        let id_pubkey = id_seckey.public_key();
-        let id_pubkey_b64 = base64::encode(id_pubkey.as_bytes());
-        Self {
-            id,
-            key: id_seckey_b64,
-            pkey: id_pubkey_b64,
-            group: group.name,
-            group_uuid: group.uuid,
-        }
-    }
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct UsernameRule {
+    /// Host name, or `*.example.com` for subdomains of example.com
+    pub host: String,
+    pub username: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Caller {
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct PinnedEntryRule {
+    /// Host name, or `*.example.com` for subdomains of example.com
+    pub host: String,
+    /// UUID of the entry as shown in KeePassXC (Entry -> Properties)
+    pub uuid: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct MarkerField {
+    /// Name of the string field without the `KPH: ` prefix, e.g. `vcs` for `KPH: vcs`
+    pub name: String,
+    /// Values which hide entries, compared case-insensitively
+    #[serde(default = "MarkerField::default_false_values")]
+    pub false_values: Vec<String>,
+    /// Whether entries without the field are used, otherwise only those labeled with another
+    /// value are
+    #[serde(default = "MarkerField::default_use_unlabeled")]
+    pub use_unlabeled: bool,
+}
+
+impl MarkerField {
+    fn default_false_values() -> Vec<String> {
+        vec!["false".to_owned()]
+    }
+
+    fn default_use_unlabeled() -> bool {
+        true
+    }
+
+    /// Whether an entry with the given value of the field is hidden from Git
+    pub fn excludes(&self, value: Option<&str>) -> bool {
+        match value {
+            Some(value) => self
+                .false_values
+                .iter()
+                .any(|false_value| false_value.eq_ignore_ascii_case(value)),
+            None => !self.use_unlabeled,
+        }
+    }
+}
+
+impl Default for MarkerField {
+    fn default() -> Self {
+        Self {
+            name: "git".to_owned(),
+            false_values: Self::default_false_values(),
+            use_unlabeled: Self::default_use_unlabeled(),
+        }
+    }
+}
+
+/// How store picks the login to update when several existing ones have the username
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreConflict {
+    /// Ask on the terminal, or update the first login if there's none
+    Ask,
+    /// Update the first login, which KeePassXC ranks best
+    UpdateFirst,
+    /// Leave the existing logins alone and create a new one
+    CreateNew,
+    /// Fail without storing anything
+    Abort,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct GroupRule {
+    /// Host name, or `*.example.com` for subdomains of example.com
+    pub host: String,
+    /// Path of the group, used to find or create it again if the UUID no longer exists
+    pub group: String,
+    pub group_uuid: String,
+}
+
+/// KeePassXC can't look up entries by UUID, so the entry is picked from the logins of its URL
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct SudoEntry {
+    pub url: String,
+    /// UUID of the entry as shown in KeePassXC (Entry -> Properties)
+    pub uuid: String,
+}
+
+/// Maps a client certificate (`http.sslCert` in Git) to the URL of its entry
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct CertificateRule {
+    pub path: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct Database {
+    pub id: String,
+    pub key: String,
+    pub pkey: String,
+    pub group: String,
+    pub group_uuid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Overrides the unlock options from command line for this database
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unlock: Option<UnlockOptions>,
+    /// When the profile was created (seconds since the Unix epoch), missing in older ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+}
+
+impl Database {
+    pub fn new(
+        id: String,
+        id_seckey: crypto_box::SecretKey,
+        group: crate::keepassxc::Group,
+    ) -> Self {
+        let id_seckey_b64 = base64::encode(id_seckey.to_bytes());
+        let id_pubkey = id_seckey.public_key();
+        let id_pubkey_b64 = base64::encode(id_pubkey.as_bytes());
+        Self {
+            id,
+            key: id_seckey_b64,
+            pkey: id_pubkey_b64,
+            group: group.name,
+            group_uuid: group.uuid,
+            label: None,
+            unlock: None,
+            created: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .ok()
+                .map(|created| created.as_secs()),
+        }
+    }
+
+    /// Whether the database has the ID or label
+    pub fn is_named(&self, name: &str) -> bool {
+        self.id == name || self.label.as_deref() == Some(name)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct DatabaseInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub group: String,
+    pub group_uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unlock: Option<UnlockOptions>,
+    pub encrypted: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[schemars(deny_unknown_fields)]
+pub struct Caller {
     pub path: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub uid: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub gid: Option<u32>,
+    /// SHA-256 of the executable in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Transparent wrapper (e.g. `sh -c`), its parent is verified instead
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub wrapper: bool,
+    /// Set-user-ID root program (e.g. sudo) whose executable can't be read, which may be
+    /// identified by the command line it was started with instead (Linux only)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub setuid: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Debug)]
+pub struct EncryptionInfo {
+    pub method: String,
+    pub slot: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reader: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(deny_unknown_fields)]
 enum Encryption {
     ChallengeResponse {
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -564,6 +1477,23 @@ enum Encryption {
             serialize_with = "aes_nonce_serialize",
             deserialize_with = "aes_nonce_deserialize"
         )]
+        #[schemars(with = "String")]
+        nonce: AesNonce,
+        #[serde(skip)]
+        response: RefCell<Option<AesKey>>,
+    },
+    Piv {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reader: Option<String>,
+        slot: u8,
+        /// Uncompressed ephemeral P-256 public key, base64 encoded
+        peer_key: String,
+        key: RefCell<String>,
+        #[serde(
+            serialize_with = "aes_nonce_serialize",
+            deserialize_with = "aes_nonce_deserialize"
+        )]
+        #[schemars(with = "String")]
         nonce: AesNonce,
         #[serde(skip)]
         response: RefCell<Option<AesKey>>,
@@ -574,18 +1504,33 @@ impl Encryption {
     fn method(&self) -> String {
         match self {
             Encryption::ChallengeResponse { .. } => "challenge-response".to_owned(),
+            Encryption::Piv { .. } => "piv".to_owned(),
+        }
+    }
+
+    #[cfg(feature = "encryption")]
+    fn token_description(&self) -> String {
+        match self {
+            Encryption::ChallengeResponse { serial, .. } => match serial {
+                Some(serial) => format!("YubiKey {}", serial),
+                None => "YubiKey".to_owned(),
+            },
+            Encryption::Piv { reader, .. } => match reader {
+                Some(reader) => format!("smart card into {}", reader),
+                None => "smart card".to_owned(),
+            },
         }
     }
 
     #[cfg(feature = "encryption")]
     fn get_response(&self) -> Result<std::cell::Ref<Option<AesKey>>> {
         match self {
-            #[cfg(not(feature = "yubikey"))]
+            #[cfg(not(any(feature = "yubikey", feature = "yubikey-ccid")))]
             Encryption::ChallengeResponse { .. } => {
                 error!("YubiKey is not enabled in this build");
                 Err(anyhow!("YubiKey is not enabled in this build"))
             }
-            #[cfg(feature = "yubikey")]
+            #[cfg(any(feature = "yubikey", feature = "yubikey-ccid"))]
             Encryption::ChallengeResponse {
                 slot,
                 challenge,
@@ -595,22 +1540,56 @@ impl Encryption {
                 if response.borrow().is_some() {
                     return Ok(response.borrow());
                 }
-                let slot = if *slot == 1 {
-                    yubico_config::Slot::Slot1
-                } else {
-                    yubico_config::Slot::Slot2
-                };
                 #[cfg(not(test))]
-                let mut yubikey = YubiKey::new()?;
+                let mut yubikey = open_yubikey()?;
                 #[cfg(test)]
                 let mut yubikey = MockYubiKeyTrait::new_mock();
-                let mut hmac_response = yubikey.challenge_response_hmac(&challenge, slot)?;
+                let mut hmac_response = yubikey.challenge_response_hmac(challenge, *slot)?;
                 debug_assert_eq!(hmac_response.len(), HMAC_SHA1_RESPONSE_LENGTH);
                 hmac_response.extend_from_slice(&[0u8; AES_KEY_LENGTH - HMAC_SHA1_RESPONSE_LENGTH]);
                 debug_assert_eq!(hmac_response.len(), AES_KEY_LENGTH);
                 *response.borrow_mut() = Some(AesKey::clone_from_slice(&hmac_response));
                 Ok(response.borrow())
             }
+            #[cfg(not(feature = "piv"))]
+            Encryption::Piv { .. } => {
+                error!("PIV is not enabled in this build");
+                Err(anyhow!("PIV is not enabled in this build"))
+            }
+            #[cfg(feature = "piv")]
+            Encryption::Piv {
+                reader,
+                slot,
+                peer_key,
+                response,
+                ..
+            } => {
+                if response.borrow().is_some() {
+                    return Ok(response.borrow());
+                }
+                #[cfg(not(test))]
+                let mut card = PivCard::new()?;
+                #[cfg(test)]
+                let mut card = MockPivCardTrait::new_mock();
+                let reader = match reader {
+                    Some(reader) => reader.clone(),
+                    None => card
+                        .list_readers()?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| anyhow!("No smart card readers found"))?,
+                };
+                let peer_key = base64::decode(peer_key)?;
+                let shared_secret = card.ecdh(&reader, *slot, &peer_key)?;
+                // derive the key from both the shared secret and the ephemeral public key
+                let mut hasher = Sha256::new();
+                hasher.update(&shared_secret);
+                hasher.update(&peer_key);
+                let digest = hasher.finalize();
+                debug_assert_eq!(digest.len(), AES_KEY_LENGTH);
+                *response.borrow_mut() = Some(AesKey::clone_from_slice(&digest));
+                Ok(response.borrow())
+            }
         }
     }
 }
@@ -621,6 +1600,7 @@ impl ToString for Encryption {
             Encryption::ChallengeResponse {
                 slot, challenge, ..
             } => format!("{}:{}:{}", self.method(), slot, challenge),
+            Encryption::Piv { slot, .. } => format!("{}:{:x}", self.method(), slot),
         }
     }
 }
@@ -665,23 +1645,52 @@ impl FromStr for Encryption {
                     response: RefCell::new(None),
                 })
             }
+            #[cfg(not(feature = "piv"))]
+            "piv" => {
+                error!("PIV is not enabled in this build");
+                Err(anyhow!("PIV is not enabled in this build"))
+            }
+            #[cfg(feature = "piv")]
+            "piv" => {
+                let slot = if let Some(slot) = profile_vec.get(1) {
+                    u8::from_str_radix(slot, 16)?
+                } else {
+                    PIV_DEFAULT_SLOT
+                };
+                // authentication, signature, key management, card authentication and retired
+                // key management slots
+                if ![0x9a, 0x9c, 0x9d, 0x9e].contains(&slot) && !(0x82..=0x95).contains(&slot) {
+                    return Err(anyhow!("Invalid PIV slot: {:x}", slot));
+                }
+                let reader = read_piv_readers()
+                    .ok()
+                    .and_then(|readers| readers.into_iter().next());
+                if reader.is_none() {
+                    warn!("Failed to find a smart card reader");
+                }
+                Ok(Encryption::Piv {
+                    reader,
+                    slot,
+                    peer_key: piv_peer_key(),
+                    key: RefCell::new(String::new()),
+                    nonce: aes_nonce(),
+                    response: RefCell::new(None),
+                })
+            }
             _ => Err(anyhow!("Unknown encryption profile: {}", profile)),
         }
     }
 }
 
-#[cfg(feature = "yubikey")]
+#[cfg(any(feature = "yubikey", feature = "yubikey-ccid"))]
 #[cfg_attr(test, automock)]
 trait YubiKeyTrait {
-    fn read_serial_number(&mut self) -> Result<u32, YubicoError>;
-    fn challenge_response_hmac(
-        &mut self,
-        challenge: &String,
-        slot: yubico_config::Slot,
-    ) -> Result<Vec<u8>, YubicoError>;
+    fn read_serial_number(&mut self) -> Result<u32>;
+    /// Sends HMAC-SHA1 challenge to slot 1 or 2, returns the response
+    fn challenge_response_hmac(&mut self, challenge: &str, slot: u8) -> Result<Vec<u8>>;
 }
 
-#[cfg(test)]
+#[cfg(all(test, any(feature = "yubikey", feature = "yubikey-ccid")))]
 impl MockYubiKeyTrait {
     fn new_mock() -> Self {
         use hmac::{Mac, NewMac};
@@ -710,6 +1719,41 @@ impl MockYubiKeyTrait {
     }
 }
 
+/// Opens YubiKey via HID, or via PC/SC (CCID) if HID is not available
+#[cfg(all(not(test), any(feature = "yubikey", feature = "yubikey-ccid")))]
+fn open_yubikey() -> Result<Box<dyn YubiKeyTrait>> {
+    #[cfg(feature = "yubikey")]
+    let hid_error = match YubiKey::new() {
+        Ok(yubikey) => return Ok(Box::new(yubikey)),
+        Err(e) => e,
+    };
+    #[cfg(not(feature = "yubikey-ccid"))]
+    return Err(hid_error);
+    #[cfg(feature = "yubikey-ccid")]
+    {
+        #[cfg(feature = "yubikey")]
+        warn!(
+            "Failed to open YubiKey via HID, trying PC/SC: {}",
+            hid_error
+        );
+        Ok(Box::new(YubiKeyCcid::new()?))
+    }
+}
+
+#[cfg(any(feature = "yubikey", feature = "yubikey-ccid"))]
+fn notify_yubikey_challenge() {
+    info!("Sending HMAC challenge, tap your YubiKey if needed");
+    show_notification(
+        NotificationEvent::YubiKey,
+        "Tap YubiKey if necessary",
+        &format!(
+            "{} is going to send HMAC challenge to YubiKey",
+            clap::crate_name!()
+        ),
+        3000,
+    );
+}
+
 #[cfg(feature = "yubikey")]
 struct YubiKey {
     yubi: Yubico,
@@ -727,18 +1771,19 @@ impl YubiKey {
 
 #[cfg(feature = "yubikey")]
 impl YubiKeyTrait for YubiKey {
-    fn read_serial_number(&mut self) -> Result<u32, YubicoError> {
+    fn read_serial_number(&mut self) -> Result<u32> {
         let config = yubico_config::Config::default()
             .set_vendor_id(self.device.vendor_id)
             .set_product_id(self.device.product_id);
-        self.yubi.read_serial_number(config)
+        Ok(self.yubi.read_serial_number(config)?)
     }
 
-    fn challenge_response_hmac(
-        &mut self,
-        challenge: &String,
-        slot: yubico_config::Slot,
-    ) -> Result<Vec<u8>, YubicoError> {
+    fn challenge_response_hmac(&mut self, challenge: &str, slot: u8) -> Result<Vec<u8>> {
+        let slot = if slot == 1 {
+            yubico_config::Slot::Slot1
+        } else {
+            yubico_config::Slot::Slot2
+        };
         debug!("Using YubiKey {:?}", slot);
         let config = yubico_config::Config::default()
             .set_vendor_id(self.device.vendor_id)
@@ -747,22 +1792,7 @@ impl YubiKeyTrait for YubiKey {
             .set_mode(yubico_config::Mode::Sha1)
             .set_slot(slot);
         debug!("Challenge: {}", challenge);
-        info!("Sending HMAC challenge, tap your YubiKey if needed");
-        #[cfg(feature = "notification")]
-        {
-            use notify_rust::{Notification, Timeout};
-            let notification = Notification::new()
-                .summary("Tap YubiKey if necessary")
-                .body(&format!(
-                    "{} is going to send HMAC challenge to YubiKey",
-                    clap::crate_name!()
-                ))
-                .timeout(Timeout::Milliseconds(3000))
-                .show();
-            if let Err(e) = notification {
-                warn!("Failed to show notification for YubiKey operation, {}", e);
-            }
-        }
+        notify_yubikey_challenge();
         let hmac_result = self
             .yubi
             .challenge_response_hmac(challenge.as_bytes(), config)?;
@@ -772,6 +1802,202 @@ impl YubiKeyTrait for YubiKey {
     }
 }
 
+/// YubiKey OTP application over PC/SC, for when raw HID access is blocked but CCID is allowed
+#[cfg(feature = "yubikey-ccid")]
+struct YubiKeyCcid {
+    card: pcsc::Card,
+}
+
+#[cfg(all(not(test), feature = "yubikey-ccid"))]
+impl YubiKeyCcid {
+    fn new() -> Result<Self> {
+        let context = pcsc::Context::establish(pcsc::Scope::User)?;
+        for reader in context.list_readers_owned()? {
+            let card = match context.connect(&reader, pcsc::ShareMode::Shared, pcsc::Protocols::ANY)
+            {
+                Ok(card) => card,
+                Err(_) => continue,
+            };
+            if transmit_apdu(&card, &YUBIKEY_OTP_SELECT_APDU).is_ok() {
+                debug!("Using YubiKey in {}", reader.to_string_lossy());
+                return Ok(Self { card });
+            }
+        }
+        Err(anyhow!("Failed to find YubiKey via PC/SC"))
+    }
+}
+
+#[cfg(feature = "yubikey-ccid")]
+impl YubiKeyCcid {
+    fn send_command(&self, command: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut apdu = vec![0x00, 0x01, command, 0x00, data.len() as u8];
+        apdu.extend_from_slice(data);
+        transmit_apdu(&self.card, &apdu)
+    }
+}
+
+#[cfg(feature = "yubikey-ccid")]
+impl YubiKeyTrait for YubiKeyCcid {
+    fn read_serial_number(&mut self) -> Result<u32> {
+        let serial = self.send_command(YUBIKEY_CMD_DEVICE_SERIAL, &[])?;
+        if serial.len() < 4 {
+            return Err(anyhow!("Invalid YubiKey serial number"));
+        }
+        Ok(u32::from_be_bytes([
+            serial[0], serial[1], serial[2], serial[3],
+        ]))
+    }
+
+    fn challenge_response_hmac(&mut self, challenge: &str, slot: u8) -> Result<Vec<u8>> {
+        debug!("Using YubiKey slot {} via PC/SC", slot);
+        debug!("Challenge: {}", challenge);
+        let command = if slot == 1 {
+            YUBIKEY_CMD_CHALLENGE_HMAC_1
+        } else {
+            YUBIKEY_CMD_CHALLENGE_HMAC_2
+        };
+        // pad with a byte different from the last one, same as variable size challenges over HID
+        let mut data = challenge.as_bytes().to_vec();
+        let padding = if data.last() == Some(&0) { 1 } else { 0 };
+        data.resize(HMAC_SHA1_CHALLENGE_LENGTH, padding);
+        notify_yubikey_challenge();
+        let mut hmac_result = self.send_command(command, &data)?;
+        if hmac_result.len() < HMAC_SHA1_RESPONSE_LENGTH {
+            return Err(anyhow!("Invalid HMAC response from YubiKey"));
+        }
+        hmac_result.truncate(HMAC_SHA1_RESPONSE_LENGTH);
+        info!("HMAC response received");
+        Ok(hmac_result)
+    }
+}
+
+#[cfg(feature = "piv")]
+#[cfg_attr(test, automock)]
+trait PivCardTrait {
+    fn list_readers(&mut self) -> Result<Vec<String>>;
+    /// Performs ECDH using the private key in `slot` and an uncompressed P-256 public key, returns
+    /// the shared secret
+    fn ecdh(&mut self, reader: &str, slot: u8, peer_key: &[u8]) -> Result<Vec<u8>>;
+}
+
+#[cfg(all(test, feature = "piv"))]
+impl MockPivCardTrait {
+    fn new_mock() -> Self {
+        let mut mock_card = Self::new();
+        mock_card
+            .expect_list_readers()
+            .returning(|| Ok(vec![tests::TEST_PIV_READER.to_owned()]));
+        mock_card.expect_ecdh().returning(|_, _, peer_key| {
+            let secret_key = p256::SecretKey::from_slice(&tests::TEST_PIV_SECRET_KEY).unwrap();
+            let peer_key = p256::PublicKey::from_sec1_bytes(peer_key).unwrap();
+            let shared_secret =
+                p256::ecdh::diffie_hellman(secret_key.to_nonzero_scalar(), peer_key.as_affine());
+            Ok(shared_secret.raw_secret_bytes().to_vec())
+        });
+        mock_card
+    }
+}
+
+#[cfg(feature = "piv")]
+struct PivCard {
+    context: pcsc::Context,
+}
+
+#[cfg(all(not(test), feature = "piv"))]
+impl PivCard {
+    fn new() -> Result<Self> {
+        let context = pcsc::Context::establish(pcsc::Scope::User)?;
+        Ok(Self { context })
+    }
+}
+
+/// Sends an APDU to the smart card, returns the response data if the status is 9000
+#[cfg(any(feature = "piv", feature = "yubikey-ccid"))]
+fn transmit_apdu(card: &pcsc::Card, apdu: &[u8]) -> Result<Vec<u8>> {
+    let mut buffer = [0u8; pcsc::MAX_BUFFER_SIZE];
+    let response = card.transmit(apdu, &mut buffer)?;
+    if response.len() < 2 {
+        return Err(anyhow!("Invalid response from smart card"));
+    }
+    let (data, status) = response.split_at(response.len() - 2);
+    match *status {
+        [0x90, 0x00] => Ok(data.to_vec()),
+        [0x63, retries] if retries & 0xf0 == 0xc0 => Err(anyhow!(
+            "Incorrect PIN, {} attempt(s) remaining",
+            retries & 0x0f
+        )),
+        [0x69, 0x83] => Err(anyhow!("PIN is blocked")),
+        _ => Err(anyhow!(
+            "Smart card returned status {:02X}{:02X}",
+            status[0],
+            status[1]
+        )),
+    }
+}
+
+#[cfg(feature = "piv")]
+impl PivCardTrait for PivCard {
+    fn list_readers(&mut self) -> Result<Vec<String>> {
+        Ok(self
+            .context
+            .list_readers_owned()?
+            .into_iter()
+            .map(|reader| reader.to_string_lossy().into_owned())
+            .collect())
+    }
+
+    fn ecdh(&mut self, reader: &str, slot: u8, peer_key: &[u8]) -> Result<Vec<u8>> {
+        debug!("Using PIV slot {:x} of {}", slot, reader);
+        let card = self.context.connect(
+            &CString::new(reader)?,
+            pcsc::ShareMode::Shared,
+            pcsc::Protocols::ANY,
+        )?;
+        transmit_apdu(&card, &PIV_SELECT_APDU)?;
+
+        info!("Verifying PIN of smart card in {}", reader);
+        crate::utils::ensure_prompt_allowed(false)?;
+        save_terminal_state();
+        let pin = rpassword::prompt_password("PIV PIN: ")?;
+        if pin.len() < 6 || pin.len() > 8 {
+            return Err(anyhow!("PIV PIN must be 6 to 8 characters long"));
+        }
+        let mut verify_apdu = vec![0x00, 0x20, 0x00, 0x80, 0x08];
+        verify_apdu.extend_from_slice(pin.as_bytes());
+        // pad with 0xff
+        verify_apdu.resize(5 + 8, 0xff);
+        transmit_apdu(&card, &verify_apdu)?;
+
+        // GENERAL AUTHENTICATE, dynamic authentication template with an empty response (0x82)
+        // and the public key to exponentiate (0x85)
+        let mut template = vec![0x82, 0x00, 0x85, peer_key.len() as u8];
+        template.extend_from_slice(peer_key);
+        let mut ecdh_apdu = vec![
+            0x00,
+            0x87,
+            PIV_ALGORITHM_ECC_P256,
+            slot,
+            (template.len() + 2) as u8,
+            0x7c,
+            template.len() as u8,
+        ];
+        ecdh_apdu.extend_from_slice(&template);
+        ecdh_apdu.push(0x00);
+        let response = transmit_apdu(&card, &ecdh_apdu)?;
+        info!("ECDH response received");
+
+        // 0x7c <length> 0x82 <length> <shared secret>
+        match response.as_slice() {
+            [0x7c, _, 0x82, length, shared_secret @ ..]
+                if shared_secret.len() == *length as usize =>
+            {
+                Ok(shared_secret.to_vec())
+            }
+            _ => Err(anyhow!("Invalid ECDH response from smart card")),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -786,6 +2012,24 @@ mod tests {
 
     pub static TEST_YUBIKEY_SERIAL: u32 = 1234567;
     pub static TEST_YUBIKEY_HMAC_SHA1_SECRET: &'static str = "test_secret";
+    #[cfg(feature = "piv")]
+    pub static TEST_PIV_READER: &str = "Mock Reader 00 00";
+    #[cfg(feature = "piv")]
+    pub static TEST_PIV_SECRET_KEY: [u8; 32] = [0x42; 32];
+
+    /// A database with only the ID and label filled in
+    fn database(id: &str, label: Option<&str>) -> Database {
+        Database {
+            id: id.to_owned(),
+            key: String::new(),
+            pkey: String::new(),
+            group: String::new(),
+            group_uuid: String::new(),
+            label: label.map(str::to_owned),
+            unlock: None,
+            created: None,
+        }
+    }
 
     #[test]
     fn test_00_config_read_write_plain_text() {
@@ -825,6 +2069,7 @@ mod tests {
         fs::remove_file(config_path).unwrap();
     }
 
+    #[cfg(any(feature = "yubikey", feature = "yubikey-ccid"))]
     #[test]
     fn test_01_config_read_write_challenge_response() {
         let config_path = {
@@ -878,6 +2123,410 @@ mod tests {
         fs::remove_file(config_path).unwrap();
     }
 
+    #[cfg(feature = "piv")]
+    #[test]
+    fn test_02_config_read_write_piv() {
+        let config_path = {
+            let mut temp = std::env::temp_dir();
+            temp.push(format!("{}.test_02.json", clap::crate_name!()));
+            assert!(
+                !temp.exists(),
+                "Test configuration file {} already exists",
+                temp.to_string_lossy()
+            );
+            temp
+        };
+        let group = Group::new("mock group", "mock uuid");
+        let secret_key = generate_secret_key();
+        let database = Database::new(
+            "mock database".to_owned(),
+            secret_key.clone(),
+            group.clone(),
+        );
+
+        {
+            // write plain text config
+            let mut config = Config::new();
+            config.add_database(database.clone(), false).unwrap();
+            config.write_to(&config_path).unwrap();
+        }
+        {
+            // read plain text, write encrypted
+            let mut config = Config::read_from(&config_path).unwrap();
+            config.add_encryption("piv").unwrap();
+            let encrypted = config.encrypt_databases().unwrap();
+            assert_eq!(encrypted, 1);
+            config.write_to(&config_path).unwrap();
+        }
+        {
+            // read encrypted, validate, write back
+            let mut config = Config::read_from(&config_path).unwrap();
+            assert_eq!(config.count_databases(), 1);
+            let databases = config.get_databases().unwrap();
+            assert_eq!(databases[0].id, database.id);
+            assert_eq!(databases[0].key, base64::encode(secret_key.to_bytes()));
+            let decrypted = config.decrypt_databases().unwrap();
+            assert_eq!(decrypted, 1);
+            config.write_to(&config_path).unwrap();
+        }
+        {
+            // still valid
+            let _config = Config::read_from(&config_path).unwrap();
+        }
+
+        fs::remove_file(config_path).unwrap();
+    }
+
+    #[test]
+    fn test_03_strict_caller_default() {
+        let mut config = Config::new();
+        assert_eq!(config.is_strict_caller(), cfg!(feature = "strict-caller"));
+        config.strict_caller = Some(true);
+        assert!(config.is_strict_caller());
+        let config: Config = serde_json::from_str(r#"{"strict_caller": false}"#).unwrap();
+        assert!(!config.is_strict_caller());
+    }
+
+    #[test]
+    fn test_04_replace_database() {
+        let mut config = Config::new();
+        let group = Group::new("mock group", "mock uuid");
+        let mut database = Database::new(
+            "mock database".to_owned(),
+            generate_secret_key(),
+            group.clone(),
+        );
+        database.label = Some("mock label".to_owned());
+        config.add_database(database.clone(), false).unwrap();
+
+        let secret_key = generate_secret_key();
+        let mut renewed = Database::new("renewed database".to_owned(), secret_key.clone(), group);
+        renewed.label = database.label.clone();
+        config.replace_database(&database.id, renewed).unwrap();
+        let databases = config.get_databases().unwrap();
+        assert_eq!(databases.len(), 1);
+        assert_eq!(databases[0].id, "renewed database");
+        assert_eq!(databases[0].key, base64::encode(secret_key.to_bytes()));
+        assert_eq!(databases[0].label, database.label);
+
+        assert!(config
+            .replace_database(&database.id, database.clone())
+            .is_err());
+    }
+
+    #[test]
+    fn test_05_host_allowlist() {
+        let config = Config::new();
+        assert!(config.is_host_allowed("example.com"));
+        let config: Config =
+            serde_json::from_str(r#"{"allowed_hosts": ["github.com", "*.Example.com"]}"#).unwrap();
+        assert!(config.is_host_allowed("github.com"));
+        assert!(config.is_host_allowed("GitHub.com."));
+        assert!(config.is_host_allowed("git.example.com"));
+        assert!(!config.is_host_allowed("example.com"));
+        assert!(!config.is_host_allowed("gitexample.com"));
+        assert!(!config.is_host_allowed("github.com.evil.com"));
+        assert!(!config.is_host_allowed("githu8.com"));
+    }
+
+    #[test]
+    fn test_06_username_override() {
+        let config: Config = serde_json::from_str(
+            r#"{"usernames": [
+                {"host": "github.com", "username": "my-bot-account"},
+                {"host": "*.example.com", "username": "me"},
+                {"host": "git.example.com", "username": "shadowed"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_username_override("GitHub.com"),
+            Some("my-bot-account")
+        );
+        assert_eq!(config.get_username_override("git.example.com"), Some("me"));
+        assert_eq!(config.get_username_override("gitlab.com"), None);
+    }
+
+    #[test]
+    fn test_07_certificate_url() {
+        let config: Config = serde_json::from_str(
+            r#"{"certificates": [{"path": "/etc/git/client.p12", "url": "https://git.example.com"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_certificate_url("/etc/git/client.p12"),
+            Some("https://git.example.com")
+        );
+        assert_eq!(config.get_certificate_url("/etc/git/other.p12"), None);
+    }
+
+    #[test]
+    fn test_08_pinned_entry() {
+        let config: Config = serde_json::from_str(
+            r#"{"pinned_entries": [{"host": "*.corp.example.com", "uuid": "0123456789abcdef"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.get_pinned_entry("git.corp.example.com"),
+            Some("0123456789abcdef")
+        );
+        assert_eq!(config.get_pinned_entry("github.com"), None);
+    }
+
+    #[test]
+    fn test_09_group_rule() {
+        let mut config = Config::new();
+        config.add_group_rule(GroupRule {
+            host: "*.corp.example.com".to_owned(),
+            group: "Git/Old".to_owned(),
+            group_uuid: "old uuid".to_owned(),
+        });
+        config.add_group_rule(GroupRule {
+            host: "*.CORP.example.com".to_owned(),
+            group: "Git/Work".to_owned(),
+            group_uuid: "work uuid".to_owned(),
+        });
+        assert_eq!(config.group_rules.len(), 1);
+        assert_eq!(
+            config
+                .get_group_rule("git.corp.example.com")
+                .map(|rule| rule.group.as_str()),
+            Some("Git/Work")
+        );
+        assert!(config.get_group_rule("github.com").is_none());
+    }
+
+    #[test]
+    fn test_10_database_priority() {
+        let config: Config =
+            serde_json::from_str(r#"{"database_priority": ["work", "personal id"]}"#).unwrap();
+        assert_eq!(
+            config.get_database_priority(&database("work id", Some("work"))),
+            0
+        );
+        assert_eq!(
+            config.get_database_priority(&database("personal id", None)),
+            1
+        );
+        assert_eq!(config.get_database_priority(&database("other id", None)), 2);
+    }
+
+    #[test]
+    fn test_11_remove_database() {
+        let mut config = Config::new();
+        let group = Group::new("mock group", "mock uuid");
+        for id in &["old database", "new database"] {
+            let database = Database::new((*id).to_owned(), generate_secret_key(), group.clone());
+            config.add_database(database, false).unwrap();
+        }
+        config.database_priority = vec!["old database".to_owned(), "new database".to_owned()];
+        config.set_target_database_name(Some("old database".to_owned()));
+        config.remove_database("old database").unwrap();
+        let databases = config.get_databases().unwrap();
+        assert_eq!(databases.len(), 1);
+        assert_eq!(databases[0].id, "new database");
+        assert_eq!(config.database_priority, ["new database"]);
+        assert_eq!(config.get_target_database_name(), None);
+        assert!(config.remove_database("old database").is_err());
+    }
+
+    #[test]
+    fn test_12_marker_field() {
+        let marker = Config::new().get_marker_field();
+        assert_eq!(marker.name, "git");
+        assert!(marker.excludes(Some("false")));
+        assert!(!marker.excludes(Some("true")));
+        assert!(!marker.excludes(None));
+
+        let config: Config =
+            serde_json::from_str(r#"{"marker_field": {"name": "vcs", "false_values": ["no", "false"], "use_unlabeled": false}}"#)
+                .unwrap();
+        let marker = config.get_marker_field();
+        assert_eq!(marker.name, "vcs");
+        assert!(marker.excludes(Some("No")));
+        assert!(!marker.excludes(Some("yes")));
+        assert!(marker.excludes(None));
+    }
+
+    #[test]
+    fn test_13_canonical_json() {
+        let config: Config = serde_json::from_str(
+            r#"{"usernames": [{"username": "bot", "host": "github.com"}], "create_only": true}"#,
+        )
+        .unwrap();
+        let json = config.to_canonical_json().unwrap();
+        assert_eq!(
+            json,
+            "{\n  \"create_only\": true,\n  \"usernames\": [\n    {\n      \"host\": \"github.com\",\n      \"username\": \"bot\"\n    }\n  ]\n}\n"
+        );
+        let config: Config = serde_json::from_str(&json).unwrap();
+        assert_eq!(config.to_canonical_json().unwrap(), json);
+    }
+
+    #[test]
+    fn test_14_unknown_keys() {
+        let (config, unknown_keys) = Config::from_json(
+            r#"{"calers": [], "pinned_entries": [{"host": "github.com", "uuid": "0123", "uid": "4567"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(unknown_keys, ["calers", "pinned_entries.0.uid"]);
+        assert_eq!(config.get_pinned_entry("github.com"), Some("0123"));
+        let (_, unknown_keys) = Config::from_json(r#"{"strict_config": true}"#).unwrap();
+        assert!(unknown_keys.is_empty());
+    }
+
+    #[test]
+    fn test_15_json_schema() {
+        let schema: serde_json::Value =
+            serde_json::from_str(&Config::json_schema().unwrap()).unwrap();
+        assert_eq!(schema["additionalProperties"], false);
+        let properties = schema["properties"].as_object().unwrap();
+        assert!(properties.contains_key("callers"));
+        assert!(properties.contains_key("encryptions"));
+        assert!(!properties.contains_key("encryption_key"));
+        let variants: Vec<_> = schema["definitions"]["Encryption"]["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|variant| variant["required"].as_array().unwrap().clone())
+            .collect();
+        assert_eq!(variants, ["ChallengeResponse", "Piv"]);
+    }
+
+    #[test]
+    fn test_16_target_database() {
+        let databases = [
+            database("personal id", Some("personal")),
+            database("work id", Some("work")),
+        ];
+        let mut config = Config::new();
+        assert_eq!(
+            config.get_target_database(&databases).unwrap().id,
+            "personal id"
+        );
+        config.set_target_database_name(Some("work".to_owned()));
+        assert_eq!(
+            config.get_target_database(&databases).unwrap().id,
+            "work id"
+        );
+        config.set_target_database_name(Some("other".to_owned()));
+        assert!(config.get_target_database(&databases).is_err());
+
+        assert_eq!(
+            config
+                .get_entry_database(&databases, Some("work id"))
+                .unwrap()
+                .id,
+            "work id"
+        );
+        assert!(config
+            .get_entry_database(&databases, Some("other id"))
+            .is_err());
+        assert!(config.get_entry_database(&databases, None).is_err());
+        assert!(config.get_entry_database(&databases[..1], None).is_ok());
+    }
+
+    #[test]
+    fn test_17_find_database() {
+        let mut config = Config::new();
+        let group = Group::new("mock group", "mock uuid");
+        let mut database =
+            Database::new("work id".to_owned(), generate_secret_key(), group.clone());
+        database.label = Some("work".to_owned());
+        config.add_database(database, false).unwrap();
+        assert_eq!(config.find_database("work").unwrap().id, "work id");
+        assert_eq!(config.find_database("work id").unwrap().id, "work id");
+        assert!(config.find_database("personal").is_err());
+        let databases = config.list_databases().unwrap();
+        assert_eq!(databases.len(), 1);
+        assert!(!databases[0].encrypted);
+        let mut database = Database::new("other id".to_owned(), generate_secret_key(), group);
+        database.label = Some("work".to_owned());
+        config.add_database(database, false).unwrap();
+        let error = config.find_database("work").unwrap_err().to_string();
+        assert!(error.contains("work id, other id"));
+        assert_eq!(config.find_database("other id").unwrap().id, "other id");
+    }
+
+    /// Paths of the keys in the value that the schema doesn't allow, as a JSON Schema validator
+    /// would report them
+    fn schema_unknown_keys(
+        schema: &serde_json::Value,
+        definitions: &serde_json::Value,
+        value: &serde_json::Value,
+        path: &str,
+    ) -> Vec<String> {
+        if let Some(reference) = schema["$ref"].as_str() {
+            let definition = &definitions[reference.trim_start_matches("#/definitions/")];
+            return schema_unknown_keys(definition, definitions, value, path);
+        }
+        if let Some(subschemas) = schema["anyOf"].as_array().or(schema["oneOf"].as_array()) {
+            // e.g. the Some of an Option, or the variant of an enum named by the only key
+            let subschema = subschemas.iter().find(|subschema| {
+                let required = subschema["required"].as_array();
+                subschema["type"] != "null"
+                    && required
+                        .map(|keys| {
+                            keys.iter()
+                                .all(|key| value.get(key.as_str().unwrap()).is_some())
+                        })
+                        .unwrap_or(true)
+            });
+            return subschema
+                .map(|subschema| schema_unknown_keys(subschema, definitions, value, path))
+                .unwrap_or_default();
+        }
+        match value {
+            serde_json::Value::Object(object) => object
+                .iter()
+                .flat_map(|(key, value)| {
+                    let path = format!("{}{}", path, key);
+                    match schema["properties"].get(key) {
+                        Some(property) => {
+                            schema_unknown_keys(property, definitions, value, &format!("{}.", path))
+                        }
+                        None if schema["additionalProperties"] == false => vec![path],
+                        None => Vec::new(),
+                    }
+                })
+                .collect(),
+            serde_json::Value::Array(items) => items
+                .iter()
+                .enumerate()
+                .flat_map(|(i, item)| {
+                    let path = format!("{}{}.", path, i);
+                    schema_unknown_keys(&schema["items"], definitions, item, &path)
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_18_json_schema_nested_keys() {
+        let schema: serde_json::Value =
+            serde_json::from_str(&Config::json_schema().unwrap()).unwrap();
+        let definitions = &schema["definitions"];
+        let config = serde_json::json!({
+            "callers": [{"path": "/usr/bin/git", "sha265": "0123"}],
+            "databases": [{
+                "id": "id", "key": "key", "pkey": "pkey", "group": "Git", "group_uuid": "uuid",
+                "lable": "work", "unlock": {"max_retries": 3, "interval": 1000, "retries": 3},
+            }],
+            "encryptions": [{"Piv": {"slot": 154, "peer_key": "", "key": "", "nonce": "", "reder": ""}}],
+            "pinned_entries": [{"host": "github.com", "uuid": "0123"}],
+        });
+        assert_eq!(
+            schema_unknown_keys(&schema, definitions, &config, ""),
+            [
+                "callers.0.sha265",
+                "databases.0.lable",
+                "databases.0.unlock.retries",
+                "encryptions.0.Piv.reder",
+            ]
+        );
+    }
+
     #[cfg(unix)]
     #[test]
     fn test_github_15_00_new_config_file_permissions() {
@@ -0,0 +1,71 @@
+use crate::keepassxc::errors::KeePassError;
+use serde::Serialize;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// Errors of git-credential-keepassxc itself that are reported using distinct exit codes, so that
+/// scripts can tell them apart from generic failures
+#[derive(Debug)]
+pub enum HelperError {
+    /// The host of the request is not in the allowlist in configuration file
+    HostNotAllowed(String),
+    /// Some database profiles failed the association check, with the number of them
+    AssociationCheckFailed(usize),
+    /// None of the logins matches the request after filtering (generic exit code, only told apart
+    /// to run the fallback helper)
+    NoMatchingLogins,
+    /// SIGINT or SIGTERM was received while waiting, e.g. for a database to be unlocked
+    Interrupted,
+}
+
+impl HelperError {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Self::HostNotAllowed(_) => 2,
+            Self::AssociationCheckFailed(_) => 3,
+            Self::NoMatchingLogins => 1,
+            // same as shells report for processes killed by SIGINT
+            Self::Interrupted => 130,
+        }
+    }
+}
+
+impl Display for HelperError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HostNotAllowed(host) => write!(f, "Host {} is not allowed", host),
+            Self::AssociationCheckFailed(count) => {
+                write!(f, "{} database(s) failed the association check", count)
+            }
+            Self::NoMatchingLogins => write!(f, "No matching logins found"),
+            Self::Interrupted => write!(f, "Interrupted"),
+        }
+    }
+}
+
+impl Error for HelperError {}
+
+/// Final error written to stderr as JSON when --errors-json is given, for wrappers that want to
+/// present errors without scraping logs
+#[derive(Serialize, Debug)]
+pub struct ErrorReport {
+    /// Same as the exit code
+    pub code: i32,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<&'static str>,
+}
+
+/// Whether the error means there are no matching logins, rather than e.g. a connection failure
+pub fn is_no_match(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<KeePassError>()
+        .map(KeePassError::is_no_logins_found)
+        .unwrap_or(false)
+        || matches!(
+            error.downcast_ref::<HelperError>(),
+            Some(HelperError::NoMatchingLogins)
+        )
+}
@@ -6,6 +6,7 @@ use serde::{Deserialize, Serialize};
 pub struct Group {
     pub name: String,
     pub uuid: String,
+    #[serde(default)]
     pub children: Vec<Group>,
 }
 
@@ -17,4 +18,102 @@ impl Group {
             ..Default::default()
         }
     }
+
+    /// Finds a descendant group by its path relative to this group, e.g. `Git/Work`, which is the
+    /// same format KeePassXC accepts in create-new-group
+    pub fn find_by_path<T: AsRef<str>>(&self, path: T) -> Option<&Group> {
+        let mut group = self;
+        for name in path.as_ref().split('/').filter(|n| !n.is_empty()) {
+            group = group.children.iter().find(|g| g.name == name)?;
+        }
+        Some(group)
+    }
+
+    pub fn find_by_uuid<T: AsRef<str>>(&self, uuid: T) -> Option<&Group> {
+        if self.uuid == uuid.as_ref() {
+            return Some(self);
+        }
+        self.children
+            .iter()
+            .find_map(|g| g.find_by_uuid(uuid.as_ref()))
+    }
+
+    /// Returns all descendants along with their paths relative to this group, in depth-first order
+    pub fn get_descendant_paths(&self) -> Vec<(String, &Group)> {
+        let mut paths = Vec::new();
+        for child in &self.children {
+            paths.push((child.name.clone(), child));
+            for (path, group) in child.get_descendant_paths() {
+                paths.push((format!("{}/{}", child.name, path), group));
+            }
+        }
+        paths
+    }
+
+    /// Renders the group and its descendants as an indented tree, one group per line
+    pub fn to_tree_string(&self) -> String {
+        let mut tree = String::new();
+        self.write_tree(&mut tree, 0);
+        tree
+    }
+
+    fn write_tree(&self, tree: &mut String, depth: usize) {
+        tree.push_str(&"  ".repeat(depth));
+        tree.push_str(&format!("{} ({})\n", self.name, self.uuid));
+        for child in &self.children {
+            child.write_tree(tree, depth + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mock_group_tree() -> Group {
+        let mut work = Group::new("Work", "uuid-work");
+        work.children.push(Group::new("Internal", "uuid-internal"));
+        let mut git = Group::new("Git", "uuid-git");
+        git.children.push(work);
+        let mut root = Group::new("Root", "uuid-root");
+        root.children.push(git);
+        root.children.push(Group::new("Email", "uuid-email"));
+        root
+    }
+
+    #[test]
+    fn test_00_find_group_by_path() {
+        let root = mock_group_tree();
+        assert_eq!(root.find_by_path("Git").unwrap().uuid, "uuid-git");
+        assert_eq!(root.find_by_path("Git/Work/").unwrap().uuid, "uuid-work");
+        assert_eq!(root.find_by_path("").unwrap().uuid, "uuid-root");
+        assert!(root.find_by_path("Work").is_none());
+    }
+
+    #[test]
+    fn test_01_find_group_by_uuid() {
+        let root = mock_group_tree();
+        assert_eq!(root.find_by_uuid("uuid-internal").unwrap().name, "Internal");
+        assert_eq!(root.find_by_uuid("uuid-email").unwrap().name, "Email");
+        assert!(root.find_by_uuid("uuid-missing").is_none());
+    }
+
+    #[test]
+    fn test_02_get_descendant_paths() {
+        let root = mock_group_tree();
+        let paths: Vec<_> = root
+            .get_descendant_paths()
+            .into_iter()
+            .map(|(path, group)| (path, group.uuid.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("Git".to_owned(), "uuid-git"),
+                ("Git/Work".to_owned(), "uuid-work"),
+                ("Git/Work/Internal".to_owned(), "uuid-internal"),
+                ("Email".to_owned(), "uuid-email"),
+            ]
+        );
+    }
 }
@@ -1,6 +1,145 @@
 use super::messages::GenericResponseWrapper;
 use std::error::Error;
 use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// Exit codes of KeePassXC errors are offset by this so that they don't clash with generic ones
+pub const KEEPASS_ERROR_EXIT_CODE_BASE: i32 = 100;
+
+macro_rules! define_error_code {
+    ([$(($variant:ident, $code:literal, $hint:literal),)*]) => {
+        /// Error codes documented in keepassxc-browser, see BrowserAction.h in KeePassXC
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum KeePassErrorCode {
+            $($variant,)*
+            Unknown(i32),
+        }
+
+        impl KeePassErrorCode {
+            pub fn from_code(code: i32) -> Self {
+                match code {
+                    $($code => Self::$variant,)*
+                    _ => Self::Unknown(code),
+                }
+            }
+
+            pub fn code(&self) -> i32 {
+                match *self {
+                    $(Self::$variant => $code,)*
+                    Self::Unknown(code) => code,
+                }
+            }
+
+            /// User-facing hint on how to deal with the error
+            pub fn hint(&self) -> &'static str {
+                match *self {
+                    $(Self::$variant => $hint,)*
+                    Self::Unknown(_) => "Unknown error, check KeePassXC for details",
+                }
+            }
+        }
+    };
+}
+
+define_error_code!([
+    (
+        DatabaseNotOpened,
+        1,
+        "Unlock the database in KeePassXC, or use --unlock to wait for it"
+    ),
+    (
+        DatabaseHashNotReceived,
+        2,
+        "Make sure a database is opened in KeePassXC"
+    ),
+    (
+        ClientPublicKeyNotReceived,
+        3,
+        "Key exchange failed, try again"
+    ),
+    (
+        CannotDecryptMessage,
+        4,
+        "KeePassXC failed to decrypt the request, was it restarted?"
+    ),
+    (
+        TimeoutOrNotConnected,
+        5,
+        "Make sure KeePassXC is running with browser integration enabled"
+    ),
+    (
+        ActionCancelledOrDenied,
+        6,
+        "The request was cancelled or denied in KeePassXC"
+    ),
+    (
+        CannotEncryptMessage,
+        7,
+        "KeePassXC failed to encrypt the response, try again"
+    ),
+    (
+        AssociationFailed,
+        8,
+        "The association may have been removed from KeePassXC, run configure --renew"
+    ),
+    (KeyChangeFailed, 9, "Key exchange failed, try again"),
+    (
+        EncryptionKeyUnrecognized,
+        10,
+        "The association has been removed from KeePassXC, run configure --renew"
+    ),
+    (
+        NoSavedDatabasesFound,
+        11,
+        "Run configure to associate with a database first"
+    ),
+    (
+        IncorrectAction,
+        12,
+        "KeePassXC doesn't support this request, try upgrading it"
+    ),
+    (
+        EmptyMessageReceived,
+        13,
+        "KeePassXC received an empty request, this is probably a bug"
+    ),
+    (NoUrlProvided, 14, "No URL was provided in the request"),
+    (
+        NoLoginsFound,
+        15,
+        "No matching logins found in the associated databases"
+    ),
+    (NoGroupsFound, 16, "No groups found in the database"),
+    (
+        CannotCreateNewGroup,
+        17,
+        "KeePassXC failed to create the group, check the group name"
+    ),
+    (
+        NoValidUuidProvided,
+        18,
+        "The entry or group UUID is invalid"
+    ),
+    (
+        AccessToAllEntriesDenied,
+        19,
+        "Access to all entries is denied, check the entry permissions in KeePassXC"
+    ),
+]);
+
+impl FromStr for KeePassErrorCode {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from_code(i32::from_str(s.trim())?))
+    }
+}
+
+impl KeePassErrorCode {
+    pub fn exit_code(&self) -> i32 {
+        KEEPASS_ERROR_EXIT_CODE_BASE + self.code()
+    }
+}
 
 #[derive(Debug)]
 pub struct KeePassError {
@@ -9,13 +148,38 @@ pub struct KeePassError {
 }
 
 impl KeePassError {
+    pub fn get_error_code(&self) -> Option<KeePassErrorCode> {
+        self.response
+            .error_code
+            .as_ref()
+            .and_then(|c| KeePassErrorCode::from_str(c).ok())
+    }
+
     pub fn is_database_locked(&self) -> bool {
-        if let Some(error_message) = &self.response.error {
-            error_message.contains("not opened")
-        } else {
-            false
+        match self.get_error_code() {
+            Some(code) => code == KeePassErrorCode::DatabaseNotOpened,
+            // fall back to the error message in case error code is missing
+            None => self
+                .response
+                .error
+                .as_ref()
+                .map(|e| e.contains("not opened"))
+                .unwrap_or(false),
         }
     }
+
+    pub fn is_no_logins_found(&self) -> bool {
+        self.get_error_code() == Some(KeePassErrorCode::NoLoginsFound)
+    }
+
+    /// Whether KeePassXC no longer recognises the association, e.g. it's been removed by user
+    pub fn is_association_revoked(&self) -> bool {
+        matches!(
+            self.get_error_code(),
+            Some(KeePassErrorCode::AssociationFailed)
+                | Some(KeePassErrorCode::EncryptionKeyUnrecognized)
+        )
+    }
 }
 
 impl Display for KeePassError {
@@ -26,3 +190,25 @@ impl Display for KeePassError {
 }
 
 impl Error for KeePassError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_00_error_code_round_trip() {
+        for code in 1..=19 {
+            let error_code = KeePassErrorCode::from_code(code);
+            assert!(!matches!(error_code, KeePassErrorCode::Unknown(_)));
+            assert_eq!(error_code.code(), code);
+        }
+        assert_eq!(
+            KeePassErrorCode::from_str("15").unwrap(),
+            KeePassErrorCode::NoLoginsFound
+        );
+        assert_eq!(
+            KeePassErrorCode::from_code(42),
+            KeePassErrorCode::Unknown(42)
+        );
+    }
+}
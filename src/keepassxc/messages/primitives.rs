@@ -93,4 +93,24 @@ define_action!([
     (DatabaseLocked, "database-locked"),
     (DatabaseUnlocked, "database-unlocked"),
     (CreateNewGroup, "create-new-group"),
+    (RequestAutotype, "request-autotype"),
+    (GetTotp, "get-totp"),
+    (DeleteEntry, "delete-entry"),
 ]);
+
+impl KeePassAction {
+    /// Whether the request doesn't change the database, so it can be sent again when it's unknown
+    /// if KeePassXC got it
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Self::ChangePublicKeys
+                | Self::GetDatabaseHash
+                | Self::TestAssociate
+                | Self::GeneratePassword
+                | Self::GetLogins
+                | Self::GetDatabaseGroups
+                | Self::GetTotp
+        )
+    }
+}
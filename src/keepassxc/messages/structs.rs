@@ -1,12 +1,23 @@
-use super::super::errors::KeePassError;
+use super::super::errors::{KeePassError, KeePassErrorCode};
 use super::primitives::*;
 use crate::utils::*;
 #[allow(unused_imports)]
 use crate::{debug, error, info, warn};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use crypto_box::PublicKey;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many times a read-only request is retried after transient transport errors, e.g. broken
+/// pipe, counting attempts to renew the session
+const MAX_TRANSPORT_RETRIES: usize = 3;
+const TRANSPORT_RETRY_INTERVAL_MS: u64 = 100;
 
 pub trait PlainTextRequest<R>
 where
@@ -14,12 +25,38 @@ where
     Self: Serialize,
 {
     fn send(&self) -> Result<R> {
+        let mut remain_retries = MAX_TRANSPORT_RETRIES;
+        loop {
+            match self.send_once() {
+                Err(e)
+                    if remain_retries > 0
+                        && self.get_action().is_read_only()
+                        && is_transient_error(&e) =>
+                {
+                    warn!(
+                        "{} request failed due to transport error, gonna reconnect and retry (Remaining: {}), {}",
+                        self.get_action().to_string(),
+                        remain_retries,
+                        e
+                    );
+                    remain_retries -= 1;
+                    reset_stream();
+                    thread::sleep(Duration::from_millis(TRANSPORT_RETRY_INTERVAL_MS));
+                }
+                result => break result,
+            }
+        }
+    }
+
+    fn send_once(&self) -> Result<R> {
         info!("Sending {} request", self.get_action().to_string());
+        let started = Instant::now();
         let request_json = serde_json::to_string(self)?;
         #[cfg(not(test))]
         let response_json = MessengingUtils::exchange_message(request_json)?;
         #[cfg(test)]
         let response_json = MockMessengingUtils::exchange_message(request_json)?;
+        trace_elapsed(&self.get_action(), started);
         let response: R = serde_json::from_str(&response_json)?;
         Ok(response)
     }
@@ -33,7 +70,58 @@ where
     Self: Serialize,
 {
     fn send<T: Into<String>>(&self, client_id: T, trigger_unlock: bool) -> Result<R> {
+        let client_id = client_id.into();
+        let mut remain_retries = MAX_TRANSPORT_RETRIES;
+        let mut session_renewed = false;
+        loop {
+            match self.send_once(client_id.as_str(), trigger_unlock) {
+                Err(e) if !session_renewed && is_session_lost(&e) => {
+                    warn!(
+                        "{} request failed as session keys are no longer valid (KeePassXC restarted?), gonna renew the session and retry, {}",
+                        self.get_action().to_string(),
+                        e
+                    );
+                    session_renewed = true;
+                    renew_session(client_id.as_str())?;
+                }
+                // requests changing the database may have been carried out before the connection
+                // broke, so only read-only ones are sent again
+                Err(e)
+                    if remain_retries > 0
+                        && self.get_action().is_read_only()
+                        && is_transient_error(&e) =>
+                {
+                    warn!(
+                        "{} request failed due to transport error, gonna reconnect and retry (Remaining: {}), {}",
+                        self.get_action().to_string(),
+                        remain_retries,
+                        e
+                    );
+                    // KeePassXC forgets the session keys once the connection is gone, and failing
+                    // to renew them uses up an attempt as well
+                    loop {
+                        remain_retries -= 1;
+                        thread::sleep(Duration::from_millis(TRANSPORT_RETRY_INTERVAL_MS));
+                        match renew_session(client_id.as_str()) {
+                            Ok(()) => break,
+                            Err(e) if remain_retries > 0 && is_transient_error(&e) => {
+                                warn!(
+                                    "Failed to renew the session, gonna retry (Remaining: {}), {}",
+                                    remain_retries, e
+                                );
+                            }
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                result => break result,
+            }
+        }
+    }
+
+    fn send_once<T: Into<String>>(&self, client_id: T, trigger_unlock: bool) -> Result<R> {
         info!("Sending {} request", self.get_action().to_string());
+        let started = Instant::now();
         let (nonce, nonce_b64) = nacl_nonce();
         let encrypted_request_json = to_encrypted_json(&self, &nonce)?;
         let trigger_unlock = if trigger_unlock {
@@ -68,6 +156,7 @@ where
                 response_wrapper_json
             );
         };
+        trace_elapsed(&self.get_action(), started);
         response_wrapper.log();
         if response_wrapper.message.is_some() && response_wrapper.nonce.is_some() {
             let (message, nonce) = (
@@ -108,10 +197,66 @@ impl_cipher_text!([
     (TestAssociateRequest, TestAssociateResponse),
     (GetLoginsRequest, GetLoginsResponse),
     (SetLoginRequest, SetLoginResponse),
-    // (GetDatabaseGroupsRequest, GetDatabaseGroupsResponse),
+    (GetDatabaseGroupsRequest, GetDatabaseGroupsResponse),
     (CreateNewGroupRequest, CreateNewGroupResponse),
+    (RequestAutotypeRequest, RequestAutotypeResponse),
+    (GetTotpRequest, GetTotpResponse),
+    (DeleteEntryRequest, DeleteEntryResponse),
 ]);
 
+pub fn exchange_keys<T: AsRef<str>>(client_id: T, session_pubkey: &PublicKey) -> Result<PublicKey> {
+    crate::otlp::in_span("key exchange", || {
+        // exchange public keys
+        let cpr_req = ChangePublicKeysRequest::new(client_id.as_ref(), session_pubkey);
+        let cpr_resp = cpr_req.send()?;
+        set_keepassxc_version(cpr_resp.version.clone());
+        let success = cpr_resp.success.as_ref().map(|s| s.0).unwrap_or(true);
+        match cpr_resp.get_public_key() {
+            Some(host_pubkey) if success => Ok(host_pubkey),
+            _ => Err(anyhow!(
+                "KeePassXC refused to exchange keys. {}",
+                BROWSER_INTEGRATION_HINT
+            )),
+        }
+    })
+}
+
+fn trace_elapsed(action: &KeePassAction, started: Instant) {
+    if is_trace_protocol() {
+        info!(
+            "TRACE {} completed in {}ms",
+            action.to_string(),
+            started.elapsed().as_millis()
+        );
+    }
+}
+
+/// Whether the error indicates that KeePassXC and us no longer share the same session keys, which
+/// happens when KeePassXC is restarted in the middle of a session
+fn is_session_lost(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        if let Some(cryption_error) = e.downcast_ref::<CryptionError>() {
+            cryption_error.is_decryption()
+        } else if let Some(keepass_error) = e.downcast_ref::<KeePassError>() {
+            keepass_error.get_error_code() == Some(KeePassErrorCode::CannotDecryptMessage)
+        } else {
+            false
+        }
+    })
+}
+
+/// Reconnects to KeePassXC and exchanges a fresh pair of session keys for the given client ID
+pub fn renew_session<T: AsRef<str>>(client_id: T) -> Result<()> {
+    reset_stream();
+    let session_seckey = generate_secret_key();
+    let session_pubkey = session_seckey.public_key();
+    let host_pubkey = exchange_keys(client_id.as_ref(), &session_pubkey)?;
+    reset_client_box();
+    get_client_box(Some(&host_pubkey), Some(&session_seckey))?;
+    info!("Session renewed");
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GenericRequestWrapper {
     pub action: KeePassAction,
@@ -220,6 +365,12 @@ impl GetDatabaseHashRequest {
     }
 }
 
+impl Default for GetDatabaseHashRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetDatabaseHashResponse {
     pub hash: Option<String>,
@@ -368,11 +519,49 @@ pub struct LoginEntry {
     #[serde(rename = "stringFields")]
     pub string_fields: Option<Vec<HashMap<String, String>>>,
     pub expired: Option<KeePassBoolean>,
+    /// Name of the group the entry lives in (KeePassXC 2.7+)
+    pub group: Option<String>,
+    /// Label (or ID) of the database the entry was found in, not part of the protocol
+    #[serde(skip)]
+    pub database: Option<String>,
+    /// ID of the database the entry was found in, where updates of it have to go
+    #[serde(skip)]
+    pub database_id: Option<String>,
+    /// UUID of the group the entry lives in, not part of the protocol but looked up by the group
+    /// name if no other group has the same name
+    #[serde(skip)]
+    pub group_uuid: Option<String>,
+}
+
+impl LoginEntry {
+    /// Returns the value of an advanced string field, `name` excluding the `KPH: ` prefix and
+    /// compared case-insensitively
+    pub fn get_string_field<T: AsRef<str>>(&self, name: T) -> Option<&str> {
+        let name = format!("KPH: {}", name.as_ref());
+        self.string_fields
+            .as_ref()?
+            .iter()
+            .flat_map(|fields| fields.iter())
+            .find(|(k, _)| k.eq_ignore_ascii_case(&name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns all advanced string fields, names excluding the `KPH: ` prefix
+    pub fn get_string_fields(&self) -> Vec<(&str, &str)> {
+        self.string_fields
+            .iter()
+            .flatten()
+            .flat_map(|fields| fields.iter())
+            .filter_map(|(k, v)| Some((k.strip_prefix("KPH: ")?, v.as_str())))
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct GetLoginsResponse {
+    /// Number of all the matching logins, which may be more than `entries` if they are capped
     pub count: usize,
+    #[serde(deserialize_with = "deserialize_capped_entries")]
     pub entries: Vec<LoginEntry>,
     pub hash: Option<String>,
     /* generic fields */
@@ -385,6 +574,57 @@ pub struct GetLoginsResponse {
     pub error_code: Option<String>,
 }
 
+/// Deserializes the entries one by one, keeping the first `CandidateLimit::max` of them (which
+/// KeePassXC ranks best) and the later ones of the username, and skipping over the rest
+fn deserialize_capped_entries<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<LoginEntry>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct CappedEntries(Option<CandidateLimit>);
+
+    impl<'de> Visitor<'de> for CappedEntries {
+        type Value = Vec<LoginEntry>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an array of login entries")
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut entries = Vec::new();
+            loop {
+                match &self.0 {
+                    Some(limit) if entries.len() >= limit.max => {
+                        // without a username, there's nothing left to look at
+                        if limit.username.is_none() {
+                            if seq.next_element::<IgnoredAny>()?.is_none() {
+                                break;
+                            }
+                            continue;
+                        }
+                        match seq.next_element::<LoginEntry>()? {
+                            Some(entry) if limit.keeps(&entry.login) => entries.push(entry),
+                            Some(_) => {}
+                            None => break,
+                        }
+                    }
+                    _ => match seq.next_element()? {
+                        Some(entry) => entries.push(entry),
+                        None => break,
+                    },
+                }
+            }
+            Ok(entries)
+        }
+    }
+
+    deserializer.deserialize_seq(CappedEntries(CandidateLimit::get()))
+}
+
 /*
  * set-login
  * https://github.com/keepassxreboot/keepassxc-browser/blob/develop/keepassxc-protocol.md#set-login
@@ -461,44 +701,50 @@ pub struct SetLoginResponse {
  * https://github.com/keepassxreboot/keepassxc-browser/blob/develop/keepassxc-protocol.md#get-database-groups
  */
 
-// #[derive(Serialize, Deserialize, Debug)]
-// pub struct GetDatabaseGroupsRequest {
-//     action: KeePassAction,
-// }
-//
-// impl GetDatabaseGroupsRequest {
-//     pub fn new() -> Self {
-//         Self {
-//             action: KeePassAction::GetDatabaseGroups,
-//         }
-//     }
-// }
-//
-// #[derive(Serialize, Deserialize, Debug)]
-// struct InnerGroups {
-//     pub groups: Vec<crate::keepassxc::Group>,
-// }
-//
-// #[derive(Serialize, Deserialize, Debug)]
-// pub struct GetDatabaseGroupsResponse {
-//     #[serde(rename = "defaultGroup")]
-//     pub default_group: Option<String>,
-//     #[serde(rename = "defaultGroupAlwaysAllow")]
-//     pub default_group_always_allow: Option<bool>,
-//     groups: InnerGroups,
-//     [> generic fields <]
-//     pub version: Option<String>,
-//     pub success: Option<KeePassBoolean>,
-//     pub error: Option<String>,
-//     #[serde(rename = "errorCode")]
-//     pub error_code: Option<String>,
-// }
-//
-// impl GetDatabaseGroupsResponse {
-//     pub fn get_groups(&self) -> &[crate::keepassxc::Group] {
-//         &self.groups.groups
-//     }
-// }
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetDatabaseGroupsRequest {
+    action: KeePassAction,
+}
+
+impl GetDatabaseGroupsRequest {
+    pub fn new() -> Self {
+        Self {
+            action: KeePassAction::GetDatabaseGroups,
+        }
+    }
+}
+
+impl Default for GetDatabaseGroupsRequest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct InnerGroups {
+    pub groups: Vec<crate::keepassxc::Group>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetDatabaseGroupsResponse {
+    #[serde(rename = "defaultGroup")]
+    pub default_group: Option<String>,
+    #[serde(rename = "defaultGroupAlwaysAllow")]
+    pub default_group_always_allow: Option<bool>,
+    groups: InnerGroups,
+    /* generic fields */
+    pub version: Option<String>,
+    pub success: Option<KeePassBoolean>,
+    pub error: Option<String>,
+    #[serde(rename = "errorCode")]
+    pub error_code: Option<String>,
+}
+
+impl GetDatabaseGroupsResponse {
+    pub fn get_groups(&self) -> &[crate::keepassxc::Group] {
+        &self.groups.groups
+    }
+}
 
 /*
  * create-new-group
@@ -535,6 +781,104 @@ pub struct CreateNewGroupResponse {
     pub error_code: Option<String>,
 }
 
+/*
+ * request-autotype
+ * https://github.com/keepassxreboot/keepassxc-browser/blob/develop/keepassxc-protocol.md#request-autotype
+ */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestAutotypeRequest {
+    action: KeePassAction,
+    search: String,
+}
+
+impl RequestAutotypeRequest {
+    pub fn new<T: Into<String>>(search: T) -> Self {
+        Self {
+            action: KeePassAction::RequestAutotype,
+            search: search.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RequestAutotypeResponse {
+    /* generic fields */
+    pub version: Option<String>,
+    pub id: Option<String>,
+    pub nonce: Option<String>,
+    pub success: Option<KeePassBoolean>,
+    pub error: Option<String>,
+    #[serde(rename = "errorCode")]
+    pub error_code: Option<String>,
+}
+
+/*
+ * get-totp
+ * https://github.com/keepassxreboot/keepassxc-browser/blob/develop/keepassxc-protocol.md#get-totp
+ */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetTotpRequest {
+    action: KeePassAction,
+    uuid: String,
+}
+
+impl GetTotpRequest {
+    pub fn new<T: Into<String>>(uuid: T) -> Self {
+        Self {
+            action: KeePassAction::GetTotp,
+            uuid: uuid.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GetTotpResponse {
+    /// Current code, empty if the entry has no TOTP configured
+    pub totp: Option<String>,
+    /* generic fields */
+    pub version: Option<String>,
+    pub id: Option<String>,
+    pub nonce: Option<String>,
+    pub success: Option<KeePassBoolean>,
+    pub error: Option<String>,
+    #[serde(rename = "errorCode")]
+    pub error_code: Option<String>,
+}
+
+/*
+ * delete-entry
+ * https://github.com/keepassxreboot/keepassxc-browser/blob/develop/keepassxc-protocol.md#delete-entry
+ */
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteEntryRequest {
+    action: KeePassAction,
+    uuid: String,
+}
+
+impl DeleteEntryRequest {
+    pub fn new<T: Into<String>>(uuid: T) -> Self {
+        Self {
+            action: KeePassAction::DeleteEntry,
+            uuid: uuid.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteEntryResponse {
+    /* generic fields */
+    pub version: Option<String>,
+    pub id: Option<String>,
+    pub nonce: Option<String>,
+    pub success: Option<KeePassBoolean>,
+    pub error: Option<String>,
+    #[serde(rename = "errorCode")]
+    pub error_code: Option<String>,
+}
+
 // no specs, need to dig into codes
 //
 // message_req_type!(DatabaseLockedReq, DatabaseLocked, "database-locked-req");
@@ -640,4 +984,115 @@ mod tests {
         receive_message_context.checkpoint();
         send_message_context.checkpoint();
     }
+
+    #[test]
+    fn test_02_detect_lost_session() {
+        let keepass_error = |error_code: &str| {
+            anyhow::Error::from(KeePassError {
+                message: "mock".to_owned(),
+                response: GenericResponseWrapper {
+                    action: KeePassAction::GetLogins,
+                    message: None,
+                    nonce: None,
+                    error: Some("mock".to_owned()),
+                    error_code: Some(error_code.to_owned()),
+                },
+            })
+        };
+        assert!(is_session_lost(&keepass_error("4")));
+        assert!(!is_session_lost(&keepass_error("15")));
+
+        let session_seckey = test_session_secret_key();
+        let session_pubkey = session_seckey.public_key();
+        let _ = get_client_box(Some(&session_pubkey), Some(&session_seckey));
+        let (_, nonce_b64) = nacl_nonce();
+        let decryption_error = to_decrypted_json(base64::encode("mock"), nonce_b64).unwrap_err();
+        assert!(is_session_lost(&decryption_error));
+    }
+
+    #[test]
+    fn test_03_get_string_field() {
+        let mut token_field = HashMap::new();
+        token_field.insert("KPH: Token".to_owned(), "mock-token".to_owned());
+        let mut git_field = HashMap::new();
+        git_field.insert("KPH: git".to_owned(), "true".to_owned());
+        let login_entry = LoginEntry {
+            login: "foo".to_owned(),
+            name: "mock".to_owned(),
+            password: "bar".to_owned(),
+            uuid: "mock-uuid".to_owned(),
+            string_fields: Some(vec![git_field, token_field]),
+            expired: None,
+            group: None,
+            database: None,
+            database_id: None,
+            group_uuid: None,
+        };
+        assert_eq!(login_entry.get_string_field("token"), Some("mock-token"));
+        assert_eq!(login_entry.get_string_field("git"), Some("true"));
+        assert_eq!(login_entry.get_string_field("PAT"), None);
+    }
+
+    #[test]
+    fn test_04_update_set_login_request() {
+        let set_login_request = SetLoginRequest::new(
+            "https://example.com",
+            "https://example.com",
+            "mock-db",
+            "foo",
+            "bar",
+            None,
+            None,
+            Some("mock-uuid"),
+        );
+        let json = serde_json::to_value(&set_login_request).unwrap();
+        let mut keys: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        // nothing but the username and password of the entry
+        assert_eq!(
+            keys,
+            [
+                "action",
+                "id",
+                "login",
+                "nonce",
+                "password",
+                "submitUrl",
+                "url",
+                "uuid"
+            ]
+        );
+        assert_eq!(json["uuid"], "mock-uuid");
+    }
+
+    #[test]
+    fn test_05_capped_entries() {
+        let entry = |login| {
+            format!(
+                r#"{{"login":"{0}","name":"mock","password":"bar","uuid":"{0}-uuid"}}"#,
+                login
+            )
+        };
+        let json = format!(
+            r#"{{"count":3,"entries":[{},{},{}],"success":"true"}}"#,
+            entry("foo"),
+            entry("bar"),
+            entry("baz")
+        );
+        let gl_resp: GetLoginsResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(gl_resp.entries.len(), 3);
+        let logins = |username| {
+            let _limit = CandidateLimit::set(Some(1), username);
+            let gl_resp: GetLoginsResponse = serde_json::from_str(&json).unwrap();
+            assert_eq!(gl_resp.count, 3);
+            gl_resp
+                .entries
+                .into_iter()
+                .map(|entry| entry.login)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(logins(None), ["foo"]);
+        assert_eq!(logins(Some("baz")), ["foo", "baz"]);
+        assert!(CandidateLimit::get().is_none());
+    }
 }
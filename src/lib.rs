@@ -0,0 +1,57 @@
+//! Talks to KeePassXC via its browser integration socket, as `git-credential-keepassxc` does, for
+//! other tools to look up logins using the same configuration file and associations
+//!
+//! [`session::start_session`] establishes an encrypted session, after which the messages in
+//! [`keepassxc::messages`] can be sent, e.g. [`keepassxc::messages::GetLoginsRequest`] for the
+//! databases returned by [`session::associated_databases`], over the socket that
+//! [`session::set_socket_path`] may point to instead of the default one. [`backend::open_backend`]
+//! does all of this, and returns the logins of a URL sorted by database priority:
+//!
+//! ```no_run
+//! use git_credential_keepassxc::backend::open_backend;
+//! use git_credential_keepassxc::config::Config;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = Config::read_from("/home/user/.config/git-credential-keepassxc")?;
+//! let backend = open_backend(&config, &None)?;
+//! for login in backend.get_logins("https://example.com")? {
+//!     println!("{} ({})", login.login, login.name);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`logins::find_login`] picks the login that `git-credential-keepassxc get` would return for a
+//! [`git::GitCredentialMessage`] instead, skipping the ones hidden from Git, recycled or revoked,
+//! and [`logins::save_login`] stores logins the way `store` does.
+//!
+//! Logs are only written once [`LOGGER`] (and [`DETAIL_LOGGER`] for sensitive details) are set.
+//! Modules hidden from the documentation are internals of the command line interface and may
+//! change at any time.
+pub mod backend;
+mod cli;
+#[doc(hidden)]
+pub mod commands;
+pub mod config;
+mod errors;
+pub mod git;
+pub mod keepassxc;
+pub mod logins;
+#[doc(hidden)]
+pub mod otlp;
+#[cfg(all(target_os = "linux", feature = "secret-service"))]
+mod secret_service;
+pub mod session;
+mod toast;
+mod update;
+mod utils;
+
+pub use cli::UnlockOptions;
+
+use once_cell::sync::OnceCell;
+use slog::Logger;
+
+/// Logger of everything but sensitive details
+pub static LOGGER: OnceCell<Logger> = OnceCell::new();
+/// Logger of sensitive details (paths, IDs, etc.), which only go to the log file
+pub static DETAIL_LOGGER: OnceCell<Logger> = OnceCell::new();
@@ -0,0 +1,930 @@
+//! Looking up and storing logins the way Git asks for them, skipping the ones that aren't meant to
+//! be used here (hidden by the marker field, recycled, revoked, etc.)
+use crate::backend::SecretBackend;
+use crate::config::{Config, MarkerField, StoreConflict};
+use crate::errors::{is_no_match, HelperError};
+use crate::git::{get_url_host, parent_domain_urls, read_public_suffix_list, GitCredentialMessage};
+use crate::keepassxc::{errors::KeePassError, messages::LoginEntry};
+use crate::utils::{
+    ensure_prompt_allowed, explain, get_username, prompt_tty, read_recent_entry, CandidateLimit,
+};
+use crate::{detail, info, warn};
+use anyhow::{anyhow, Context, Result};
+use std::str::FromStr;
+use url::Url;
+
+/// How to look up logins for `get`, from command line, Git configuration and configuration file
+#[derive(Clone, Debug, Default)]
+pub struct LookupOptions {
+    /// Keeps the logins of other usernames even if some match the requested one
+    pub no_filter_username: bool,
+    /// Keeps the logins in the recycle bin
+    pub include_recycled: bool,
+    /// Returns the token field instead of the password for forges accepting tokens
+    pub prefer_token: bool,
+    /// Returns advanced string fields as extra Git credential attributes
+    pub advanced_fields: bool,
+    /// How many parent domains to try if the URL has got no logins, `None` to use the
+    /// configuration file
+    pub parent_domain_depth: Option<u8>,
+    /// How many of the logins left after filtering are considered at most, all if `None`
+    pub max_candidates: Option<usize>,
+}
+
+/// How `store` saves logins, from command line, Git configuration and configuration file
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    /// Only creates new logins, leaving existing ones of the username alone
+    pub create_only: bool,
+    /// Which login to update when several existing ones have the username
+    pub conflict: StoreConflict,
+    /// String field hiding entries from Git
+    pub marker: MarkerField,
+    /// Only updates logins whose `KPH: git-allow` names this user or machine
+    pub require_allow_marker: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            create_only: false,
+            conflict: StoreConflict::Ask,
+            marker: MarkerField::default(),
+            require_allow_marker: false,
+        }
+    }
+}
+
+/// Refuses requests for hosts that are not in the allowlist, if any
+pub fn verify_host(config: &Config, url: &str) -> Result<()> {
+    let host = get_url_host(url);
+    if config.is_host_allowed(&host) {
+        return Ok(());
+    }
+    warn!("Request refused as the host is not in the allowlist");
+    Err(HelperError::HostNotAllowed(host).into())
+}
+
+/// Forges which accept personal access tokens in place of passwords over HTTPS, and the username
+/// to use along with tokens when the entry doesn't have one
+const TOKEN_FORGES: &[(&str, &str)] = &[("github.com", "x-access-token"), ("gitlab.com", "oauth2")];
+/// Attributes which tell Git what to do rather than describe the credential, e.g. `quit=1` stops
+/// Git from asking other helpers, so they're never taken from entries
+const GIT_CONTROL_ATTRIBUTES: &[&str] = &[
+    "quit",
+    "continue",
+    "capability[]",
+    "wwwauth[]",
+    "authtype",
+    "credential",
+    "state[]",
+    "ephemeral",
+];
+/// Names of advanced string fields (without `KPH: ` prefix) that may carry tokens
+const TOKEN_FIELDS: &[&str] = &["token", "PAT"];
+
+/// Returns the username and token to use instead of the login itself, if the URL belongs to a
+/// known forge and the entry has got a token field
+pub fn get_token_login<'a>(url: &str, login: &'a LoginEntry) -> Option<(&'a str, &'a str)> {
+    let host = Url::parse(url).ok()?.host_str()?.to_owned();
+    let default_username = TOKEN_FORGES
+        .iter()
+        .find(|(forge, _)| host == *forge)
+        .map(|(_, username)| *username)?;
+    let token = TOKEN_FIELDS
+        .iter()
+        .find_map(|field| login.get_string_field(field))?;
+    let username = if login.login.is_empty() {
+        default_username
+    } else {
+        login.login.as_str()
+    };
+    Some((username, token))
+}
+
+/// Drops logins hidden from Git by the marker field, `KPH: git` == `false` by default
+pub fn filter_kph_logins<'a>(
+    login_entries: &'a [LoginEntry],
+    marker: &MarkerField,
+) -> (u32, Vec<&'a LoginEntry>) {
+    let mut kph_false = 0u32;
+    let login_entries: Vec<&LoginEntry> = login_entries
+        .iter()
+        .filter(|entry| {
+            let value = entry.get_string_field(&marker.name);
+            if !marker.excludes(value) {
+                return true;
+            }
+            kph_false += 1;
+            explain(format!(
+                "Dropped login {} of entry {} ({}): {}",
+                entry.login,
+                entry.name,
+                entry.uuid,
+                match value {
+                    Some(value) => format!("labeled as KPH: {} == {}", marker.name, value),
+                    None => format!("not labeled with KPH: {}", marker.name),
+                }
+            ));
+            false
+        })
+        .collect();
+    (kph_false, login_entries)
+}
+
+/// Drops logins of entries in the recycle bin, which KeePassXC may still return. Only works with
+/// KeePassXC 2.7+, which sends the group of entries.
+pub fn filter_recycled_logins<'a>(
+    login_entries: Vec<&'a LoginEntry>,
+    recycle_bin: &str,
+) -> Vec<&'a LoginEntry> {
+    login_entries
+        .into_iter()
+        .filter(|entry| {
+            let recycled = entry.group.as_deref() == Some(recycle_bin);
+            if recycled {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): entry is in the recycle bin {}",
+                    entry.login, entry.name, entry.uuid, recycle_bin
+                ));
+            }
+            !recycled
+        })
+        .collect()
+}
+
+/// Drops logins in the revoked group, along with the ones of the same username and password, which
+/// KeePassXC can't move there, see erase_login. Only works with KeePassXC 2.7+, which sends the
+/// group of entries, and only if no other group has the same name as the revoked one (which is
+/// warned about), as entries are told apart by group UUID.
+pub fn filter_revoked_logins(
+    backend: &dyn SecretBackend,
+    login_entries: Vec<LoginEntry>,
+    revoked_group: &str,
+) -> Result<Vec<LoginEntry>> {
+    let group_uuid = match backend.get_group_uuid(revoked_group)? {
+        Some(group_uuid) => group_uuid,
+        // nothing has been revoked yet
+        None => return Ok(login_entries),
+    };
+    let revoked: Vec<(String, String)> = login_entries
+        .iter()
+        .filter(|entry| entry.group_uuid.as_ref() == Some(&group_uuid))
+        .map(|entry| (entry.login.clone(), entry.password.clone()))
+        .collect();
+    Ok(login_entries
+        .into_iter()
+        .filter(|entry| {
+            let is_revoked = revoked
+                .iter()
+                .any(|(login, password)| entry.login == *login && entry.password == *password);
+            if is_revoked {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): login is revoked in group {}",
+                    entry.login, entry.name, entry.uuid, revoked_group
+                ));
+            }
+            !is_revoked
+        })
+        .collect())
+}
+
+/// Names an entry's `KPH: git-allow` may list to be used here: the user, the machine or both as
+/// `user@machine`
+pub fn allow_identities() -> Vec<String> {
+    let hostname = hostname::get()
+        .map(|h| h.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "localhost".to_owned());
+    let mut identities = vec![hostname.clone()];
+    if let Some(user) = get_username() {
+        identities.push(format!("{}@{}", user, hostname));
+        identities.push(user);
+    }
+    identities
+}
+
+/// Keeps only logins of entries whose `KPH: git-allow` lists one of the identities, separated by
+/// commas or whitespace
+pub fn filter_allowed_logins<'a>(
+    login_entries: Vec<&'a LoginEntry>,
+    identities: &[String],
+) -> Vec<&'a LoginEntry> {
+    login_entries
+        .into_iter()
+        .filter(|entry| {
+            let allowed = entry.get_string_field("git-allow").is_some_and(|allow| {
+                allow
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .any(|name| identities.iter().any(|i| i.eq_ignore_ascii_case(name)))
+            });
+            if !allowed {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): KPH: git-allow doesn't list any of {}",
+                    entry.login,
+                    entry.name,
+                    entry.uuid,
+                    identities.join(", ")
+                ));
+            }
+            allowed
+        })
+        .collect()
+}
+
+/// Whether the repository path (`KPH: git-repo`) of an entry covers the requested path, e.g.
+/// `org` covers `org/repo.git`
+pub fn repo_prefix_matches(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_matches('/').trim_end_matches(".git");
+    let path = path.trim_matches('/').trim_end_matches(".git");
+    !prefix.is_empty()
+        && path.starts_with(prefix)
+        && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+}
+
+/// Picks the logins labeled with the longest `KPH: git-repo` prefix of the requested path, or the
+/// unlabeled ones if none match. Only works when Git sends the path (credential.useHttpPath).
+pub fn filter_repo_logins<'a>(
+    login_entries: Vec<&'a LoginEntry>,
+    url: &str,
+) -> Vec<&'a LoginEntry> {
+    let path = match Url::parse(url) {
+        Ok(url) if !url.path().trim_matches('/').is_empty() => url.path().to_owned(),
+        _ => return login_entries,
+    };
+    let mut best_prefix_len = None;
+    for entry in &login_entries {
+        if let Some(prefix) = entry.get_string_field("git-repo") {
+            if repo_prefix_matches(prefix, &path) {
+                best_prefix_len = best_prefix_len.max(Some(prefix.trim_matches('/').len()));
+            }
+        }
+    }
+    login_entries
+        .into_iter()
+        .filter(|entry| {
+            let prefix = entry.get_string_field("git-repo");
+            let keep = match (prefix, best_prefix_len) {
+                (Some(prefix), Some(len)) => {
+                    repo_prefix_matches(prefix, &path) && prefix.trim_matches('/').len() == len
+                }
+                (Some(_), None) => false,
+                (None, best_prefix_len) => best_prefix_len.is_none(),
+            };
+            if !keep {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): {} for {}",
+                    entry.login,
+                    entry.name,
+                    entry.uuid,
+                    match prefix {
+                        Some(prefix) => format!("KPH: git-repo {} is not the best match", prefix),
+                        None => "a login labeled with KPH: git-repo matches".to_owned(),
+                    },
+                    path
+                ));
+            }
+            keep
+        })
+        .collect()
+}
+
+/// Entry to use for `get`, e.g. set by direnv for the repository, taking precedence over pinned
+/// entries in the configuration file
+pub const ENTRY_OVERRIDE_ENV: &str = "GIT_CREDENTIAL_KEEPASSXC_ENTRY";
+
+/// Drops the logins which aren't meant to be used here, the same way for every lookup: the revoked
+/// ones, the ones hidden by the marker field, the ones in the recycle bin (unless
+/// `include_recycled`) and, if required, the ones not allowing this user or machine
+pub fn filter_logins(
+    config: &Config,
+    backend: &dyn SecretBackend,
+    login_entries: Vec<LoginEntry>,
+    include_recycled: bool,
+) -> Result<Vec<LoginEntry>> {
+    let login_entries = match config.get_revoked_group() {
+        Some(revoked_group) => filter_revoked_logins(backend, login_entries, revoked_group)?,
+        None => login_entries,
+    };
+    let marker = config.get_marker_field();
+    let (kph_false, login_entries) = filter_kph_logins(&login_entries, &marker);
+    if kph_false > 0 {
+        info!("{} login(s) were hidden by KPH: {}", kph_false, marker.name);
+    }
+    let login_entries = if include_recycled {
+        login_entries
+    } else {
+        filter_recycled_logins(login_entries, config.get_recycle_bin_group())
+    };
+    let login_entries = if config.is_require_allow_marker() {
+        filter_allowed_logins(login_entries, &allow_identities())
+    } else {
+        login_entries
+    };
+    Ok(login_entries.into_iter().cloned().collect())
+}
+
+/// Keeps only the login of the entry pinned for the URL by ENTRY_OVERRIDE_ENV or the configuration
+/// file, if any, and fails if it's not among the logins
+pub fn filter_pinned_logins(
+    config: &Config,
+    login_entries: Vec<LoginEntry>,
+    url: &str,
+) -> Result<Vec<LoginEntry>> {
+    let pinned_entry = match std::env::var(ENTRY_OVERRIDE_ENV) {
+        Ok(uuid) if !uuid.is_empty() => Some((uuid, "environment variable")),
+        _ => config
+            .get_pinned_entry(&get_url_host(url))
+            .map(|uuid| (uuid.to_owned(), "configuration file")),
+    };
+    let (uuid, source) = match pinned_entry {
+        Some(pinned_entry) => pinned_entry,
+        None => return Ok(login_entries),
+    };
+    info!("Login is pinned by {}", source);
+    detail!("Login is pinned to entry {} by {}", uuid, source);
+    // KeePassXC can't look up entries by UUID, so pick the pinned one from the matching logins
+    let pinned: Vec<_> = login_entries
+        .into_iter()
+        .filter(|entry| {
+            let is_pinned = entry.uuid.eq_ignore_ascii_case(&uuid);
+            if !is_pinned {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): login is pinned to entry {}",
+                    entry.login, entry.name, entry.uuid, uuid
+                ));
+            }
+            is_pinned
+        })
+        .collect();
+    if pinned.is_empty() {
+        return Err(anyhow!(
+            "Pinned entry {} is not among the matching logins",
+            uuid
+        ));
+    }
+    Ok(pinned)
+}
+
+/// Looks up the login for the request and returns the response to Git, along with the entry it's
+/// from
+pub fn find_login(
+    config: &Config,
+    options: &LookupOptions,
+    backend: &dyn SecretBackend,
+    git_req: GitCredentialMessage,
+    url: &str,
+    is_cert: bool,
+) -> Result<(GitCredentialMessage, LoginEntry)> {
+    let parent_domain_depth = options
+        .parent_domain_depth
+        .unwrap_or_else(|| config.get_parent_domain_depth());
+    // bounds the work on huge get-logins responses
+    let _limit = CandidateLimit::set(options.max_candidates, git_req.username.as_deref());
+
+    let login_entries = match backend.get_logins(url) {
+        Ok(login_entries) if !login_entries.is_empty() => login_entries,
+        result => {
+            let mut fallback = None;
+            let parent_urls = if parent_domain_depth > 0 {
+                // without the list, unrelated sites sharing e.g. co.uk could be tried
+                match config
+                    .get_public_suffix_list()
+                    .and_then(|path| read_public_suffix_list(path.as_deref()))
+                {
+                    Ok(suffixes) => parent_domain_urls(url, parent_domain_depth, &suffixes),
+                    Err(e) => {
+                        warn!("Not trying parent domains, {}", e);
+                        Vec::new()
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+            for parent_url in parent_urls {
+                info!("No matching logins found, trying parent domain");
+                detail!("No matching logins found, trying {}", parent_url);
+                explain(format!(
+                    "No logins left for {}, trying parent domain {}",
+                    url, parent_url
+                ));
+                match backend.get_logins(&parent_url) {
+                    Ok(login_entries) if !login_entries.is_empty() => {
+                        fallback = Some(login_entries);
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e)
+                        if e.downcast_ref::<KeePassError>()
+                            .map(KeePassError::is_no_logins_found)
+                            .unwrap_or(false) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            match fallback {
+                Some(login_entries) => login_entries,
+                // report the original error if parent domains don't match either
+                None => result?,
+            }
+        }
+    };
+    let login_entries = filter_logins(config, backend, login_entries, options.include_recycled)?;
+    let pinned_entries = filter_pinned_logins(config, login_entries, url)?;
+    let mut login_entries = if is_cert {
+        pinned_entries.iter().collect()
+    } else {
+        filter_repo_logins(pinned_entries.iter().collect(), url)
+    };
+    if login_entries.is_empty() {
+        return Err(HelperError::NoMatchingLogins.into());
+    }
+    if login_entries.len() > 1 && git_req.username.is_some() {
+        let username = git_req.username.as_ref().unwrap();
+        let login_entries_name_matches: Vec<_> = login_entries
+            .iter()
+            .filter(|entry| entry.login == *username)
+            .cloned()
+            .collect();
+        if !login_entries_name_matches.is_empty() {
+            if options.no_filter_username {
+                info!(
+                    "Username filtering skipped, {} login(s) would have been filtered out",
+                    login_entries.len() - login_entries_name_matches.len()
+                );
+                for entry in login_entries
+                    .iter()
+                    .filter(|entry| entry.login != *username)
+                {
+                    detail!(
+                        "Login {} ({}) would have been filtered out by username",
+                        entry.login,
+                        entry.uuid
+                    );
+                    explain(format!(
+                        "Kept login {} of entry {} ({}): username doesn't match {} but --no-filter-username is given",
+                        entry.login, entry.name, entry.uuid, username
+                    ));
+                }
+            } else {
+                for entry in login_entries
+                    .iter()
+                    .filter(|entry| entry.login != *username)
+                {
+                    explain(format!(
+                        "Dropped login {} of entry {} ({}): username doesn't match {}",
+                        entry.login, entry.name, entry.uuid, username
+                    ));
+                }
+                info!(
+                    "{} login(s) left after filtering by username",
+                    login_entries_name_matches.len()
+                );
+                login_entries = login_entries_name_matches;
+            }
+        } else {
+            explain(format!(
+                "Kept all {} login(s): none of them matches username {}",
+                login_entries.len(),
+                username
+            ));
+        }
+    }
+    if let Some(max_candidates) = options.max_candidates {
+        if login_entries.len() > max_candidates {
+            warn!(
+                "{} logins are left after filtering, only the first {} are considered, see max_candidates in configuration file",
+                login_entries.len(),
+                max_candidates
+            );
+            // logins of the username are never dropped, e.g. with --no-filter-username
+            let username = git_req.username.as_deref();
+            let mut rank = 0;
+            login_entries.retain(|entry| {
+                rank += 1;
+                rank <= max_candidates || username == Some(entry.login.as_str())
+            });
+        }
+    }
+    if login_entries.len() > 1 {
+        let key = recent_entry_key(url, git_req.username.as_deref());
+        if let Some(uuid) = read_recent_entry(&key) {
+            if let Some(index) = login_entries.iter().position(|entry| entry.uuid == uuid) {
+                let entry = login_entries.remove(index);
+                info!(
+                    "Preferring login {} ({}), which was used last for {}",
+                    entry.login, entry.uuid, key
+                );
+                login_entries.insert(0, entry);
+            }
+        }
+    }
+    if login_entries.len() > 1 {
+        warn!("More than 1 matching logins found, only the first one will be returned");
+        // entry URLs are not part of the response, but KeePassXC already sorts the logins by how
+        // well their URLs match (exact URL, then path, host and finally parent domain)
+        let ranking: Vec<_> = login_entries
+            .iter()
+            .enumerate()
+            .map(|(rank, entry)| format!("#{} {} of {}", rank + 1, entry.login, entry.name))
+            .collect();
+        info!(
+            "{} logins ranked by KeePassXC URL priority: {}",
+            login_entries.len(),
+            ranking.join(", ")
+        );
+        for (rank, entry) in login_entries.iter().enumerate() {
+            detail!(
+                "Rank {}: login {} of entry {} ({})",
+                rank + 1,
+                entry.login,
+                entry.name,
+                entry.uuid
+            );
+            if rank > 0 {
+                explain(format!(
+                    "Dropped login {} of entry {} ({}): ranked #{} by KeePassXC URL priority",
+                    entry.login,
+                    entry.name,
+                    entry.uuid,
+                    rank + 1
+                ));
+            }
+        }
+    }
+
+    let login = login_entries.first().unwrap();
+    explain(format!(
+        "Returned login {} of entry {} ({})",
+        login.login, login.name, login.uuid
+    ));
+    let mut git_resp = git_req;
+    // extra attributes of the request (e.g. capability[]) are not meant to be echoed back
+    git_resp.extra.clear();
+    if is_cert {
+        // Git only asks for the passphrase, the certificate itself is read from http.sslCert
+        git_resp.password = Some(
+            login
+                .get_string_field("cert_password")
+                .unwrap_or(&login.password)
+                .to_owned(),
+        );
+        return Ok((git_resp, (*login).clone()));
+    }
+    match get_token_login(url, login).filter(|_| options.prefer_token) {
+        Some((username, token)) => {
+            info!("Returning token field of the login as password");
+            git_resp.username = Some(username.to_owned());
+            git_resp.password = Some(token.to_owned());
+        }
+        None => {
+            git_resp.username = Some(login.login.clone());
+            git_resp.password = Some(login.password.clone());
+        }
+    }
+    // added to the entry by hand, see README
+    git_resp.oauth_refresh_token = login
+        .get_string_field("oauth_refresh_token")
+        .map(str::to_owned);
+    // KeePassXC only tells whether an entry has expired, not when
+    git_resp.password_expiry_utc = login
+        .get_string_field("password_expiry_utc")
+        .map(str::to_owned);
+    if options.advanced_fields {
+        git_resp.extra = advanced_field_attributes(login, config.get_advanced_field_names());
+    }
+    Ok((git_resp, (*login).clone()))
+}
+
+/// Advanced string fields of the login listed in `names` as extra Git credential attributes,
+/// skipping the ones that Git can't carry, that have a field of their own or that control Git
+fn advanced_field_attributes(login: &LoginEntry, names: &[String]) -> Vec<(String, String)> {
+    login
+        .get_string_fields()
+        .into_iter()
+        .filter(|(name, value)| {
+            if !names.iter().any(|allowed| allowed == name) {
+                return false;
+            }
+            if GIT_CONTROL_ATTRIBUTES.contains(name) {
+                warn!(
+                    "Advanced string field KPH: {} of entry {} would control Git, skipped",
+                    name, login.uuid
+                );
+                return false;
+            }
+            if name.is_empty() || name.contains(['=', '\n', '\0']) || value.contains(['\n', '\0']) {
+                warn!(
+                    "Advanced string field KPH: {} of entry {} can't be passed to Git, skipped",
+                    name.escape_debug(),
+                    login.uuid
+                );
+                return false;
+            }
+            !GitCredentialMessage::FIELDS.contains(name)
+        })
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect()
+}
+
+/// Updates the existing login of the username, or creates a new one, returning the UUID of the
+/// existing entry
+///
+/// Only the password of an existing login is updated, so that its other data (e.g. notes, string
+/// fields and additional URLs) is left alone.
+pub fn save_login(
+    backend: &dyn SecretBackend,
+    git_req: &GitCredentialMessage,
+    url: &str,
+    options: &StoreOptions,
+) -> Result<Option<String>> {
+    if git_req.username.is_none() {
+        return Err(anyhow!("Username is missing"));
+    }
+    if git_req.password.is_none() {
+        return Err(anyhow!("Password is missing"));
+    }
+
+    // unlabeled entries are updated even if get skips them (use_unlabeled), as otherwise storing
+    // would add a duplicate of them every time
+    let marker = &MarkerField {
+        use_unlabeled: true,
+        ..options.marker.clone()
+    };
+    let mut not_allowed = 0;
+    let login_entries = backend.get_logins(url).and_then(|entries| {
+        let (kph_false, entries) = filter_kph_logins(&entries, marker);
+        if kph_false > 0 {
+            info!("{} login(s) were hidden by KPH: {}", kph_false, marker.name);
+        }
+        let username = git_req.username.as_ref().unwrap();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| entry.login == *username)
+            .collect();
+        info!(
+            "{} login(s) left after filtering by username",
+            entries.len()
+        );
+        let entries: Vec<_> = if options.require_allow_marker {
+            let count = entries.len();
+            let entries = filter_allowed_logins(entries, &allow_identities());
+            not_allowed = count - entries.len();
+            entries.into_iter().cloned().collect()
+        } else {
+            entries.into_iter().cloned().collect()
+        };
+        if entries.is_empty() {
+            // this Err is never used
+            Err(anyhow!(
+                "No remaining logins after filtering out {} one(s) hidden by KPH: {}",
+                kph_false,
+                marker.name
+            ))
+        } else {
+            Ok(entries)
+        }
+    });
+
+    let username = git_req.username.as_ref().unwrap();
+    let password = git_req.password.as_ref().unwrap();
+    let password_expiry_utc = git_req
+        .password_expiry_utc
+        .as_ref()
+        .map(|expiry| {
+            u64::from_str(expiry).with_context(|| format!("Invalid password expiry {}", expiry))
+        })
+        .transpose()?;
+    if login_entries.is_err() && not_allowed > 0 {
+        // set-login can't add KPH: git-allow, so a new entry would be hidden from get as well and
+        // every store would add another one
+        return Err(anyhow!(
+            "{} existing login(s) of the username are not allowed here by KPH: git-allow, refusing to create another one",
+            not_allowed
+        ));
+    }
+    if let Ok(login_entries) = login_entries {
+        if let Some(login_entry) = login_entries
+            .iter()
+            .find(|entry| &entry.password == password)
+        {
+            // KeePassXC treats this as error, and Git sometimes does this as the operation should
+            // be idempotent
+            return Ok(Some(login_entry.uuid.clone()));
+        }
+        if options.create_only {
+            info!("Existing login found, not updating it in create-only mode");
+            return Ok(None);
+        }
+        let login_entry = if login_entries.len() == 1 {
+            warn!("Existing login found, gonna update the entry");
+            login_entries.first()
+        } else if let Some(login_entry) = read_recent_entry(&recent_entry_key(url, Some(username)))
+            .and_then(|uuid| login_entries.iter().find(|entry| entry.uuid == uuid))
+        {
+            warn!("More than 1 existing logins found, gonna update the one used last");
+            Some(login_entry)
+        } else {
+            resolve_store_conflict(&login_entries, options.conflict)?
+        };
+        match login_entry {
+            Some(login_entry) => {
+                backend.store_login(
+                    url,
+                    username,
+                    password,
+                    password_expiry_utc,
+                    Some(login_entry),
+                )?;
+                Ok(Some(login_entry.uuid.clone()))
+            }
+            None => {
+                backend.store_login(url, username, password, password_expiry_utc, None)?;
+                Ok(None)
+            }
+        }
+    } else {
+        // logins of the same username under other URLs (e.g. an old hostname) are not returned by
+        // get-logins, and set-login can't add URLs to existing entries at the time of writing, so
+        // there's no way to avoid the new entry
+        info!("No existing logins found, gonna create a new one");
+        backend.store_login(url, username, password, password_expiry_utc, None)?;
+        Ok(None)
+    }
+}
+
+/// Picks which of several existing logins of the username store updates, None to create a new one
+pub fn resolve_store_conflict(
+    login_entries: &[LoginEntry],
+    conflict: StoreConflict,
+) -> Result<Option<&LoginEntry>> {
+    let conflict = match conflict {
+        StoreConflict::Ask if ensure_prompt_allowed(false).is_ok() => {
+            return ask_store_conflict(login_entries)
+        }
+        StoreConflict::Ask => {
+            info!("Not running in a terminal, falling back to updating the first login");
+            StoreConflict::UpdateFirst
+        }
+        conflict => conflict,
+    };
+    match conflict {
+        StoreConflict::CreateNew => {
+            warn!("More than 1 existing logins found, gonna create a new entry");
+            Ok(None)
+        }
+        StoreConflict::Abort => Err(anyhow!(
+            "{} existing logins found for the username, refusing to choose one (see store_conflict in configuration file)",
+            login_entries.len()
+        )),
+        _ => {
+            warn!("More than 1 existing logins found, gonna update the first entry");
+            Ok(login_entries.first())
+        }
+    }
+}
+
+fn ask_store_conflict(login_entries: &[LoginEntry]) -> Result<Option<&LoginEntry>> {
+    eprintln!(
+        "{} existing logins found for the username:",
+        login_entries.len()
+    );
+    for (index, entry) in login_entries.iter().enumerate() {
+        eprintln!(
+            "  {}) Update entry {} ({}){}",
+            index + 1,
+            entry.name,
+            entry.uuid,
+            entry
+                .database
+                .as_ref()
+                .map(|database| format!(" in {}", database))
+                .unwrap_or_default()
+        );
+    }
+    eprintln!("  n) Create a new entry");
+    eprintln!("  a) Abort");
+    loop {
+        let answer = prompt_tty(&format!("Choose [1-{}/n/a]", login_entries.len()))?;
+        match answer.to_lowercase().as_str() {
+            "n" => return Ok(None),
+            "a" => return Err(anyhow!("Storing login is aborted")),
+            answer => match usize::from_str(answer) {
+                Ok(choice) if choice >= 1 && choice <= login_entries.len() => {
+                    return Ok(login_entries.get(choice - 1))
+                }
+                _ => eprintln!("Invalid choice {}", answer),
+            },
+        }
+    }
+}
+
+/// Host (and username) under which the last used entry is remembered, e.g. `alice@example.com`
+pub fn recent_entry_key(url: &str, username: Option<&str>) -> String {
+    let host = Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_owned))
+        .unwrap_or_else(|| url.to_owned());
+    match username {
+        Some(username) => format!("{}@{}", username, host),
+        None => host,
+    }
+}
+
+/// Deletes the entries of the logins Git asks to erase, i.e. the ones of the username (and the
+/// password, if given) that get would return. Without the password, several such logins are
+/// refused as Git may not mean all of them.
+pub fn delete_logins(
+    config: &Config,
+    backend: &dyn SecretBackend,
+    git_req: &GitCredentialMessage,
+    url: &str,
+) -> Result<()> {
+    let username = git_req
+        .username
+        .as_ref()
+        .ok_or_else(|| anyhow!("Username is required to erase a login"))?;
+    let login_entries = match backend.get_logins(url) {
+        Ok(login_entries) => login_entries,
+        Err(e) if is_no_match(&e) => {
+            info!("No matching login to erase");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let login_entries = filter_logins(config, backend, login_entries, false)?;
+    let login_entries = filter_pinned_logins(config, login_entries, url)?;
+    let matching: Vec<_> = login_entries
+        .iter()
+        .filter(|entry| entry.login == *username)
+        .filter(|entry| {
+            git_req
+                .password
+                .as_ref()
+                .is_none_or(|password| entry.password == *password)
+        })
+        .collect();
+    if git_req.password.is_none() && matching.len() > 1 {
+        return Err(anyhow!(
+            "{} logins found for the username, refusing to erase them without the password",
+            matching.len()
+        ));
+    }
+    if matching.is_empty() {
+        info!("No matching login to erase");
+        return Ok(());
+    }
+    for login in matching {
+        backend.delete_login(login)?;
+        info!("Login has been erased");
+        detail!(
+            "Login {} of entry {} ({}) has been deleted",
+            login.login,
+            login.name,
+            login.uuid
+        );
+    }
+    Ok(())
+}
+
+/// Quarantines the login rejected by the server in the revoked group for review. KeePassXC only
+/// updates the username and password of existing entries, so the login is copied and the original
+/// is then skipped by filter_revoked_logins.
+pub fn revoke_login(
+    backend: &dyn SecretBackend,
+    git_req: &GitCredentialMessage,
+    url: &str,
+    revoked_group: &str,
+) -> Result<()> {
+    let (username, password) = match (git_req.username.as_ref(), git_req.password.as_ref()) {
+        (Some(username), Some(password)) => (username, password),
+        _ => {
+            return Err(anyhow!(
+                "Username and password are required to revoke a login"
+            ))
+        }
+    };
+    let login_entries = backend.get_logins(url)?;
+    let group_uuid = backend.get_group_uuid(revoked_group)?;
+    let (revoked, active): (Vec<_>, Vec<_>) = login_entries
+        .iter()
+        .filter(|entry| entry.login == *username && entry.password == *password)
+        .partition(|entry| group_uuid.is_some() && entry.group_uuid == group_uuid);
+    if !revoked.is_empty() {
+        info!("Login has already been revoked");
+        return Ok(());
+    }
+    let login = active
+        .first()
+        .ok_or_else(|| anyhow!("No matching login to revoke"))?;
+    backend.revoke_login(url, login, revoked_group)?;
+    warn!(
+        "Login has been copied to group {} for review and is no longer used, KeePassXC doesn't allow moving entries via socket so please remove the original (or change its password) afterwards",
+        revoked_group
+    );
+    detail!(
+        "Login {} of entry {} ({}) has been revoked",
+        login.login,
+        login.name,
+        login.uuid
+    );
+    Ok(())
+}
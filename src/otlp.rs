@@ -0,0 +1,243 @@
+//! Spans of each operation, exported via OTLP/HTTP (JSON) when the `otlp` feature is enabled and
+//! `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` or `OTEL_EXPORTER_OTLP_ENDPOINT` is set
+#[cfg(not(feature = "otlp"))]
+use anyhow::Result;
+
+/// Runs the operation in a span, which is marked as failed if the operation returns an error
+#[cfg(not(feature = "otlp"))]
+pub fn in_span<T, F: FnOnce() -> Result<T>>(_name: &str, f: F) -> Result<T> {
+    f()
+}
+
+/// Sends the finished spans to the collector
+#[cfg(not(feature = "otlp"))]
+pub fn flush() {}
+
+#[cfg(feature = "otlp")]
+pub use self::exporter::{flush, in_span};
+
+#[cfg(feature = "otlp")]
+mod exporter {
+    use crate::errors::HelperError;
+    use crate::keepassxc::errors::KeePassError;
+    use crate::{debug, warn};
+    use anyhow::{anyhow, Context, Result};
+    use once_cell::sync::OnceCell;
+    use rand::RngCore;
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+    use std::io::{Read, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+    use std::sync::Mutex;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use url::Url;
+
+    const EXPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+    struct Exporter {
+        endpoint: Url,
+        trace_id: String,
+    }
+
+    static EXPORTER: OnceCell<Option<Exporter>> = OnceCell::new();
+    static FINISHED_SPANS: OnceCell<Mutex<Vec<Value>>> = OnceCell::new();
+    thread_local!(static SPAN_STACK: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+
+    fn random_hex(length: usize) -> String {
+        let mut bytes = vec![0u8; length];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn now_nanos() -> String {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    /// Reads the endpoint from the standard OpenTelemetry environment variables, spans are only
+    /// recorded if there's one
+    fn get_exporter() -> Option<&'static Exporter> {
+        EXPORTER
+            .get_or_init(|| {
+                let endpoint = match std::env::var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT") {
+                    Ok(endpoint) => endpoint,
+                    Err(_) => format!(
+                        "{}/v1/traces",
+                        std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                            .ok()?
+                            .trim_end_matches('/')
+                    ),
+                };
+                match Url::parse(&endpoint) {
+                    Ok(endpoint) if endpoint.scheme() == "http" => Some(Exporter {
+                        endpoint,
+                        trace_id: random_hex(16),
+                    }),
+                    _ => {
+                        warn!(
+                            "OTLP endpoint {} is not supported, only http:// URLs are",
+                            endpoint
+                        );
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    pub fn in_span<T, F: FnOnce() -> Result<T>>(name: &str, f: F) -> Result<T> {
+        let exporter = match get_exporter() {
+            Some(exporter) => exporter,
+            None => return f(),
+        };
+        let span_id = random_hex(8);
+        let parent_span_id = SPAN_STACK.with(|stack| {
+            let mut stack = stack.borrow_mut();
+            let parent_span_id = stack.last().cloned();
+            stack.push(span_id.clone());
+            parent_span_id
+        });
+        let start = now_nanos();
+        let result = f();
+        let end = now_nanos();
+        SPAN_STACK.with(|stack| stack.borrow_mut().pop());
+
+        let mut span = json!({
+            "traceId": exporter.trace_id,
+            "spanId": span_id,
+            "name": name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": start,
+            "endTimeUnixNano": end,
+        });
+        if let Some(parent_span_id) = parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        if let Err(ref e) = result {
+            // only the kind, as messages may have hosts, paths or usernames
+            let kind = error_kind(e);
+            // STATUS_CODE_ERROR
+            span["status"] = json!({"code": 2, "message": kind});
+            span["attributes"] = json!([{"key": "error.type", "value": {"stringValue": kind}}]);
+        }
+        if let Ok(mut spans) = FINISHED_SPANS.get_or_init(|| Mutex::new(Vec::new())).lock() {
+            spans.push(span);
+        }
+        result
+    }
+
+    pub fn flush() {
+        let exporter = match EXPORTER.get().and_then(Option::as_ref) {
+            Some(exporter) => exporter,
+            None => return,
+        };
+        let spans = match FINISHED_SPANS.get().and_then(|spans| spans.lock().ok()) {
+            Some(mut spans) if !spans.is_empty() => spans.drain(..).collect::<Vec<_>>(),
+            _ => return,
+        };
+        let service_name =
+            std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| clap::crate_name!().to_owned());
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [
+                        {"key": "service.name", "value": {"stringValue": service_name}},
+                    ],
+                },
+                "scopeSpans": [{
+                    "scope": {"name": clap::crate_name!(), "version": clap::crate_version!()},
+                    "spans": spans,
+                }],
+            }],
+        });
+        // tracing must never break credential requests
+        match send(&exporter.endpoint, &request.to_string()) {
+            Ok(_) => {
+                debug!("Exported {} span(s) via OTLP", spans.len());
+            }
+            Err(e) => {
+                warn!("Failed to export spans via OTLP: {}", e);
+            }
+        }
+    }
+
+    /// Category of the error, without details such as hosts, paths or usernames that the message
+    /// may have
+    fn error_kind(error: &anyhow::Error) -> String {
+        if let Some(e) = error.downcast_ref::<KeePassError>() {
+            return match e.get_error_code() {
+                Some(code) => format!("KeePassXC {:?}", code),
+                None => "KeePassXC".to_owned(),
+            };
+        }
+        if let Some(e) = error.downcast_ref::<HelperError>() {
+            return match e {
+                HelperError::HostNotAllowed(_) => "HostNotAllowed",
+                HelperError::AssociationCheckFailed(_) => "AssociationCheckFailed",
+                HelperError::NoMatchingLogins => "NoMatchingLogins",
+                HelperError::Interrupted => "Interrupted",
+            }
+            .to_owned();
+        }
+        if let Some(e) = error.downcast_ref::<std::io::Error>() {
+            return format!("IO {:?}", e.kind());
+        }
+        "Other".to_owned()
+    }
+
+    /// Posts the spans over plain HTTP, which is what local collectors listen on by default
+    fn send(endpoint: &Url, body: &str) -> Result<()> {
+        let host = endpoint
+            .host_str()
+            .ok_or_else(|| anyhow!("OTLP endpoint has no host"))?;
+        let address = (host, endpoint.port_or_known_default().unwrap_or(80))
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("Failed to resolve {}", host))?;
+        let mut stream = TcpStream::connect_timeout(&address, EXPORT_TIMEOUT)
+            .with_context(|| format!("Failed to connect to {}", address))?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+        // the port is part of the Host header unless it's the default one of the scheme
+        let host_header = match endpoint.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            endpoint.path(),
+            host_header,
+            body.len(),
+            body
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status = response.split_whitespace().nth(1).unwrap_or_default();
+        if status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(anyhow!("Collector responded with status {}", status))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_00_error_kind() {
+            let error = anyhow::Error::from(HelperError::HostNotAllowed("example.com".to_owned()));
+            assert_eq!(error_kind(&error), "HostNotAllowed");
+            let error = Err::<(), _>(std::io::Error::from(std::io::ErrorKind::NotFound))
+                .context("Failed to read /home/alice/.git-credentials")
+                .unwrap_err();
+            assert_eq!(error_kind(&error), "IO NotFound");
+            assert_eq!(error_kind(&anyhow!("Group Git/alice not found")), "Other");
+        }
+    }
+}
@@ -0,0 +1,529 @@
+//! Minimal freedesktop Secret Service (org.freedesktop.secrets) on the session bus, backed by
+//! KeePassXC, for tools which only speak the Secret Service API
+//!
+//! Only lookups are supported: items are found via `SearchItems` and read via `GetSecret(s)` in a
+//! plain session, whereas creating or deleting items and collections is refused. Searches skip
+//! the same logins as `get` does, e.g. the ones hidden by the marker field or revoked.
+//!
+//! Items are only visible to the client which found them, and forgotten once it closes its session
+//! or leaves the bus. Passwords aren't kept, they're looked up again whenever a secret is read.
+use crate::backend::SecretBackend;
+use crate::config::Config;
+use crate::errors::is_no_match;
+use crate::keepassxc::messages::LoginEntry;
+use crate::logins::{filter_logins, verify_host};
+use crate::utils::check_interrupted;
+use crate::{debug, detail, info, warn};
+use anyhow::{anyhow, Result};
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use dbus::blocking::Connection;
+use dbus::channel::default_reply;
+use dbus::strings::ErrorName;
+use dbus::{Message, MessageType, Path};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::time::Duration;
+
+const SERVICE_NAME: &str = "org.freedesktop.secrets";
+const SERVICE_PATH: &str = "/org/freedesktop/secrets";
+const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/keepassxc";
+const SESSION_PATH: &str = "/org/freedesktop/secrets/session";
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const SESSION_INTERFACE: &str = "org.freedesktop.Secret.Session";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to check for interruption while waiting for method calls
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// (session, parameters, value, content type)
+type Secret = (Path<'static>, Vec<u8>, Vec<u8>, String);
+type Properties = HashMap<String, Variant<Box<dyn RefArg>>>;
+
+/// Error replied to the client, as D-Bus error name and message
+struct DBusError(&'static str, String);
+
+impl DBusError {
+    fn failed<T: Into<String>>(message: T) -> Self {
+        Self("org.freedesktop.DBus.Error.Failed", message.into())
+    }
+}
+
+impl From<anyhow::Error> for DBusError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::failed(error.to_string())
+    }
+}
+
+impl From<dbus::arg::TypeMismatchError> for DBusError {
+    fn from(error: dbus::arg::TypeMismatchError) -> Self {
+        Self("org.freedesktop.DBus.Error.InvalidArgs", error.to_string())
+    }
+}
+
+/// Login found by a search, along with the attributes it was searched by
+struct Item {
+    url: String,
+    uuid: String,
+    login: String,
+    name: String,
+    attributes: HashMap<String, String>,
+}
+
+struct Server<'a, F: Fn(u32) -> Result<()>> {
+    connection: &'a Connection,
+    config: &'a Config,
+    backend: &'a dyn SecretBackend,
+    verify_client: F,
+    /// Whether each client, by its unique bus name (which is never reused), has passed caller
+    /// verification
+    clients: HashMap<String, bool>,
+    /// Owner of each session
+    sessions: HashMap<String, String>,
+    next_session: u64,
+    /// Items found by each client, by their paths
+    items: HashMap<String, HashMap<String, Item>>,
+}
+
+/// Builds the URL to look up from attributes of libsecret's network password schema (`server`,
+/// `protocol`, `port` and `object`) or a plain `url`, `host` or `service`
+fn attributes_url(attributes: &HashMap<String, String>) -> Option<String> {
+    if let Some(url) = attributes.get("url") {
+        return Some(url.clone());
+    }
+    let host = ["server", "host", "service"]
+        .iter()
+        .find_map(|key| attributes.get(*key))
+        .filter(|host| !host.is_empty())?;
+    let protocol = attributes
+        .get("protocol")
+        .map(String::as_str)
+        .unwrap_or("https");
+    let mut url = format!("{}://{}", protocol, host);
+    if let Some(port) = attributes
+        .get("port")
+        .filter(|p| !p.is_empty() && *p != "0")
+    {
+        url.push(':');
+        url.push_str(port);
+    }
+    if let Some(path) = attributes.get("object").or_else(|| attributes.get("path")) {
+        url.push('/');
+        url.push_str(path.trim_start_matches('/'));
+    }
+    Some(url)
+}
+
+fn item_path(login: &LoginEntry) -> String {
+    let uuid: String = login
+        .uuid
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{}/{}", COLLECTION_PATH, uuid)
+}
+
+fn to_paths<T: AsRef<str>>(paths: &[T]) -> Vec<Path<'static>> {
+    paths
+        .iter()
+        .map(|p| Path::from(p.as_ref().to_owned()))
+        .collect()
+}
+
+fn error_reply(msg: &Message, name: &str, message: &str) -> Message {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    msg.error(&ErrorName::from(name), &message)
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value))
+}
+
+impl<'a, F: Fn(u32) -> Result<()>> Server<'a, F> {
+    fn is_client_allowed(&mut self, sender: &str) -> bool {
+        if let Some(allowed) = self.clients.get(sender) {
+            return *allowed;
+        }
+        let proxy = self.connection.with_proxy(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            DBUS_TIMEOUT,
+        );
+        let pid: Result<(u32,), _> = proxy.method_call(
+            "org.freedesktop.DBus",
+            "GetConnectionUnixProcessID",
+            (sender,),
+        );
+        let allowed = match pid {
+            Ok((pid,)) => match (self.verify_client)(pid) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("D-Bus client {} is refused, {}", sender, e);
+                    false
+                }
+            },
+            Err(e) => {
+                warn!("Failed to retrieve PID of D-Bus client {}, {}", sender, e);
+                false
+            }
+        };
+        self.clients.insert(sender.to_owned(), allowed);
+        allowed
+    }
+
+    fn handle(&mut self, msg: &Message) -> Option<Message> {
+        let sender = msg.sender()?.to_string();
+        if !self.is_client_allowed(&sender) {
+            return Some(error_reply(
+                msg,
+                "org.freedesktop.DBus.Error.AccessDenied",
+                "You are not allowed to use this program",
+            ));
+        }
+        let path = msg.path()?.to_string();
+        let interface = msg.interface().map(|i| i.to_string()).unwrap_or_default();
+        let member = msg.member()?.to_string();
+        debug!("D-Bus call {}.{} on {}", interface, member, path);
+        match self.dispatch(msg, &sender, &path, &interface, &member) {
+            Ok(Some(reply)) => Some(reply),
+            Ok(None) => default_reply(msg),
+            Err(DBusError(name, message)) => Some(error_reply(msg, name, &message)),
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        msg: &Message,
+        sender: &str,
+        path: &str,
+        interface: &str,
+        member: &str,
+    ) -> Result<Option<Message>, DBusError> {
+        let reply = msg.method_return();
+        let reply = match (path, interface, member) {
+            (SERVICE_PATH, SERVICE_INTERFACE, "OpenSession") => {
+                let (algorithm, _): (&str, Variant<Box<dyn RefArg>>) = msg.read2()?;
+                // libsecret falls back to plain when encryption is refused this way
+                if algorithm != "plain" {
+                    return Err(DBusError(
+                        "org.freedesktop.DBus.Error.NotSupported",
+                        format!("Algorithm {} is not supported", algorithm),
+                    ));
+                }
+                self.next_session += 1;
+                let session = format!("{}/{}", SESSION_PATH, self.next_session);
+                self.sessions.insert(session.clone(), sender.to_owned());
+                reply.append2(variant(String::new()), Path::from(session))
+            }
+            (SERVICE_PATH, SERVICE_INTERFACE, "SearchItems") => {
+                let attributes: HashMap<String, String> = msg.read1()?;
+                let items = self.search(sender, attributes)?;
+                reply.append2(to_paths(&items), Vec::<Path>::new())
+            }
+            (SERVICE_PATH, SERVICE_INTERFACE, "GetSecrets") => {
+                let (items, session): (Vec<Path>, Path) = msg.read2()?;
+                let session = self.check_session(sender, &session)?;
+                let mut secrets: HashMap<Path, Secret> = HashMap::new();
+                for item in items {
+                    if let Some(secret) = self.get_secret(sender, &item, &session)? {
+                        secrets.insert(item.into_static(), secret);
+                    }
+                }
+                reply.append1(secrets)
+            }
+            // KeePassXC has to be unlocked anyway for lookups to work
+            (SERVICE_PATH, SERVICE_INTERFACE, "Unlock") => {
+                let objects: Vec<Path> = msg.read1()?;
+                reply.append2(objects, Path::from("/"))
+            }
+            (SERVICE_PATH, SERVICE_INTERFACE, "ReadAlias") => {
+                let name: &str = msg.read1()?;
+                let collection = if name == "default" {
+                    COLLECTION_PATH
+                } else {
+                    "/"
+                };
+                reply.append1(Path::from(collection))
+            }
+            (COLLECTION_PATH, COLLECTION_INTERFACE, "SearchItems") => {
+                let attributes: HashMap<String, String> = msg.read1()?;
+                let items = self.search(sender, attributes)?;
+                reply.append1(to_paths(&items))
+            }
+            (_, ITEM_INTERFACE, "GetSecret") if self.get_item(sender, path).is_some() => {
+                let session: Path = msg.read1()?;
+                let session = self.check_session(sender, &session)?;
+                let secret = self
+                    .get_secret(sender, &Path::from(path), &session)?
+                    .ok_or_else(|| DBusError::failed("No such item"))?;
+                reply.append1(secret)
+            }
+            (_, SESSION_INTERFACE, "Close") if self.sessions.contains_key(path) => {
+                self.check_session(sender, &Path::from(path))?;
+                self.sessions.remove(path);
+                // items are shared by the sessions of the client
+                if !self.sessions.values().any(|owner| owner == sender) {
+                    self.items.remove(sender);
+                }
+                reply
+            }
+            (_, PROPERTIES_INTERFACE, "Get") => {
+                let (interface, name): (&str, &str) = msg.read2()?;
+                let value = self
+                    .properties(sender, path, interface)
+                    .and_then(|mut properties| properties.remove(name))
+                    .ok_or_else(|| {
+                        DBusError(
+                            "org.freedesktop.DBus.Error.UnknownProperty",
+                            format!("No property {} in {}", name, interface),
+                        )
+                    })?;
+                reply.append1(value)
+            }
+            (_, PROPERTIES_INTERFACE, "GetAll") => {
+                let interface: &str = msg.read1()?;
+                reply.append1(self.properties(sender, path, interface).unwrap_or_default())
+            }
+            (_, SERVICE_INTERFACE, _)
+            | (_, COLLECTION_INTERFACE, _)
+            | (_, ITEM_INTERFACE, _)
+            | (_, PROPERTIES_INTERFACE, "Set") => {
+                return Err(DBusError(
+                    "org.freedesktop.DBus.Error.NotSupported",
+                    format!("{} is not supported, only lookups are", member),
+                ))
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(reply))
+    }
+
+    fn check_session(&self, sender: &str, session: &Path) -> Result<Path<'static>, DBusError> {
+        match self.sessions.get(&session.to_string()) {
+            Some(owner) if owner == sender => Ok(session.clone().into_static()),
+            _ => Err(DBusError(
+                "org.freedesktop.Secret.Error.NoSession",
+                format!("No such session {}", session),
+            )),
+        }
+    }
+
+    fn get_item(&self, sender: &str, path: &str) -> Option<&Item> {
+        self.items.get(sender)?.get(path)
+    }
+
+    /// Forgets everything about a client which has left the bus
+    fn remove_client(&mut self, sender: &str) {
+        self.clients.remove(sender);
+        self.items.remove(sender);
+        self.sessions.retain(|_, owner| owner != sender);
+    }
+
+    /// Looks up the login of the item again, so that no password is kept in between
+    fn get_secret(
+        &self,
+        sender: &str,
+        item: &Path,
+        session: &Path<'static>,
+    ) -> Result<Option<Secret>> {
+        let item = match self.get_item(sender, &item.to_string()) {
+            Some(item) => item,
+            None => return Ok(None),
+        };
+        let logins = filter_logins(
+            self.config,
+            self.backend,
+            self.backend.get_logins(&item.url)?,
+            false,
+        )?;
+        let login = logins
+            .into_iter()
+            .find(|login| login.uuid == item.uuid && login.login == item.login)
+            .ok_or_else(|| anyhow!("Login of entry {} is no longer found", item.uuid))?;
+        detail!(
+            "Returning secret of login {} of entry {} ({}) via D-Bus",
+            login.login,
+            login.name,
+            login.uuid
+        );
+        Ok(Some((
+            session.clone(),
+            Vec::new(),
+            login.password.as_bytes().to_vec(),
+            "text/plain; charset=utf8".to_owned(),
+        )))
+    }
+
+    /// Looks up logins by the attributes and returns the paths of the items
+    fn search(&mut self, sender: &str, attributes: HashMap<String, String>) -> Result<Vec<String>> {
+        let url = match attributes_url(&attributes) {
+            Some(url) => url,
+            None => {
+                debug!("Search attributes don't contain a URL or host, nothing is found");
+                return Ok(Vec::new());
+            }
+        };
+        info!("Searching logins via D-Bus");
+        detail!("Searching logins of {} via D-Bus", url);
+        verify_host(self.config, &url)?;
+        let logins = match self.backend.get_logins(&url) {
+            Ok(logins) => filter_logins(self.config, self.backend, logins, false)?,
+            Err(e) if is_no_match(&e) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let username = attributes
+            .get("user")
+            .or_else(|| attributes.get("username"));
+        let items = self.items.entry(sender.to_owned()).or_default();
+        let mut paths = Vec::new();
+        for login in logins {
+            if username.is_some_and(|username| *username != login.login) {
+                continue;
+            }
+            let path = item_path(&login);
+            let mut item_attributes = attributes.clone();
+            item_attributes.insert("user".to_owned(), login.login.clone());
+            items.insert(
+                path.clone(),
+                Item {
+                    url: url.clone(),
+                    uuid: login.uuid,
+                    login: login.login,
+                    name: login.name,
+                    attributes: item_attributes,
+                },
+            );
+            paths.push(path);
+        }
+        info!("Found {} item(s) via D-Bus", paths.len());
+        Ok(paths)
+    }
+
+    fn properties(&self, sender: &str, path: &str, interface: &str) -> Option<Properties> {
+        let mut properties = Properties::new();
+        match (path, interface) {
+            (SERVICE_PATH, SERVICE_INTERFACE) => {
+                properties.insert(
+                    "Collections".to_owned(),
+                    variant(to_paths(&[COLLECTION_PATH])),
+                );
+            }
+            (COLLECTION_PATH, COLLECTION_INTERFACE) => {
+                let items: Vec<_> = self
+                    .items
+                    .get(sender)
+                    .map(|items| items.keys().collect())
+                    .unwrap_or_default();
+                properties.insert("Items".to_owned(), variant(to_paths(&items)));
+                properties.insert("Label".to_owned(), variant("KeePassXC".to_owned()));
+                properties.insert("Locked".to_owned(), variant(false));
+                properties.insert("Created".to_owned(), variant(0u64));
+                properties.insert("Modified".to_owned(), variant(0u64));
+            }
+            (_, ITEM_INTERFACE) => {
+                let item = self.get_item(sender, path)?;
+                properties.insert("Label".to_owned(), variant(item.name.clone()));
+                properties.insert("Attributes".to_owned(), variant(item.attributes.clone()));
+                properties.insert("Locked".to_owned(), variant(false));
+                properties.insert("Created".to_owned(), variant(0u64));
+                properties.insert("Modified".to_owned(), variant(0u64));
+            }
+            _ => return None,
+        }
+        Some(properties)
+    }
+}
+
+/// Serves the Secret Service until interrupted. `verify_client` is given the PID of each D-Bus
+/// client and fails if it's not allowed to use this program.
+pub fn serve<F: Fn(u32) -> Result<()>>(
+    config: &Config,
+    backend: &dyn SecretBackend,
+    verify_client: F,
+) -> Result<()> {
+    let connection = Connection::new_session()?;
+    match connection.request_name(SERVICE_NAME, false, false, true)? {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {}
+        _ => {
+            return Err(anyhow!(
+                "{} is already provided by another program, e.g. GNOME Keyring or KeePassXC itself",
+                SERVICE_NAME
+            ))
+        }
+    }
+    // to forget clients once they leave the bus
+    connection.add_match_no_cb(
+        "type='signal',sender='org.freedesktop.DBus',interface='org.freedesktop.DBus',member='NameOwnerChanged'",
+    )?;
+    info!("Serving {} on the session bus", SERVICE_NAME);
+    let mut server = Server {
+        connection: &connection,
+        config,
+        backend,
+        verify_client,
+        clients: HashMap::new(),
+        sessions: HashMap::new(),
+        next_session: 0,
+        items: HashMap::new(),
+    };
+    loop {
+        check_interrupted()?;
+        let msg = match connection.channel().blocking_pop_message(POLL_INTERVAL)? {
+            Some(msg) => msg,
+            None => continue,
+        };
+        if msg.msg_type() == MessageType::Signal
+            && msg.member().as_deref() == Some("NameOwnerChanged")
+        {
+            if let (Some(name), _, Some("")) = msg.get3::<&str, &str, &str>() {
+                server.remove_client(name);
+            }
+            continue;
+        }
+        if msg.msg_type() != MessageType::MethodCall {
+            continue;
+        }
+        if let Some(reply) = server.handle(&msg) {
+            let _ = connection.channel().send(reply);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_00_attributes_url() {
+        let attributes = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            attributes_url(&attributes(&[
+                ("protocol", "https"),
+                ("server", "example.com"),
+                ("port", "8443"),
+                ("object", "group/repo.git"),
+                ("user", "foo"),
+            ])),
+            Some("https://example.com:8443/group/repo.git".to_owned())
+        );
+        assert_eq!(
+            attributes_url(&attributes(&[
+                ("service", "example.com"),
+                ("username", "foo")
+            ])),
+            Some("https://example.com".to_owned())
+        );
+        assert_eq!(
+            attributes_url(&attributes(&[("url", "ftp://example.com")])),
+            Some("ftp://example.com".to_owned())
+        );
+        assert_eq!(attributes_url(&attributes(&[("user", "foo")])), None);
+    }
+}
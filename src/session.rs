@@ -0,0 +1,307 @@
+//! Establishing sessions with KeePassXC and checking database associations, which everything
+//! talking to KeePassXC starts with
+use crate::cli::UnlockOptions;
+use crate::config::{Config, Database, GroupRule};
+use crate::keepassxc::{errors::KeePassError, messages::*, Group};
+use crate::utils::{
+    check_interrupted, compare_versions, generate_secret_key, get_client_box,
+    get_keepassxc_version, get_min_keepassxc_version, show_unlock_dialog, wait_for_unlock_signal,
+};
+use crate::{detail, info, warn};
+use anyhow::{anyhow, Result};
+use crypto_box::{PublicKey, SecretKey};
+use std::time::Duration;
+
+pub use crate::utils::{nacl_nonce, set_socket_path, to_decrypted_json, to_encrypted_json};
+
+/// Oldest KeePassXC version handling get-totp, older ones reject it as an unknown action
+pub const GET_TOTP_MIN_VERSION: &str = "2.6.0";
+
+/// Oldest KeePassXC version handling delete-entry
+pub const DELETE_ENTRY_MIN_VERSION: &str = "2.7.0";
+
+/// Compares dot-separated versions numerically, see compare_versions
+fn is_older_version(version: &str, min_version: &str) -> bool {
+    compare_versions(version, min_version).is_lt()
+}
+
+/// Fails if the running KeePassXC is known to be older than `min_version`, which `feature` needs,
+/// instead of sending a request it can't handle
+pub fn require_keepassxc_version(feature: &str, min_version: &str) -> Result<()> {
+    match get_keepassxc_version() {
+        Some(version) if is_older_version(&version, min_version) => Err(anyhow!(
+            "{} requires KeePassXC {} or newer, but {} is running",
+            feature,
+            min_version,
+            version
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Refuses KeePassXC of `version` if it's older than `min_version` from command line or
+/// configuration file
+fn check_keepassxc_version(version: Option<&str>, min_version: Option<&str>) -> Result<()> {
+    let min_version = match min_version {
+        Some(min_version) => min_version,
+        None => return Ok(()),
+    };
+    match version {
+        Some(version) if is_older_version(version, min_version) => Err(anyhow!(
+            "KeePassXC {} is older than the minimum version {}, see min_keepassxc_version in configuration file",
+            version,
+            min_version
+        )),
+        Some(version) => {
+            info!("KeePassXC {} is running", version);
+            Ok(())
+        }
+        None => {
+            warn!(
+                "KeePassXC didn't tell its version, cannot check it against the minimum version {}",
+                min_version
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Starts a session with KeePassXC by exchanging a new pair of keys, returning the client ID
+/// along with our secret key and KeePassXC's public key
+pub fn start_session() -> Result<(String, SecretKey, PublicKey)> {
+    // generate keys for encrypting current session
+    let session_seckey = generate_secret_key();
+    let session_pubkey = session_seckey.public_key();
+
+    // temporary client id
+    let (_, client_id) = nacl_nonce();
+
+    // exchange public keys
+    let host_pubkey = exchange_keys(&client_id, &session_pubkey)?;
+    check_keepassxc_version(
+        get_keepassxc_version().as_deref(),
+        get_min_keepassxc_version(),
+    )?;
+
+    // initialise crypto_box
+    let _ = get_client_box(Some(&host_pubkey), Some(&session_seckey));
+
+    Ok((client_id, session_seckey, host_pubkey))
+}
+
+/// Returns the database profiles in configuration file which KeePassXC still recognises, waiting
+/// for locked databases to be unlocked according to the unlock options
+pub fn associated_databases<T: AsRef<str>>(
+    config: &Config,
+    client_id: T,
+    unlock_options: &Option<UnlockOptions>,
+) -> Result<Vec<Database>> {
+    let databases: Vec<_> = config
+        .get_databases()?
+        .iter()
+        .filter(|ref db| {
+            // per-database unlock options take precedence over the command line ones
+            let unlock_options = db.unlock.as_ref().or(unlock_options.as_ref());
+            let mut remain_retries = unlock_options.map_or_else(|| 0, |v| v.max_retries);
+            let mut success = false;
+            loop {
+                let taso_req = TestAssociateRequest::new(db.id.as_str(), db.pkey.as_str());
+                // trigger unlock if unlock options are given
+                let taso_resp = taso_req.send(client_id.as_ref(), unlock_options.is_some());
+                let database_locked = match &taso_resp {
+                    Ok(_) => false,
+                    Err(e) => {
+                        if let Some(keepass_error) = e.downcast_ref::<KeePassError>() {
+                            keepass_error.is_database_locked()
+                        } else {
+                            false
+                        }
+                    }
+                };
+                if let Ok(ref taso_resp) = taso_resp {
+                    success = taso_resp
+                        .success
+                        .clone()
+                        .unwrap_or_else(|| KeePassBoolean(false))
+                        .into();
+                }
+                if let Err(ref e) = taso_resp {
+                    if e.downcast_ref::<KeePassError>()
+                        .map(KeePassError::is_association_revoked)
+                        .unwrap_or(false)
+                    {
+                        warn!("Association has been removed from KeePassXC, run configure --renew to associate again");
+                    }
+                }
+                if taso_resp.is_err() || !success {
+                    warn!("Failed to authenticate against a database using stored key");
+                    detail!(
+                        "Failed to authenticate against database {} using stored key",
+                        db.id
+                    );
+                }
+                if database_locked
+                    && unlock_options.is_none()
+                    && config.is_unlock_dialog()
+                    && !atty::is(atty::Stream::Stderr)
+                {
+                    info!("Database is locked, asking to unlock it via dialog");
+                    let message = format!(
+                        "KeePassXC database {} is locked and Git is waiting for credentials. Unlock it in KeePassXC, then choose Retry.",
+                        db.label.as_deref().unwrap_or(&db.id)
+                    );
+                    match show_unlock_dialog(&message) {
+                        Ok(true) => continue,
+                        Ok(false) => {
+                            info!("Unlock dialog is cancelled");
+                        }
+                        Err(e) => {
+                            warn!("Failed to show unlock dialog, {}", e);
+                        }
+                    }
+                }
+                if success || !database_locked || unlock_options.is_none() {
+                    break;
+                }
+                // loop get-databasehash until unlocked
+                while remain_retries > 0 || unlock_options.unwrap().max_retries == 0 {
+                    warn!(
+                        "Database {} is locked, gonna retry once it's unlocked or in {}ms (Remaining: {})",
+                        db.id,
+                        unlock_options.unwrap().interval,
+                        remain_retries
+                    );
+                    // KeePassXC signals unlocking, polling is only the fallback in case the
+                    // signal is missed
+                    let interval = Duration::from_millis(unlock_options.unwrap().interval);
+                    match wait_for_unlock_signal(interval) {
+                        Ok(true) => {
+                            info!("KeePassXC signalled that a database is unlocked");
+                        }
+                        Ok(false) => {}
+                        Err(e) => {
+                            if check_interrupted().is_err() {
+                                return false;
+                            }
+                            warn!("Failed to wait for unlock signal, {}", e);
+                        }
+                    }
+
+                    let gh_req = GetDatabaseHashRequest::new();
+                    if gh_req.send(client_id.as_ref(), false).is_ok() {
+                        info!("Database {} is unlocked", db.id);
+                        break;
+                    }
+                    if unlock_options.unwrap().max_retries != 0 {
+                        remain_retries -= 1;
+                    }
+                }
+                // still not unlocked, break
+                if remain_retries == 0 && unlock_options.unwrap().max_retries != 0 {
+                    break;
+                }
+            }
+            success
+        })
+        .cloned()
+        .collect();
+    check_interrupted()?;
+    if databases.is_empty() {
+        Err(anyhow!(
+            "No valid database associations found in configuration file"
+        ))
+    } else {
+        info!(
+            "Successfully authenticated against {} database(s)",
+            databases.len()
+        );
+        Ok(databases)
+    }
+}
+
+/// Returns the root group of the active database along with all its descendants
+pub fn get_root_group<T: AsRef<str>>(client_id: T) -> Result<Group> {
+    let gdg_req = GetDatabaseGroupsRequest::new();
+    let gdg_resp = gdg_req.send(client_id.as_ref(), false)?;
+    gdg_resp
+        .get_groups()
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow!("KeePassXC didn't return any groups"))
+}
+
+/// Finds the group by its path, or creates it if it doesn't exist and `create` is set
+pub fn find_group<T: AsRef<str>>(client_id: T, group_name: &str, create: bool) -> Result<Group> {
+    let root_group = get_root_group(client_id.as_ref())?;
+    if let Some(group) = root_group.find_by_path(group_name) {
+        info!("Group {} already exists ({})", group_name, group.uuid);
+        return Ok(Group::new(group.name.clone(), group.uuid.clone()));
+    }
+    if !create {
+        return Err(anyhow!(
+            "Group {} doesn't exist, create it in KeePassXC or pass --create-group",
+            group_name
+        ));
+    }
+    info!("Group {} doesn't exist, gonna create it", group_name);
+    let cng_req = CreateNewGroupRequest::new(group_name);
+    let cng_resp = cng_req.send(client_id.as_ref(), false)?;
+    Ok(Group::new(cng_resp.name, cng_resp.uuid))
+}
+
+/// Makes sure the group of a database profile still exists, otherwise falls back to a group of the
+/// same name (which is only created if `create` is set)
+pub fn validate_group<T: AsRef<str>>(
+    client_id: T,
+    database: &Database,
+    create: bool,
+) -> Result<Group> {
+    let root_group = get_root_group(client_id.as_ref())?;
+    if let Some(group) = root_group.find_by_uuid(&database.group_uuid) {
+        return Ok(Group::new(group.name.clone(), group.uuid.clone()));
+    }
+    warn!(
+        "Group {} ({}) no longer exists in database {}",
+        database.group, database.group_uuid, database.id
+    );
+    find_group(client_id, &database.group, create)
+}
+
+/// Same as validate_group but for the group a host is mapped to
+pub fn validate_group_rule<T: AsRef<str>>(
+    client_id: T,
+    rule: &GroupRule,
+    create: bool,
+) -> Result<Group> {
+    let root_group = get_root_group(client_id.as_ref())?;
+    if let Some(group) = root_group.find_by_uuid(&rule.group_uuid) {
+        return Ok(Group::new(group.name.clone(), group.uuid.clone()));
+    }
+    warn!(
+        "Group {} ({}) of host {} no longer exists",
+        rule.group, rule.group_uuid, rule.host
+    );
+    find_group(client_id, &rule.group, create)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{parse_version, set_keepassxc_version};
+
+    #[test]
+    fn test_00_require_keepassxc_version() {
+        set_keepassxc_version(None);
+        assert!(require_keepassxc_version("TOTP", GET_TOTP_MIN_VERSION).is_ok());
+        set_keepassxc_version(Some("2.5.4".to_owned()));
+        assert!(require_keepassxc_version("TOTP", GET_TOTP_MIN_VERSION).is_err());
+        set_keepassxc_version(Some("2.7.6".to_owned()));
+        assert!(require_keepassxc_version("TOTP", GET_TOTP_MIN_VERSION).is_ok());
+        assert!(require_keepassxc_version("Erasing logins", DELETE_ENTRY_MIN_VERSION).is_ok());
+        set_keepassxc_version(Some("2.7".to_owned()));
+        assert!(require_keepassxc_version("Erasing logins", DELETE_ENTRY_MIN_VERSION).is_ok());
+        assert!(parse_version("2.7.0-beta1").is_ok());
+        assert!(parse_version("latest").is_err());
+        assert!(parse_version("v2.7").is_err());
+    }
+}
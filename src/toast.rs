@@ -0,0 +1,128 @@
+//! Toast notifications on Windows via WinRT when the `notification` feature is enabled, which
+//! unlike notify-rust can carry buttons and report the one clicked back to the waiting process
+#[cfg(not(all(windows, feature = "notification")))]
+use anyhow::{anyhow, Result};
+
+/// Shows a toast with Approve and Deny buttons and waits for the answer, dismissing the toast or
+/// letting it time out counts as denied
+#[cfg(not(all(windows, feature = "notification")))]
+pub fn request_approval(_summary: &str, _body: &str, _timeout: u32) -> Result<bool> {
+    Err(anyhow!(
+        "Approving requests via notifications is only supported on Windows with notification feature"
+    ))
+}
+
+#[cfg(all(windows, feature = "notification"))]
+pub use self::winrt::{request_approval, show};
+
+#[cfg(all(windows, feature = "notification"))]
+mod winrt {
+    use anyhow::{anyhow, Context, Result};
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use windows::core::{IInspectable, Interface, HSTRING};
+    use windows::Data::Xml::Dom::XmlDocument;
+    use windows::Foundation::TypedEventHandler;
+    use windows::UI::Notifications::{
+        ToastActivatedEventArgs, ToastDismissedEventArgs, ToastFailedEventArgs, ToastNotification,
+        ToastNotificationManager, ToastNotifier,
+    };
+
+    /// Toasts must come from a registered application, borrow PowerShell's like other unpackaged
+    /// programs do
+    const APP_USER_MODEL_ID: &str =
+        "{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\\WindowsPowerShell\\v1.0\\powershell.exe";
+    const APPROVE_ARGUMENT: &str = "approve";
+    const DENY_ARGUMENT: &str = "deny";
+
+    fn escape_xml(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    }
+
+    fn create_toast(summary: &str, body: &str, with_actions: bool) -> Result<ToastNotification> {
+        // the reminder scenario keeps the toast on screen until it's answered
+        let (scenario, actions) = if with_actions {
+            (
+                r#" scenario="reminder""#.to_owned(),
+                format!(
+                    r#"<actions><action content="Approve" arguments="{}"/><action content="Deny" arguments="{}"/></actions>"#,
+                    APPROVE_ARGUMENT, DENY_ARGUMENT
+                ),
+            )
+        } else {
+            (String::new(), String::new())
+        };
+        let xml = format!(
+            r#"<toast{}><visual><binding template="ToastGeneric"><text>{}</text><text>{}</text></binding></visual>{}</toast>"#,
+            scenario,
+            escape_xml(summary),
+            escape_xml(body),
+            actions
+        );
+        let document = XmlDocument::new()?;
+        document.LoadXml(&HSTRING::from(xml))?;
+        Ok(ToastNotification::CreateToastNotification(&document)?)
+    }
+
+    fn create_notifier() -> Result<ToastNotifier> {
+        ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(APP_USER_MODEL_ID))
+            .context("Failed to create toast notifier")
+    }
+
+    pub fn show(summary: &str, body: &str) -> Result<()> {
+        let toast = create_toast(summary, body, false)?;
+        create_notifier()?.Show(&toast)?;
+        Ok(())
+    }
+
+    pub fn request_approval(summary: &str, body: &str, timeout: u32) -> Result<bool> {
+        let toast = create_toast(summary, body, true)?;
+        // None if the toast couldn't be shown
+        let (tx, rx) = mpsc::channel::<Option<bool>>();
+
+        let activated_tx = tx.clone();
+        toast.Activated(&TypedEventHandler::new(
+            move |_: &Option<ToastNotification>, args: &Option<IInspectable>| {
+                // clicking the toast itself instead of a button doesn't approve the request
+                let approved = match args {
+                    Some(args) => {
+                        args.cast::<ToastActivatedEventArgs>()?
+                            .Arguments()?
+                            .to_string()
+                            == APPROVE_ARGUMENT
+                    }
+                    None => false,
+                };
+                let _ = activated_tx.send(Some(approved));
+                Ok(())
+            },
+        ))?;
+        let dismissed_tx = tx.clone();
+        toast.Dismissed(&TypedEventHandler::new(
+            move |_: &Option<ToastNotification>, _: &Option<ToastDismissedEventArgs>| {
+                let _ = dismissed_tx.send(Some(false));
+                Ok(())
+            },
+        ))?;
+        toast.Failed(&TypedEventHandler::new(
+            move |_: &Option<ToastNotification>, _: &Option<ToastFailedEventArgs>| {
+                let _ = tx.send(None);
+                Ok(())
+            },
+        ))?;
+
+        let notifier = create_notifier()?;
+        notifier.Show(&toast)?;
+        let answer = rx.recv_timeout(Duration::from_millis(timeout as u64));
+        let _ = notifier.Hide(&toast);
+        match answer {
+            Ok(Some(approved)) => Ok(approved),
+            Ok(None) => Err(anyhow!("Failed to show toast notification")),
+            Err(_) => Ok(false),
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Updating the executable from the project's GitHub releases, which publish one binary per
+//! platform along with `SHA256SUMS` and a minisign signature of each binary
+use crate::utils::compare_versions;
+use crate::{debug, info};
+use anyhow::{anyhow, Context, Result};
+use minisign_verify::{PublicKey, Signature};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Public key of release signatures, baked in by release builds
+pub const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("GIT_CREDENTIAL_KEEPASSXC_UPDATE_KEY");
+
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+
+#[derive(Deserialize, Debug)]
+pub struct Release {
+    pub tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    /// Version of the release, i.e. the tag without the leading v
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches('v')
+    }
+
+    fn download_asset(&self, name: &str) -> Result<Vec<u8>> {
+        let asset = self
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| anyhow!("Release {} has no asset {}", self.tag_name, name))?;
+        download(&asset.browser_download_url)
+    }
+}
+
+/// Name of the release binary for the running platform, e.g. git-credential-keepassxc-linux-x86_64
+pub fn asset_name() -> String {
+    format!(
+        "{}-{}-{}{}",
+        clap::crate_name!(),
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+/// Fetches with curl, which is available on all supported platforms and saves us a TLS stack
+fn download(url: &str) -> Result<Vec<u8>> {
+    debug!("Downloading {}", url);
+    let output = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", url])
+        .output()
+        .context("Failed to run curl")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to download {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+pub fn get_latest_release() -> Result<Release> {
+    let repository = env!("CARGO_PKG_REPOSITORY")
+        .trim_start_matches("https://github.com/")
+        .trim_end_matches('/');
+    let body = download(&format!(
+        "https://api.github.com/repos/{}/releases/latest",
+        repository
+    ))?;
+    serde_json::from_slice(&body).context("Failed to parse release information")
+}
+
+/// Compares dot-separated versions numerically, pre-release suffixes are ignored
+pub fn is_newer(version: &str, current: &str) -> bool {
+    compare_versions(version, current).is_gt()
+}
+
+/// Looks up the checksum of a file in the output format of sha256sum
+fn find_checksum<'a>(checksums: &'a str, name: &str) -> Option<&'a str> {
+    checksums.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let checksum = parts.next()?;
+        let file = parts.next()?.trim_start_matches('*');
+        if file == name {
+            Some(checksum)
+        } else {
+            None
+        }
+    })
+}
+
+pub fn verify_checksum(binary: &[u8], checksums: &str, name: &str) -> Result<()> {
+    let expected = find_checksum(checksums, name)
+        .ok_or_else(|| anyhow!("{} has no checksum of {}", CHECKSUMS_ASSET, name))?;
+    let actual: String = Sha256::digest(binary)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    if !expected.eq_ignore_ascii_case(&actual) {
+        return Err(anyhow!(
+            "Checksum mismatch of {}, expected {}, got {}",
+            name,
+            expected,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Makes sure the trusted comment of a signature, which is signed as well, names the binary and
+/// version that are expected, e.g. `git-credential-keepassxc-linux-x86_64 0.14.0`, so that an older
+/// signed binary can't be passed off as the release
+pub fn verify_trusted_comment(trusted_comment: &str, name: &str, version: &str) -> Result<()> {
+    let mut parts = trusted_comment.split_whitespace();
+    let (signed_name, signed_version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(signed_name), Some(signed_version), None) => {
+            (signed_name, signed_version.trim_start_matches('v'))
+        }
+        _ => {
+            return Err(anyhow!(
+                "Signature doesn't tell the binary and its version, trusted comment is {}",
+                trusted_comment
+            ))
+        }
+    };
+    if signed_name != name || signed_version != version {
+        return Err(anyhow!(
+            "Signature is of {} {}, expected {} {}",
+            signed_name,
+            signed_version,
+            name,
+            version
+        ));
+    }
+    Ok(())
+}
+
+pub fn verify_signature(
+    binary: &[u8],
+    signature: &[u8],
+    public_key: &str,
+    name: &str,
+    version: &str,
+) -> Result<()> {
+    let public_key = PublicKey::from_base64(public_key.trim())
+        .map_err(|e| anyhow!("Invalid public key: {}", e))?;
+    let signature = Signature::decode(&String::from_utf8_lossy(signature))
+        .map_err(|e| anyhow!("Invalid signature: {}", e))?;
+    public_key
+        .verify(binary, &signature, false)
+        .map_err(|e| anyhow!("Failed to verify signature: {}", e))?;
+    verify_trusted_comment(signature.trusted_comment(), name, version)
+}
+
+/// Downloads and verifies the binary for this platform
+pub fn download_verified(release: &Release, public_key: &str) -> Result<Vec<u8>> {
+    let name = asset_name();
+    let binary = release.download_asset(&name)?;
+    let checksums = release.download_asset(CHECKSUMS_ASSET)?;
+    verify_checksum(&binary, &String::from_utf8_lossy(&checksums), &name)?;
+    info!("Checksum of {} verified", name);
+    let signature = release.download_asset(&format!("{}.minisig", name))?;
+    verify_signature(&binary, &signature, public_key, &name, release.version())?;
+    info!("Signature of {} {} verified", name, release.version());
+    Ok(binary)
+}
+
+/// Replaces the executable with the new binary, moving the running one aside first as Windows
+/// doesn't allow overwriting it
+pub fn replace_executable<T: AsRef<Path>>(executable: T, binary: &[u8]) -> Result<()> {
+    let executable = executable.as_ref();
+    let new_path = executable.with_extension("new");
+    let old_path = executable.with_extension("old");
+    fs::write(&new_path, binary)
+        .with_context(|| format!("Failed to write {}", new_path.to_string_lossy()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = fs::metadata(executable)?.permissions().mode();
+        fs::set_permissions(&new_path, fs::Permissions::from_mode(permissions))?;
+    }
+    fs::rename(executable, &old_path)
+        .with_context(|| format!("Failed to move {} aside", executable.to_string_lossy()))?;
+    if let Err(e) = fs::rename(&new_path, executable) {
+        fs::rename(&old_path, executable)?;
+        return Err(e).context("Failed to replace executable");
+    }
+    // a running executable can't be deleted on Windows, it's then left for the next update
+    let _ = fs::remove_file(&old_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_00_is_newer() {
+        assert!(is_newer("0.5.0", "0.4.0"));
+        assert!(is_newer("0.10.0", "0.9.1"));
+        assert!(is_newer("1.0.0", "0.4.0-beta"));
+        assert!(!is_newer("0.4.0", "0.4.0"));
+        assert!(!is_newer("0.3.9", "0.4.0"));
+        assert!(!is_newer("2.7.0", "2.7"));
+    }
+
+    #[test]
+    fn test_01_verify_checksum() {
+        let checksums = "\
+            b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c  foo\n\
+            7d865e959b2466918c9863afca942d0fb89d7c9ac0c99bafc3749504ded97730 *bar\n";
+        assert!(verify_checksum(b"foo\n", checksums, "foo").is_ok());
+        assert!(verify_checksum(b"bar\n", checksums, "bar").is_ok());
+        assert!(verify_checksum(b"bar\n", checksums, "foo").is_err());
+        assert!(verify_checksum(b"foo\n", checksums, "baz").is_err());
+    }
+
+    #[test]
+    fn test_02_verify_trusted_comment() {
+        let name = "git-credential-keepassxc-linux-x86_64";
+        assert!(verify_trusted_comment(&format!("{} 0.14.0", name), name, "0.14.0").is_ok());
+        assert!(verify_trusted_comment(&format!("{} v0.14.0", name), name, "0.14.0").is_ok());
+        assert!(verify_trusted_comment(&format!("{} 0.13.0", name), name, "0.14.0").is_err());
+        assert!(verify_trusted_comment("foo 0.14.0", name, "0.14.0").is_err());
+        assert!(verify_trusted_comment("timestamp:1700000000\tfile:foo", name, "0.14.0").is_err());
+    }
+}
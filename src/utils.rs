@@ -1,3 +1,4 @@
+use crate::errors::HelperError;
 use anyhow::{anyhow, Context, Error, Result};
 use crypto_box::{
     self,
@@ -8,143 +9,1992 @@ use crypto_box::{
 use mockall::mock;
 #[cfg(windows)]
 use named_pipe::PipeClient;
-use once_cell::unsync::OnceCell;
+use once_cell::sync::OnceCell;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
 use std::io::{Read, Write};
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::str;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 static KEEPASS_SOCKET_NAME: &str = "org.keepassxc.KeePassXC.BrowserServer";
 static KEEPASS_SOCKET_NAME_LEGACY: &str = "kpxc_server";
+static KEEPASS_FLATPAK_APP_ID: &str = "org.keepassxc.KeePassXC";
+pub static BROWSER_INTEGRATION_HINT: &str = "Make sure KeePassXC is running and browser integration is enabled (Tools -> Settings -> Browser Integration -> Enable browser integration)";
 
 #[macro_export]
 macro_rules! error {
     ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Error, "", $($args)+);
+        #[cfg(not(test))]
+        {
+            if let Some(logger) = $crate::LOGGER.get() {
+                slog::log!(logger, slog::Level::Error, "", $($args)+);
+            }
+        }
         #[cfg(test)] eprintln!("{}: {}", slog::Level::Error, format!($($args)+));
     };
 }
-#[macro_export]
-macro_rules! warn {
-    ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Warning, "", $($args)+);
-        #[cfg(test)] eprintln!("{}: {}", slog::Level::Warning, format!($($args)+));
-    };
+#[macro_export]
+macro_rules! warn {
+    ($($args:tt)+) => {
+        #[cfg(not(test))]
+        {
+            if let Some(logger) = $crate::LOGGER.get() {
+                slog::log!(logger, slog::Level::Warning, "", $($args)+);
+            }
+        }
+        #[cfg(test)] eprintln!("{}: {}", slog::Level::Warning, format!($($args)+));
+    };
+}
+#[macro_export]
+macro_rules! info {
+    ($($args:tt)+) => {
+        #[cfg(not(test))]
+        {
+            if let Some(logger) = $crate::LOGGER.get() {
+                slog::log!(logger, slog::Level::Info, "", $($args)+);
+            }
+        }
+        #[cfg(test)] eprintln!("{}: {}", slog::Level::Info, format!($($args)+));
+    };
+}
+#[macro_export]
+macro_rules! debug {
+    ($($args:tt)+) => {
+        #[cfg(not(test))]
+        {
+            if let Some(logger) = $crate::LOGGER.get() {
+                slog::log!(logger, slog::Level::Debug, "", $($args)+);
+            }
+        }
+        #[cfg(test)] eprintln!("{}: {}", slog::Level::Debug, format!($($args)+));
+    };
+}
+
+/// Logs details that may be sensitive (paths, IDs, etc.) to the log file only, so that they don't
+/// end up in whatever the caller shows to the user
+#[macro_export]
+macro_rules! detail {
+    ($($args:tt)+) => {
+        #[cfg(not(test))]
+        {
+            if let Some(logger) = $crate::DETAIL_LOGGER.get() {
+                slog::log!(logger, slog::Level::Info, "", $($args)+);
+            }
+        }
+        #[cfg(test)] eprintln!("DETAIL: {}", format!($($args)+));
+    };
+}
+
+thread_local!(static SOCKET_PATH: RefCell<Option<PathBuf>> = RefCell::new(None));
+thread_local!(static KEEPASSXC_VERSION: RefCell<Option<String>> = const { RefCell::new(None) });
+static MIN_KEEPASSXC_VERSION: OnceCell<String> = OnceCell::new();
+thread_local!(static CANDIDATE_LIMIT: RefCell<Option<CandidateLimit>> = const { RefCell::new(None) });
+
+/// Returns all the paths that KeePassXC may listen on, in the order of preference
+pub fn get_candidate_socket_paths() -> Result<Vec<PathBuf>> {
+    let base_dirs = directories_next::BaseDirs::new()
+        .ok_or_else(|| anyhow!("Failed to initialise base_dirs"))?;
+    let get_socket_path_with_name = |name: &str| -> Result<PathBuf> {
+        let socket_dir = if cfg!(windows) && name == KEEPASS_SOCKET_NAME_LEGACY {
+            let cache_dir = base_dirs.cache_dir();
+            PathBuf::from(format!(
+                "\\\\.\\pipe\\\\{}\\Temp\\{}",
+                cache_dir.to_string_lossy(),
+                name
+            ))
+        } else if cfg!(windows) {
+            PathBuf::from(format!("\\\\.\\pipe\\{}", name))
+        } else if cfg!(target_os = "macos") {
+            std::env::temp_dir().join(name)
+        } else {
+            base_dirs
+                .runtime_dir()
+                .ok_or_else(|| anyhow!("Failed to locate runtime_dir automatically"))?
+                .join(name)
+        };
+        Ok(socket_dir)
+    };
+    let mut paths = vec![
+        get_socket_path_with_name(KEEPASS_SOCKET_NAME_LEGACY)?,
+        get_socket_path_with_name(KEEPASS_SOCKET_NAME)?,
+    ];
+    if cfg!(all(unix, not(target_os = "macos"))) {
+        // KeePassXC installed via Flatpak
+        paths.push(get_socket_path_with_name(&format!(
+            "app/{}/{}",
+            KEEPASS_FLATPAK_APP_ID, KEEPASS_SOCKET_NAME
+        ))?);
+    }
+    Ok(paths)
+}
+
+/// Fails fast when nobody can answer an interactive prompt, i.e. Git has disabled prompts via
+/// GIT_TERMINAL_PROMPT or there's no terminal (`reads_stdin` is false for prompts that read from
+/// the terminal directly, e.g. passwords)
+pub fn ensure_prompt_allowed(reads_stdin: bool) -> Result<()> {
+    if let Ok(terminal_prompt) = std::env::var("GIT_TERMINAL_PROMPT") {
+        if ["0", "false", "no", "off"]
+            .iter()
+            .any(|v| terminal_prompt.eq_ignore_ascii_case(v))
+        {
+            return Err(anyhow!(
+                "Interactive prompt is required but disabled by GIT_TERMINAL_PROMPT"
+            ));
+        }
+    }
+    if !atty::is(atty::Stream::Stderr) || (reads_stdin && !atty::is(atty::Stream::Stdin)) {
+        return Err(anyhow!(
+            "Interactive prompt is required but not running in a terminal"
+        ));
+    }
+    Ok(())
+}
+
+/// Asks for a line of input on the terminal directly, for commands of which Git owns stdin and
+/// stdout, e.g. store
+pub fn prompt_tty(question: &str) -> Result<String> {
+    use std::io::BufRead;
+
+    ensure_prompt_allowed(false)?;
+    #[cfg(unix)]
+    let tty_path = "/dev/tty";
+    #[cfg(windows)]
+    let tty_path = "CONIN$";
+    let tty = std::fs::File::open(tty_path).context("Failed to open terminal")?;
+    eprint!("{}: ", question);
+    let mut answer = String::new();
+    if std::io::BufReader::new(tty).read_line(&mut answer)? == 0 {
+        return Err(anyhow!("Unexpected end of input"));
+    }
+    Ok(answer.trim().to_owned())
+}
+
+/// Expands leading `~` and `$VAR`/`${VAR}` environment variables in the given path
+pub fn expand_path<T: AsRef<str>>(path: T) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        let base_dirs = directories_next::BaseDirs::new()
+            .ok_or_else(|| anyhow!("Failed to initialise base_dirs"))?;
+        expanded.push_str(&base_dirs.home_dir().to_string_lossy());
+        rest = &rest[1..];
+    }
+    while let Some(idx) = rest.find('$') {
+        expanded.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let (name, remaining) = if rest.starts_with('{') {
+            let end = rest
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable in path {}", path))?;
+            (&rest[1..end], &rest[end + 1..])
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        if name.is_empty() {
+            expanded.push('$');
+        } else {
+            let value = std::env::var(name).map_err(|_| {
+                anyhow!("Environment variable {} in path {} is not set", name, path)
+            })?;
+            expanded.push_str(&value);
+        }
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Talks to KeePassXC via the socket at the path instead of the default one from now on
+pub fn set_socket_path(path: PathBuf) {
+    SOCKET_PATH.with(|s| {
+        *s.borrow_mut() = Some(path);
+    });
+    reset_stream();
+}
+
+/// Remembers the version KeePassXC told when exchanging keys
+pub fn set_keepassxc_version(version: Option<String>) {
+    KEEPASSXC_VERSION.with(|v| *v.borrow_mut() = version);
+}
+
+pub fn get_keepassxc_version() -> Option<String> {
+    KEEPASSXC_VERSION.with(|v| v.borrow().clone())
+}
+
+/// Sets the oldest KeePassXC version to talk to, which has to be a plain version like 2.7.0
+pub fn set_min_keepassxc_version(version: String) -> Result<()> {
+    parse_version(&version)
+        .with_context(|| format!("Invalid minimum KeePassXC version {}", version))?;
+    let _ = MIN_KEEPASSXC_VERSION.set(version);
+    Ok(())
+}
+
+pub fn get_min_keepassxc_version() -> Option<&'static str> {
+    MIN_KEEPASSXC_VERSION.get().map(String::as_str)
+}
+
+/// Parses a dot-separated version, ignoring pre-release and build suffixes, e.g. 2.7.0-beta
+pub fn parse_version(version: &str) -> Result<Vec<u64>> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| {
+            part.parse()
+                .map_err(|_| anyhow!("{} is not a dot-separated version", version))
+        })
+        .collect()
+}
+
+/// Compares dot-separated versions numerically, where missing parts count as 0 (2.7 == 2.7.0)
+/// and so do unparsable ones
+pub fn compare_versions(version: &str, other: &str) -> std::cmp::Ordering {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    let (mut version, mut other) = (parse(version), parse(other));
+    let len = version.len().max(other.len());
+    version.resize(len, 0);
+    other.resize(len, 0);
+    version.cmp(&other)
+}
+
+/// How many logins of a get-logins response are deserialized, besides those of the username which
+/// are always kept
+#[derive(Clone, Debug)]
+pub struct CandidateLimit {
+    pub max: usize,
+    pub username: Option<String>,
+}
+
+impl CandidateLimit {
+    /// Limits get-logins responses on this thread until the returned guard is dropped
+    pub fn set(max: Option<usize>, username: Option<&str>) -> CandidateLimitGuard {
+        let limit = max.map(|max| CandidateLimit {
+            max,
+            username: username.map(str::to_owned),
+        });
+        CandidateLimitGuard(CANDIDATE_LIMIT.with(|l| l.replace(limit)))
+    }
+
+    pub fn get() -> Option<CandidateLimit> {
+        CANDIDATE_LIMIT.with(|l| l.borrow().clone())
+    }
+
+    /// Whether the login is kept even though `max` logins are found already
+    pub fn keeps(&self, login: &str) -> bool {
+        self.username.as_deref() == Some(login)
+    }
+}
+
+/// Restores the previous candidate limit of the thread when dropped
+pub struct CandidateLimitGuard(Option<CandidateLimit>);
+
+impl Drop for CandidateLimitGuard {
+    fn drop(&mut self) {
+        let limit = self.0.take();
+        CANDIDATE_LIMIT.with(|l| *l.borrow_mut() = limit);
+    }
+}
+
+pub fn get_socket_path() -> Result<PathBuf> {
+    let socket_path = SOCKET_PATH.with(|s| -> Result<_> {
+        let mut socket_path = s.borrow_mut();
+        if socket_path.is_none() {
+            let candidates = get_candidate_socket_paths()?;
+            // prefer the first existing one, or fall back to the standard path
+            let path = candidates
+                .iter()
+                .find(|p| p.exists())
+                .unwrap_or(&candidates[1])
+                .clone();
+            *socket_path = Some(path);
+        }
+        Ok(socket_path.as_ref().unwrap().clone())
+    });
+    if let Ok(ref socket_path) = socket_path {
+        debug!("Socket path: {}", socket_path.to_string_lossy());
+    }
+    socket_path
+}
+
+static TRACE_PROTOCOL: AtomicBool = AtomicBool::new(false);
+static ACTIVATE_KEEPASSXC: AtomicBool = AtomicBool::new(false);
+static EXPLAIN: AtomicBool = AtomicBool::new(false);
+static ERRORS_JSON: AtomicBool = AtomicBool::new(false);
+/// Fields of which the values are masked in protocol traces
+static SECRET_FIELDS: &[&str] = &[
+    "password",
+    "key",
+    "idKey",
+    "publicKey",
+    "nonce",
+    "message",
+    "totp",
+];
+
+pub fn set_trace_protocol(enabled: bool) {
+    TRACE_PROTOCOL.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_trace_protocol() -> bool {
+    TRACE_PROTOCOL.load(Ordering::Relaxed)
+}
+
+pub fn set_activate_keepassxc(enabled: bool) {
+    ACTIVATE_KEEPASSXC.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_errors_json(enabled: bool) {
+    ERRORS_JSON.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_errors_json() -> bool {
+    ERRORS_JSON.load(Ordering::Relaxed)
+}
+
+pub fn set_explain(enabled: bool) {
+    EXPLAIN.store(enabled, Ordering::Relaxed);
+}
+
+/// Prints why a login was kept or dropped to stderr if --explain is given, regardless of the
+/// verbosity since the user explicitly asked for it
+pub fn explain<T: AsRef<str>>(message: T) {
+    if EXPLAIN.load(Ordering::Relaxed) {
+        eprintln!("[explain] {}", message.as_ref());
+    }
+}
+
+/// Set by SIGINT or SIGTERM
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Number of live InterruptGuard, while any of which the signal handler leaves it to the guarded
+/// code to stop instead of exiting right away
+static INTERRUPT_GUARDS: AtomicUsize = AtomicUsize::new(0);
+/// Terminal settings to restore when exiting on a signal, e.g. with echo turned off by a PIN prompt
+#[cfg(unix)]
+static TERMINAL_STATE: OnceCell<(libc::c_int, libc::termios)> = OnceCell::new();
+
+#[cfg(unix)]
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+    if INTERRUPT_GUARDS.load(Ordering::SeqCst) > 0 {
+        return;
+    }
+    // only async-signal-safe functions from here on
+    unsafe {
+        if let Some((fd, termios)) = TERMINAL_STATE.get() {
+            libc::tcsetattr(*fd, libc::TCSANOW, termios);
+        }
+        libc::_exit(HelperError::Interrupted.exit_code());
+    }
+}
+
+/// Exits with the dedicated code on SIGINT and SIGTERM, or lets the code under an InterruptGuard
+/// stop with HelperError::Interrupted
+#[cfg(unix)]
+pub fn install_signal_handlers() {
+    for signal in &[libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_interrupt as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*signal, &action, std::ptr::null_mut()) != 0 {
+                warn!(
+                    "Failed to install handler of signal {}, {}",
+                    signal,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() {}
+
+/// Saves the settings of the controlling terminal, so that they are restored if the process is
+/// interrupted while e.g. a password prompt has turned off echo
+#[cfg(all(unix, feature = "piv"))]
+pub fn save_terminal_state() {
+    use std::os::unix::io::IntoRawFd;
+
+    if TERMINAL_STATE.get().is_some() {
+        return;
+    }
+    let tty = match std::fs::OpenOptions::new().read(true).open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(e) => {
+            debug!("Failed to open terminal, {}", e);
+            return;
+        }
+    };
+    // kept open till exit so that the handler can use it
+    let fd = tty.into_raw_fd();
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } == 0 {
+        let _ = TERMINAL_STATE.set((fd, termios));
+    } else {
+        unsafe { libc::close(fd) };
+    }
+}
+
+#[cfg(all(not(unix), feature = "piv"))]
+pub fn save_terminal_state() {}
+
+/// Defers exiting on SIGINT or SIGTERM while alive, for code that can stop cleanly by checking
+/// check_interrupted(), e.g. waits and writes
+pub struct InterruptGuard;
+
+impl InterruptGuard {
+    // not Default, as creating a guard isn't free of side effects
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        INTERRUPT_GUARDS.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        INTERRUPT_GUARDS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn check_interrupted() -> Result<()> {
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        Err(HelperError::Interrupted.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Sleeps in short steps, stopping with HelperError::Interrupted on SIGINT or SIGTERM
+pub fn sleep_interruptible(duration: std::time::Duration) -> Result<()> {
+    let _guard = InterruptGuard::new();
+    let deadline = std::time::Instant::now() + duration;
+    loop {
+        check_interrupted()?;
+        let now = std::time::Instant::now();
+        if now >= deadline {
+            return Ok(());
+        }
+        std::thread::sleep((deadline - now).min(std::time::Duration::from_millis(100)));
+    }
+}
+
+/// Well-known D-Bus names of KeePassXC, the former of which is registered by KeePassXC itself
+/// whereas the latter is only known to the bus when KeePassXC is installed from Flatpak
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+static KEEPASSXC_DBUS_NAMES: [&str; 2] = [
+    "org.keepassxc.KeePassXC.MainWindow",
+    "org.keepassxc.KeePassXC",
+];
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+const DBUS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+const ACTIVATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
+
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+fn is_keepassxc_running(connection: &dbus::blocking::Connection) -> Result<bool> {
+    let proxy = connection.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        DBUS_TIMEOUT,
+    );
+    for name in KEEPASSXC_DBUS_NAMES.iter() {
+        let (has_owner,): (bool,) =
+            proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (*name,))?;
+        if has_owner {
+            debug!("D-Bus name {} has an owner", name);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+fn activate_keepassxc(connection: &dbus::blocking::Connection) -> Result<()> {
+    let proxy = connection.with_proxy(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        ACTIVATION_TIMEOUT,
+    );
+    let (activatable_names,): (Vec<String>,) =
+        proxy.method_call("org.freedesktop.DBus", "ListActivatableNames", ())?;
+    let name = KEEPASSXC_DBUS_NAMES
+        .iter()
+        .find(|name| activatable_names.iter().any(|n| n == *name))
+        .ok_or_else(|| anyhow!("KeePassXC cannot be activated through D-Bus"))?;
+    info!("Activating KeePassXC through D-Bus name {}", name);
+    let (_,): (u32,) =
+        proxy.method_call("org.freedesktop.DBus", "StartServiceByName", (*name, 0u32))?;
+    Ok(())
+}
+
+/// Asks D-Bus whether KeePassXC is running to explain why the socket is unavailable, and
+/// activates KeePassXC then waits for the socket if allowed to
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+fn recover_connection(path: &std::path::Path, error: std::io::Error) -> Result<Stream> {
+    use std::io::ErrorKind;
+    let description = format!("Unix socket {}", path.to_string_lossy());
+    if !matches!(
+        error.kind(),
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused
+    ) {
+        return Err(explain_connection_error(error, description));
+    }
+    let connection = match dbus::blocking::Connection::new_session() {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("Failed to connect to D-Bus session bus, {}", e);
+            return Err(explain_connection_error(error, description));
+        }
+    };
+    match is_keepassxc_running(&connection) {
+        Ok(true) => Err(Error::from(error).context(format!(
+            "KeePassXC is running but {} is unavailable. Make sure browser integration is enabled (Tools -> Settings -> Browser Integration -> Enable browser integration)",
+            description
+        ))),
+        Ok(false) if ACTIVATE_KEEPASSXC.load(Ordering::Relaxed) => {
+            activate_keepassxc(&connection)
+                .with_context(|| format!("KeePassXC is not running and {} is unavailable", description))?;
+            let start = std::time::Instant::now();
+            loop {
+                match UnixStream::connect(path) {
+                    Ok(stream) => {
+                        info!("Connected to KeePassXC after activation");
+                        return Ok(stream);
+                    }
+                    Err(e) if start.elapsed() >= ACTIVATION_TIMEOUT => {
+                        return Err(explain_connection_error(e, description)
+                            .context("KeePassXC was activated but its socket did not show up in time"));
+                    }
+                    Err(_) => sleep_interruptible(std::time::Duration::from_millis(500))?,
+                }
+            }
+        }
+        Ok(false) => Err(Error::from(error).context(format!(
+            "KeePassXC is not running and {} is unavailable. Start KeePassXC or use --activate to start it on demand",
+            description
+        ))),
+        Err(e) => {
+            warn!("Failed to query KeePassXC status from D-Bus, {}", e);
+            Err(explain_connection_error(error, description))
+        }
+    }
+}
+
+/// Linux key management constants, see keyctl(2)
+#[cfg(all(target_os = "linux", feature = "encryption"))]
+mod keyctl {
+    pub const KEY_SPEC_USER_KEYRING: libc::c_long = -4;
+    pub const KEYCTL_SETPERM: libc::c_long = 5;
+    pub const KEYCTL_SEARCH: libc::c_long = 10;
+    pub const KEYCTL_READ: libc::c_long = 11;
+    pub const KEYCTL_SET_TIMEOUT: libc::c_long = 15;
+    /// View, read, write, search, link and setattr for possessors only
+    pub const KEY_POS_ALL: libc::c_long = 0x3f00_0000;
+}
+
+/// Keeps a secret in the user keyring of the kernel for `timeout` seconds, so that following
+/// invocations can reuse it
+#[cfg(all(target_os = "linux", feature = "encryption"))]
+pub fn cache_secret(description: &str, secret: &[u8], timeout: u64) -> Result<()> {
+    let key_type = std::ffi::CString::new("user")?;
+    let description = std::ffi::CString::new(description)?;
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            secret.as_ptr(),
+            secret.len(),
+            keyctl::KEY_SPEC_USER_KEYRING,
+        )
+    };
+    if serial < 0 {
+        return Err(Error::from(std::io::Error::last_os_error()).context("Failed to add key"));
+    }
+    for (operation, argument) in [
+        (keyctl::KEYCTL_SETPERM, keyctl::KEY_POS_ALL),
+        (keyctl::KEYCTL_SET_TIMEOUT, timeout as libc::c_long),
+    ]
+    .iter()
+    {
+        if unsafe { libc::syscall(libc::SYS_keyctl, *operation, serial, *argument) } < 0 {
+            return Err(Error::from(std::io::Error::last_os_error())
+                .context("Failed to restrict cached key"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(not(target_os = "linux"), feature = "encryption"))]
+pub fn cache_secret(_description: &str, _secret: &[u8], _timeout: u64) -> Result<()> {
+    Err(anyhow!("Caching is only supported on Linux"))
+}
+
+/// Reads a secret cached by [`cache_secret`] if it hasn't expired yet
+#[cfg(all(target_os = "linux", feature = "encryption"))]
+pub fn read_cached_secret(description: &str) -> Option<Vec<u8>> {
+    let key_type = std::ffi::CString::new("user").ok()?;
+    let description = std::ffi::CString::new(description).ok()?;
+    let serial = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            keyctl::KEYCTL_SEARCH,
+            keyctl::KEY_SPEC_USER_KEYRING,
+            key_type.as_ptr(),
+            description.as_ptr(),
+            0,
+        )
+    };
+    if serial < 0 {
+        return None;
+    }
+    let mut buffer = vec![0u8; 256];
+    let length = unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            keyctl::KEYCTL_READ,
+            serial,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+        )
+    };
+    if length < 0 || length as usize > buffer.len() {
+        return None;
+    }
+    buffer.truncate(length as usize);
+    Some(buffer)
+}
+
+#[cfg(all(not(target_os = "linux"), feature = "encryption"))]
+pub fn read_cached_secret(_description: &str) -> Option<Vec<u8>> {
+    None
+}
+
+/// Events that desktop notifications can be shown for
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+pub enum NotificationEvent {
+    /// A caller has requested credentials
+    #[serde(rename = "credential-request")]
+    CredentialRequest,
+    /// YubiKey is going to receive a challenge and may need to be tapped
+    #[serde(rename = "yubikey")]
+    YubiKey,
+}
+
+static NOTIFICATION_EVENTS: OnceCell<Vec<NotificationEvent>> = OnceCell::new();
+
+pub fn set_notification_events(events: Vec<NotificationEvent>) {
+    let _ = NOTIFICATION_EVENTS.set(events);
+}
+
+/// All events are enabled by default with the `notification` feature, and none without it, where
+/// notifications run an external program
+fn is_notification_enabled(event: NotificationEvent) -> bool {
+    NOTIFICATION_EVENTS
+        .get()
+        .map(|events| events.contains(&event))
+        .unwrap_or(cfg!(feature = "notification"))
+}
+
+/// Shows the notification using a command line tool of the desktop, for builds without the
+/// `notification` feature
+#[cfg(not(feature = "notification"))]
+pub fn show_notification(event: NotificationEvent, summary: &str, body: &str, timeout: u32) {
+    if !is_notification_enabled(event) {
+        debug!("Notification for {:?} is disabled", event);
+        return;
+    }
+    for (program, args) in notification_commands(summary, body, timeout) {
+        match std::process::Command::new(program)
+            .args(&args)
+            .stdout(std::process::Stdio::null())
+            .status()
+        {
+            Ok(status) if status.success() => return,
+            Ok(status) => {
+                warn!(
+                    "Failed to show notification for {:?}, {} exited with {}",
+                    event, program, status
+                );
+                return;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("{} is not available for notifications", program);
+            }
+            Err(e) => {
+                warn!("Failed to show notification for {:?}, {}", event, e);
+                return;
+            }
+        }
+    }
+    warn!(
+        "No program found to show notification for {:?}, install notify-send or build with the notification feature",
+        event
+    );
+}
+
+#[cfg(feature = "notification")]
+pub fn show_notification(event: NotificationEvent, summary: &str, body: &str, timeout: u32) {
+    if !is_notification_enabled(event) {
+        debug!("Notification for {:?} is disabled", event);
+        return;
+    }
+    #[cfg(windows)]
+    let notification = {
+        // Windows decides how long toasts stay on screen
+        let _ = timeout;
+        crate::toast::show(summary, body)
+    };
+    #[cfg(not(windows))]
+    let notification = {
+        use notify_rust::{Notification, Timeout};
+        Notification::new()
+            .summary(summary)
+            .body(body)
+            .timeout(Timeout::Milliseconds(timeout))
+            .show()
+    };
+    if let Err(e) = notification {
+        warn!("Failed to show notification for {:?}, {}", event, e);
+    }
+}
+
+/// Asks to unlock the database in KeePassXC via a system dialog, for when there's no terminal to
+/// show errors in. Returns whether the user chose to retry
+pub fn show_unlock_dialog(message: &str) -> Result<bool> {
+    for (program, args) in unlock_dialog_commands("git-credential-keepassxc", message) {
+        match std::process::Command::new(program).args(&args).status() {
+            Ok(status) => return Ok(status.success()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("{} is not available for unlock dialog", program);
+            }
+            Err(e) => return Err(Error::from(e).context(format!("Failed to run {}", program))),
+        }
+    }
+    Err(anyhow!("No program found to show unlock dialog"))
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn unlock_dialog_commands(title: &str, message: &str) -> Vec<(&'static str, Vec<String>)> {
+    // zenity takes Pango markup
+    let markup = message
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    vec![
+        (
+            "zenity",
+            vec![
+                "--question".to_owned(),
+                format!("--title={}", title),
+                format!("--text={}", markup),
+                "--ok-label=Retry".to_owned(),
+                "--cancel-label=Cancel".to_owned(),
+            ],
+        ),
+        (
+            "kdialog",
+            vec![
+                "--title".to_owned(),
+                title.to_owned(),
+                "--yes-label".to_owned(),
+                "Retry".to_owned(),
+                "--no-label".to_owned(),
+                "Cancel".to_owned(),
+                "--yesno".to_owned(),
+                message.to_owned(),
+            ],
+        ),
+    ]
+}
+
+#[cfg(target_os = "macos")]
+fn unlock_dialog_commands(title: &str, message: &str) -> Vec<(&'static str, Vec<String>)> {
+    // texts are passed as arguments of the script to avoid quoting them in AppleScript
+    let script = [
+        "on run argv",
+        r#"display dialog (item 1 of argv) with title (item 2 of argv) buttons {"Cancel", "Retry"} default button "Retry" cancel button "Cancel" with icon caution"#,
+        "end run",
+    ];
+    let mut args = Vec::new();
+    for line in &script {
+        args.push("-e".to_owned());
+        args.push((*line).to_owned());
+    }
+    args.push(message.to_owned());
+    args.push(title.to_owned());
+    vec![("osascript", args)]
+}
+
+#[cfg(windows)]
+fn unlock_dialog_commands(title: &str, message: &str) -> Vec<(&'static str, Vec<String>)> {
+    let quote = |text: &str| format!("'{}'", text.replace('\'', "''"));
+    let script = format!(
+        "Add-Type -AssemblyName System.Windows.Forms; if ([System.Windows.Forms.MessageBox]::Show({}, {}, 'RetryCancel', 'Warning') -ne 'Retry') {{ exit 1 }}",
+        quote(message),
+        quote(title)
+    );
+    vec![(
+        "powershell",
+        vec![
+            "-NoProfile".to_owned(),
+            "-NonInteractive".to_owned(),
+            "-Command".to_owned(),
+            script,
+        ],
+    )]
+}
+
+#[cfg(all(unix, not(target_os = "macos"), not(feature = "notification")))]
+fn notification_commands(
+    summary: &str,
+    body: &str,
+    timeout: u32,
+) -> Vec<(&'static str, Vec<String>)> {
+    vec![(
+        "notify-send",
+        vec![
+            format!("--expire-time={}", timeout),
+            "--".to_owned(),
+            summary.to_owned(),
+            body.to_owned(),
+        ],
+    )]
+}
+
+#[cfg(all(target_os = "macos", not(feature = "notification")))]
+fn notification_commands(
+    summary: &str,
+    body: &str,
+    _timeout: u32,
+) -> Vec<(&'static str, Vec<String>)> {
+    // macOS decides how long notifications stay on screen, texts are passed as arguments as in
+    // unlock_dialog_commands
+    let script = [
+        "on run argv",
+        "display notification (item 1 of argv) with title (item 2 of argv)",
+        "end run",
+    ];
+    let mut args = Vec::new();
+    for line in &script {
+        args.push("-e".to_owned());
+        args.push((*line).to_owned());
+    }
+    args.push(body.to_owned());
+    args.push(summary.to_owned());
+    vec![("osascript", args)]
+}
+
+/// Toasts need the `notification` feature on Windows
+#[cfg(all(windows, not(feature = "notification")))]
+fn notification_commands(
+    _summary: &str,
+    _body: &str,
+    _timeout: u32,
+) -> Vec<(&'static str, Vec<String>)> {
+    Vec::new()
+}
+
+/// Masks secret values in a keepassxc-protocol message
+pub fn redact_message(message: &mut serde_json::Value) {
+    use serde_json::Value;
+    match message {
+        Value::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                if value.is_null() {
+                    continue;
+                }
+                if SECRET_FIELDS.contains(&name.as_str()) {
+                    *value = Value::String("<redacted>".to_owned());
+                } else if name == "stringFields" {
+                    // custom fields may well contain secrets, keep the names only
+                    if let Value::Array(fields) = value {
+                        for field in fields.iter_mut() {
+                            if let Value::Object(field) = field {
+                                for field_value in field.values_mut() {
+                                    *field_value = Value::String("<redacted>".to_owned());
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    redact_message(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact_message),
+        _ => {}
+    }
+}
+
+/// Logs a keepassxc-protocol message with secrets masked if --trace-protocol is enabled
+pub fn trace_message<T: AsRef<str>>(label: &str, json: T) {
+    if !is_trace_protocol() {
+        return;
+    }
+    match serde_json::from_str::<serde_json::Value>(json.as_ref()) {
+        Ok(mut message) => {
+            redact_message(&mut message);
+            info!("TRACE {}: {}", label, message);
+        }
+        Err(_) => {
+            info!(
+                "TRACE {}: <{} bytes of non-JSON data>",
+                label,
+                json.as_ref().len()
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidKeyError(String, usize);
+impl fmt::Display for InvalidKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {} key length {}", self.0, self.1)
+    }
+}
+impl std::error::Error for InvalidKeyError {}
+#[derive(Debug)]
+pub struct CryptionError(bool);
+impl fmt::Display for CryptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 {
+            write!(f, "Encryption error")
+        } else {
+            write!(f, "Decryption error")
+        }
+    }
+}
+impl std::error::Error for CryptionError {}
+impl CryptionError {
+    pub fn is_decryption(&self) -> bool {
+        !self.0
+    }
+}
+
+/// How long to wait for other invocations handling the same URL before going ahead anyway
+pub const REQUEST_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Held while a request is being handled, released when dropped
+#[cfg(unix)]
+pub struct RequestLock {
+    _file: std::fs::File,
+}
+#[cfg(windows)]
+pub struct RequestLock;
+
+/// Directory for per-user runtime files, e.g. request locks
+fn runtime_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|p| p.is_dir())
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// Marker file of which the modification time tells when the URL (along with the configuration
+/// file, in the key) last had no matching logins
+fn no_match_cache_path(key: &str) -> PathBuf {
+    use sha2::{Digest, Sha256};
+    let hash: String = Sha256::digest(key.as_bytes())
+        .iter()
+        .take(16)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    #[cfg(unix)]
+    let name = format!(
+        "{}-{}-{}.nomatch",
+        clap::crate_name!(),
+        get_current_user().0,
+        hash
+    );
+    // the temporary directory is per user on Windows
+    #[cfg(windows)]
+    let name = format!("{}-{}.nomatch", clap::crate_name!(), hash);
+    runtime_dir().join(name)
+}
+
+/// Whether the key has been marked as having no matching logins within the TTL, by a lookup of
+/// the same variant (e.g. username and options)
+pub fn is_no_match_cached(key: &str, variant: &str, ttl: std::time::Duration) -> bool {
+    let metadata = match std::fs::symlink_metadata(no_match_cache_path(key)) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return false,
+    };
+    // others may be able to create files in the temporary directory
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.uid() != get_current_user().0 {
+            return false;
+        }
+    }
+    let is_fresh = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map(|age| age < ttl)
+        .unwrap_or(false);
+    is_fresh
+        && std::fs::read_to_string(no_match_cache_path(key)).is_ok_and(|cached| cached == variant)
+}
+
+/// Marks the key as having no matching logins from now on for the variant of the lookup, which
+/// replaces any other one as only the last is remembered
+pub fn cache_no_match(key: &str, variant: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options.open(no_match_cache_path(key))?;
+    file.write_all(variant.as_bytes())?;
+    file.set_modified(std::time::SystemTime::now())?;
+    Ok(())
+}
+
+/// Forgets the key having no matching logins in any variant, e.g. after storing one
+pub fn clear_no_match(key: &str) {
+    let _ = std::fs::remove_file(no_match_cache_path(key));
+}
+
+/// State file of the entries last used per host and username
+fn recent_entries_path() -> PathBuf {
+    #[cfg(unix)]
+    let name = format!(
+        "{}-{}-recent.json",
+        clap::crate_name!(),
+        get_current_user().0
+    );
+    #[cfg(windows)]
+    let name = format!("{}-recent.json", clap::crate_name!());
+    runtime_dir().join(name)
+}
+
+fn read_recent_entries() -> HashMap<String, String> {
+    let path = recent_entries_path();
+    // others may be able to create files in the temporary directory
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() && metadata.uid() == get_current_user().0 => {}
+            _ => return HashMap::new(),
+        }
+    }
+    std::fs::read(&path)
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+/// UUID of the entry last used for the key, see save_recent_entry
+pub fn read_recent_entry(key: &str) -> Option<String> {
+    read_recent_entries().remove(key)
+}
+
+/// Remembers the entry as the one last used for the key
+pub fn save_recent_entry(key: &str, uuid: &str) -> Result<()> {
+    let mut entries = read_recent_entries();
+    if entries.get(key).map(String::as_str) == Some(uuid) {
+        return Ok(());
+    }
+    entries.insert(key.to_owned(), uuid.to_owned());
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600).custom_flags(libc::O_NOFOLLOW);
+    }
+    let mut file = options.open(recent_entries_path())?;
+    file.write_all(&serde_json::to_vec(&entries)?)?;
+    Ok(())
+}
+
+/// Waits for other invocations handling the same URL (e.g. parallel git-lfs transfers) to finish,
+/// so that only the first one prompts the user. Returns `None` if the lock is unavailable, in
+/// which case the request simply goes ahead.
+#[cfg(unix)]
+pub fn lock_request<T: AsRef<str>>(url: T, timeout: std::time::Duration) -> Option<RequestLock> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut hasher = DefaultHasher::new();
+    url.as_ref().hash(&mut hasher);
+    let (uid, _) = get_current_user();
+    let path = runtime_dir().join(format!(
+        "{}-{}-{:016x}.lock",
+        clap::crate_name!(),
+        uid,
+        hasher.finish()
+    ));
+    let file = match std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Failed to open lock file, {}", e);
+            return None;
+        }
+    };
+    let start = std::time::Instant::now();
+    let mut waiting = false;
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+            return Some(RequestLock { _file: file });
+        }
+        let error = std::io::Error::last_os_error();
+        if error.kind() != std::io::ErrorKind::WouldBlock {
+            warn!("Failed to lock {}, {}", path.to_string_lossy(), error);
+            return None;
+        }
+        if start.elapsed() >= timeout {
+            warn!("Other invocations are still handling the same URL, going ahead anyway");
+            return None;
+        }
+        if !waiting {
+            info!("Waiting for other invocations handling the same URL");
+            waiting = true;
+        }
+        // the caller checks for interruption once the lock is given up
+        if sleep_interruptible(std::time::Duration::from_millis(100)).is_err() {
+            return None;
+        }
+    }
+}
+
+/// Not implemented on Windows, where parallel requests go ahead at once
+#[cfg(windows)]
+pub fn lock_request<T: AsRef<str>>(_url: T, _timeout: std::time::Duration) -> Option<RequestLock> {
+    None
+}
+
+/// Normalises a caller path before it's saved or compared. On Windows, app execution aliases
+/// (e.g. python.exe in %LOCALAPPDATA%\Microsoft\WindowsApps) are resolved to their targets, which
+/// is what running processes report, and symbolic links are followed.
+#[cfg(windows)]
+pub fn normalize_caller_path<T: AsRef<Path>>(path: T) -> PathBuf {
+    let path = path.as_ref();
+    let resolved = read_app_exec_link(path)
+        .or_else(|| std::fs::canonicalize(path).ok())
+        .unwrap_or_else(|| path.to_owned());
+    // canonicalize returns verbatim paths, e.g. \\?\C:\Windows, unlike the process paths
+    let stripped = resolved
+        .to_string_lossy()
+        .strip_prefix(r"\\?\")
+        .filter(|stripped| !stripped.starts_with("UNC\\"))
+        .map(PathBuf::from);
+    stripped.unwrap_or(resolved)
+}
+
+#[cfg(unix)]
+pub fn normalize_caller_path<T: AsRef<Path>>(path: T) -> PathBuf {
+    path.as_ref().to_owned()
+}
+
+/// Resolves symbolic links in a caller path before comparison, e.g. /bin/git to /usr/bin/git or
+/// Nix and Homebrew links to their stores, keeping the path as is if it can't be resolved
+#[cfg(windows)]
+pub fn canonicalize_caller_path<T: AsRef<Path>>(path: T) -> PathBuf {
+    normalize_caller_path(path)
+}
+
+#[cfg(unix)]
+pub fn canonicalize_caller_path<T: AsRef<Path>>(path: T) -> PathBuf {
+    std::fs::canonicalize(path.as_ref()).unwrap_or_else(|_| path.as_ref().to_owned())
+}
+
+/// Makes the path absolute against the working directory, dropping `.` and `..` components
+/// without resolving symbolic links
+pub fn absolute_path<T: AsRef<Path>>(path: T) -> Result<PathBuf> {
+    use std::path::Component;
+    let path = std::env::current_dir()?.join(path.as_ref());
+    let mut absolute = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+    }
+    Ok(absolute)
+}
+
+fn is_executable(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        #[cfg(unix)]
+        Ok(metadata) => {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+        }
+        #[cfg(windows)]
+        Ok(metadata) => metadata.is_file(),
+        Err(_) => false,
+    }
+}
+
+/// Resolves the path of a caller to the absolute path of an existing executable, looking up bare
+/// names (e.g. `git`) in PATH like a shell would
+pub fn resolve_executable<T: AsRef<Path>>(path: T) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let is_bare_name = path.parent() == Some(Path::new("")) && !path.as_os_str().is_empty();
+    let candidates = if is_bare_name {
+        let mut names = vec![path.to_owned()];
+        if cfg!(windows) && path.extension().is_none() {
+            names.push(path.with_extension("exe"));
+        }
+        std::env::var_os("PATH")
+            .map(|dirs| {
+                std::env::split_paths(&dirs)
+                    .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+                    .filter(|candidate| candidate.is_absolute())
+                    .collect()
+            })
+            .unwrap_or_default()
+    } else {
+        vec![absolute_path(path)?]
+    };
+    candidates
+        .into_iter()
+        .find(|candidate| is_executable(candidate))
+        .ok_or_else(|| anyhow!("{} is not an existing executable", path.to_string_lossy()))
+}
+
+/// Whether the path of a caller profile is the one of the process, case-insensitively on Windows
+pub fn caller_path_matches<T: AsRef<Path>>(profile_path: &str, process_path: T) -> bool {
+    let process_path = process_path.as_ref().to_string_lossy();
+    if cfg!(windows) {
+        let normalize = |path: &str| path.replace('/', "\\").to_lowercase();
+        normalize(profile_path) == normalize(&process_path)
+    } else {
+        profile_path == process_path
+    }
+}
+
+/// Reads the target of an app execution alias, i.e. a reparse point of IO_REPARSE_TAG_APPEXECLINK
+#[cfg(windows)]
+fn read_app_exec_link(path: &Path) -> Option<PathBuf> {
+    use std::ffi::c_void;
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+    const FSCTL_GET_REPARSE_POINT: u32 = 0x0009_00a8;
+    const MAXIMUM_REPARSE_DATA_BUFFER_SIZE: usize = 16 * 1024;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn DeviceIoControl(
+            device: *mut c_void,
+            io_control_code: u32,
+            in_buffer: *const c_void,
+            in_buffer_size: u32,
+            out_buffer: *mut c_void,
+            out_buffer_size: u32,
+            bytes_returned: *mut u32,
+            overlapped: *mut c_void,
+        ) -> i32;
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(FILE_FLAG_OPEN_REPARSE_POINT | FILE_FLAG_BACKUP_SEMANTICS)
+        .open(path)
+        .ok()?;
+    let mut buffer = vec![0u8; MAXIMUM_REPARSE_DATA_BUFFER_SIZE];
+    let mut bytes_returned = 0u32;
+    let success = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as *mut c_void,
+            FSCTL_GET_REPARSE_POINT,
+            std::ptr::null(),
+            0,
+            buffer.as_mut_ptr() as *mut c_void,
+            buffer.len() as u32,
+            &mut bytes_returned,
+            std::ptr::null_mut(),
+        )
+    };
+    if success == 0 {
+        return None;
+    }
+    parse_app_exec_link(&buffer[..bytes_returned as usize])
+}
+
+/// Parses REPARSE_DATA_BUFFER of an app execution alias, of which the data is a version followed
+/// by NUL-terminated UTF-16 strings: package ID, app user model ID and target path
+#[cfg(any(windows, test))]
+fn parse_app_exec_link(buffer: &[u8]) -> Option<PathBuf> {
+    const IO_REPARSE_TAG_APPEXECLINK: u32 = 0x8000_001b;
+    const APPEXECLINK_VERSION: u32 = 3;
+
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes = buffer.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    // ReparseTag, ReparseDataLength and Reserved come first
+    if read_u32(0)? != IO_REPARSE_TAG_APPEXECLINK || read_u32(8)? != APPEXECLINK_VERSION {
+        return None;
+    }
+    let data: Vec<u16> = buffer
+        .get(12..)?
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    let target = data.split(|c| *c == 0).nth(2)?;
+    if target.is_empty() {
+        return None;
+    }
+    Some(PathBuf::from(String::from_utf16(target).ok()?))
+}
+
+/// The process which invoked git-credential-keepassxc (normally Git)
+pub struct CallerProcess {
+    pub pid: usize,
+    pub path: PathBuf,
+    /// SHA-256 of the executable in hex, if requested
+    pub sha256: Option<String>,
+    /// Whether the path is taken from the command line of a set-user-ID process, which its starter
+    /// chooses, and there's no hash of what it actually runs
+    pub by_command_line: bool,
+    /// Real UID of the current process
+    #[cfg(unix)]
+    pub uid: u32,
+    /// Real GID of the current process
+    #[cfg(unix)]
+    pub gid: u32,
+    #[cfg(target_os = "linux")]
+    pidfd: PidFd,
+    #[cfg(windows)]
+    handle: ProcessHandle,
+}
+
+/// Real UID and GID of the current process
+#[cfg(unix)]
+pub fn get_current_user() -> (u32, u32) {
+    unsafe { (libc::getuid(), libc::getgid()) }
+}
+
+/// Name of the user this program runs as, looked up by the effective UID as `USER` may be set to
+/// anything
+#[cfg(unix)]
+pub fn get_username() -> Option<String> {
+    let mut passwd = unsafe { std::mem::zeroed::<libc::passwd>() };
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let ret = unsafe {
+        libc::getpwuid_r(
+            libc::geteuid(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        warn!("Failed to look up the current user");
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+#[cfg(windows)]
+pub fn get_username() -> Option<String> {
+    std::env::var("USERNAME").ok()
+}
+
+/// Error of caller profiles pinning the SHA-256 of the executable on Windows, where the file at
+/// the path of the process may have been renamed and replaced since it started
+#[cfg(windows)]
+pub const CALLER_HASH_UNSUPPORTED: &str =
+    "Verifying the SHA-256 of callers is not supported on Windows, remove it from caller profiles";
+
+/// SHA-256 of the content in hex
+pub fn sha256_hex<R: Read>(mut reader: R) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Refers to a process by a pidfd rather than its PID, which may be reused once it exits. Empty if
+/// the kernel (< 5.3) doesn't support pidfds.
+#[cfg(target_os = "linux")]
+struct PidFd(Option<libc::c_long>);
+
+#[cfg(target_os = "linux")]
+impl PidFd {
+    fn open(pid: libc::pid_t) -> Result<Self> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd >= 0 {
+            return Ok(Self(Some(fd)));
+        }
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() == Some(libc::ENOSYS) {
+            debug!("pidfd is not supported by the kernel");
+            Ok(Self(None))
+        } else {
+            Err(Error::from(error).context("Failed to open pidfd of caller process"))
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        if let Some(fd) = self.0 {
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_pidfd_send_signal,
+                    fd,
+                    0,
+                    std::ptr::null::<libc::siginfo_t>(),
+                    0,
+                )
+            };
+            result == 0
+        } else {
+            true
+        }
+    }
+}
+
+/// Parent PID of a process according to /proc/<PID>/stat
+#[cfg(target_os = "linux")]
+fn get_ppid(pid: libc::pid_t) -> Result<libc::pid_t> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .with_context(|| format!("Failed to retrieve status of process {}", pid))?;
+    // the executable name in parentheses may contain anything, the parent PID is the second field
+    // after it
+    stat.rfind(')')
+        .and_then(|end| stat[end + 1..].split_whitespace().nth(1))
+        .and_then(|ppid| ppid.parse().ok())
+        .ok_or_else(|| anyhow!("Failed to parse status of process {}", pid))
 }
-#[macro_export]
-macro_rules! info {
-    ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Info, "", $($args)+);
-        #[cfg(test)] eprintln!("{}: {}", slog::Level::Info, format!($($args)+));
+
+/// Executable of a set-user-ID root process started by this user, e.g. sudo, whose /proc/<PID>/exe
+/// only root can read. It's looked up by the command line, which its starter chooses, so it's only
+/// accepted if it's a set-user-ID root program as well.
+#[cfg(target_os = "linux")]
+fn read_setuid_executable(pid: libc::pid_t) -> Result<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let uids: Vec<u32> = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Uid:"))
+        .map(|uids| {
+            uids.split_whitespace()
+                .filter_map(|uid| uid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+    // real and effective UID
+    if uids.len() < 2 || uids[0] != unsafe { libc::getuid() } || uids[1] != 0 {
+        return Err(anyhow!(
+            "Process is not set-user-ID root started by this user"
+        ));
+    }
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid))?;
+    let argv0 = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+    let path = resolve_executable(String::from_utf8_lossy(argv0).as_ref())?;
+    let metadata = std::fs::metadata(&path)?;
+    if metadata.uid() != 0 || metadata.mode() & libc::S_ISUID == 0 {
+        return Err(anyhow!(
+            "{} is not a set-user-ID root program",
+            path.to_string_lossy()
+        ));
+    }
+    Ok(path)
+}
+
+/// Reads the process while holding a pidfd of it. `is_parent` tells whether the process is still
+/// the parent of the previous one, i.e. what has been read via /proc/<PID> belongs to it.
+#[cfg(target_os = "linux")]
+fn read_process<F: Fn() -> bool>(
+    pid: libc::pid_t,
+    hash_executable: bool,
+    is_parent: F,
+) -> Result<CallerProcess> {
+    let pidfd = PidFd::open(pid)?;
+    let ensure_parent = || {
+        if !pidfd.is_alive() {
+            return Err(anyhow!("Caller process exited during verification"));
+        }
+        if !is_parent() {
+            return Err(anyhow!("Caller process changed during verification"));
+        }
+        Ok(())
+    };
+    ensure_parent()?;
+    let exe = format!("/proc/{}/exe", pid);
+    // the executable is hashed through /proc/<PID>/exe where possible, which is what the process
+    // actually runs
+    let (path, executable) = match std::fs::read_link(&exe) {
+        Ok(path) => (path, Some(PathBuf::from(exe))),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            let path = read_setuid_executable(pid).with_context(|| {
+                format!("Failed to retrieve executable path of process {}", pid)
+            })?;
+            info!("Caller runs set-user-ID, identified it by its command line");
+            // the file at the path may not be what the process runs, so there's nothing to hash
+            (path, None)
+        }
+        Err(e) => {
+            return Err(Error::from(e).context(format!(
+                "Failed to retrieve executable path of process {}",
+                pid
+            )))
+        }
+    };
+    let sha256 = match &executable {
+        Some(executable) if hash_executable => Some(sha256_hex(
+            std::fs::File::open(executable)
+                .with_context(|| format!("Failed to read executable of process {}", pid))?,
+        )?),
+        _ => None,
     };
+    ensure_parent()?;
+    let (uid, gid) = get_current_user();
+    Ok(CallerProcess {
+        pid: pid as usize,
+        path,
+        sha256,
+        by_command_line: executable.is_none(),
+        uid,
+        gid,
+        pidfd,
+    })
 }
-#[macro_export]
-macro_rules! debug {
-    ($($args:tt)+) => {
-        #[cfg(not(test))] slog::log!(crate::LOGGER.get().unwrap(), slog::Level::Debug, "", $($args)+);
-        #[cfg(test)] eprintln!("{}: {}", slog::Level::Debug, format!($($args)+));
+
+#[cfg(target_os = "linux")]
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        if let Some(fd) = self.0 {
+            unsafe { libc::close(fd as libc::c_int) };
+        }
+    }
+}
+
+/// Reads a process which isn't related to us, e.g. a D-Bus client, while holding a pidfd of it,
+/// which only guards against the process exiting during verification
+#[cfg(all(target_os = "linux", feature = "secret-service"))]
+pub fn get_client_process(pid: u32, hash_executable: bool) -> Result<CallerProcess> {
+    read_process(pid as libc::pid_t, hash_executable, || true)
+}
+
+/// Reads everything about the parent through /proc/<PPID> while holding a pidfd of it, so that the
+/// result can't come from another process reusing the PID, and hashes the executable the process
+/// actually runs rather than whatever is at its path now
+#[cfg(target_os = "linux")]
+pub fn get_caller_process(hash_executable: bool) -> Result<CallerProcess> {
+    detail!("PID: {}", std::process::id());
+    let ppid = unsafe { libc::getppid() };
+    read_process(ppid, hash_executable, || unsafe { libc::getppid() } == ppid)
+}
+
+/// The parent of a (wrapper) process, which must still be alive as well
+#[cfg(target_os = "linux")]
+pub fn get_parent_process(child: &CallerProcess, hash_executable: bool) -> Result<CallerProcess> {
+    let pid = child.pid as libc::pid_t;
+    let ppid = get_ppid(pid)?;
+    read_process(ppid, hash_executable, || {
+        child.pidfd.is_alive() && get_ppid(pid).ok() == Some(ppid)
+    })
+}
+
+#[cfg(not(any(
+    windows,
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+pub fn get_caller_process(hash_executable: bool) -> Result<CallerProcess> {
+    use sysinfo::{get_current_pid, ProcessExt, System, SystemExt};
+    let pid = get_current_pid().map_err(|s| anyhow!("Failed to retrieve current PID: {}", s))?;
+    detail!("PID: {}", pid);
+    let system = System::new_all();
+    let proc = system
+        .get_process(pid)
+        .ok_or_else(|| anyhow!("Failed to retrieve information of current process"))?;
+    let ppid = proc
+        .parent()
+        .ok_or_else(|| anyhow!("Failed to retrieve parent PID"))?;
+    get_process(&system, ppid, hash_executable)
+}
+
+#[cfg(not(any(
+    windows,
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+pub fn get_parent_process(child: &CallerProcess, hash_executable: bool) -> Result<CallerProcess> {
+    use sysinfo::{Pid, ProcessExt, System, SystemExt};
+    let system = System::new_all();
+    let ppid = system
+        .get_process(child.pid as Pid)
+        .and_then(|proc| proc.parent())
+        .ok_or_else(|| anyhow!("Failed to retrieve parent PID of process {}", child.pid))?;
+    get_process(&system, ppid, hash_executable)
+}
+
+#[cfg(not(any(
+    windows,
+    target_os = "linux",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn get_process(
+    system: &sysinfo::System,
+    ppid: sysinfo::Pid,
+    hash_executable: bool,
+) -> Result<CallerProcess> {
+    use sysinfo::{ProcessExt, SystemExt};
+    let pproc = system
+        .get_process(ppid)
+        .ok_or_else(|| anyhow!("Failed to retrieve parent process information"))?;
+    let (uid, gid) = get_current_user();
+    let sha256 = if hash_executable {
+        Some(sha256_hex(std::fs::File::open(pproc.exe())?)?)
+    } else {
+        None
     };
+    Ok(CallerProcess {
+        pid: ppid as usize,
+        path: pproc.exe().to_owned(),
+        sha256,
+        by_command_line: false,
+        uid,
+        gid,
+    })
 }
 
-thread_local!(pub static SOCKET_PATH: OnceCell<PathBuf> = OnceCell::new());
-pub fn get_socket_path() -> Result<PathBuf> {
-    let socket_path = SOCKET_PATH.with(|s| -> Result<_> {
-        Ok(s.get_or_try_init(|| -> Result<_> {
-            let base_dirs = directories_next::BaseDirs::new()
-                .ok_or_else(|| anyhow!("Failed to initialise base_dirs"))?;
-            let get_socket_path_with_name = |name: &str| -> Result<PathBuf> {
-                let socket_dir = if cfg!(windows) && name == KEEPASS_SOCKET_NAME_LEGACY {
-                    let cache_dir = base_dirs.cache_dir();
-                    PathBuf::from(format!(
-                        "\\\\.\\pipe\\\\{}\\Temp\\{}",
-                        cache_dir.to_string_lossy(),
-                        name
-                    ))
-                } else if cfg!(windows) {
-                    PathBuf::from(format!("\\\\.\\pipe\\{}", name))
-                } else if cfg!(target_os = "macos") {
-                    std::env::temp_dir().join(name)
-                } else {
-                    base_dirs
-                        .runtime_dir()
-                        .ok_or_else(|| anyhow!("Failed to locate runtime_dir automatically"))?
-                        .join(name)
-                };
-                Ok(socket_dir)
-            };
-            let legacy_path = get_socket_path_with_name(KEEPASS_SOCKET_NAME_LEGACY);
-            if legacy_path.is_ok() && legacy_path.as_ref().unwrap().exists() {
-                legacy_path
-            } else {
-                get_socket_path_with_name(KEEPASS_SOCKET_NAME)
-            }
-        })?
-        .clone())
-    });
-    if let Ok(ref socket_path) = socket_path {
-        debug!("Socket path: {}", socket_path.to_string_lossy());
+/// Refers to a process by a handle rather than its PID, which can't be reused by another process
+/// while the handle is open
+#[cfg(windows)]
+struct ProcessHandle(windows_sys::Win32::Foundation::HANDLE);
+
+#[cfg(windows)]
+impl ProcessHandle {
+    fn open(pid: u32) -> Result<Self> {
+        use windows_sys::Win32::System::Threading::{
+            OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+        };
+        let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+        if handle.is_null() {
+            return Err(Error::from(std::io::Error::last_os_error())
+                .context(format!("Failed to open process {}", pid)));
+        }
+        Ok(Self(handle))
+    }
+
+    /// Path of the image the process runs
+    fn image_path(&self) -> Result<PathBuf> {
+        use std::os::windows::ffi::OsStringExt;
+        use windows_sys::Win32::System::Threading::{
+            QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        };
+        let mut path = vec![0u16; 32768];
+        let mut length = path.len() as u32;
+        let result = unsafe {
+            QueryFullProcessImageNameW(self.0, PROCESS_NAME_WIN32, path.as_mut_ptr(), &mut length)
+        };
+        if result == 0 {
+            return Err(Error::from(std::io::Error::last_os_error())
+                .context("Failed to retrieve executable path of caller process"));
+        }
+        path.truncate(length as usize);
+        Ok(PathBuf::from(std::ffi::OsString::from_wide(&path)))
     }
-    socket_path
 }
 
-#[derive(Debug)]
-pub struct InvalidKeyError(String, usize);
-impl fmt::Display for InvalidKeyError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Invalid {} key length {}", self.0, self.1)
+#[cfg(windows)]
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        unsafe { windows_sys::Win32::Foundation::CloseHandle(self.0) };
     }
 }
-impl std::error::Error for InvalidKeyError {}
-#[derive(Debug)]
-pub struct CryptionError(bool);
-impl fmt::Display for CryptionError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.0 {
-            write!(f, "Encryption error")
-        } else {
-            write!(f, "Decryption error")
-        }
+
+/// Creation time of a process in 100 ns intervals since 1601
+#[cfg(windows)]
+fn get_creation_time(handle: windows_sys::Win32::Foundation::HANDLE) -> Result<u64> {
+    use windows_sys::Win32::Foundation::FILETIME;
+    use windows_sys::Win32::System::Threading::GetProcessTimes;
+    let mut times = [FILETIME::default(); 4];
+    let [creation, exit, kernel, user] = &mut times;
+    if unsafe { GetProcessTimes(handle, creation, exit, kernel, user) } == 0 {
+        return Err(Error::from(std::io::Error::last_os_error())
+            .context("Failed to retrieve creation time of process"));
     }
+    Ok((u64::from(times[0].dwHighDateTime) << 32) | u64::from(times[0].dwLowDateTime))
+}
+
+/// Opens a handle of the parent, which sysinfo only tells the PID of, and makes sure it's not
+/// another process that reused the PID by comparing creation times with the child's, before
+/// reading the path through the handle
+#[cfg(windows)]
+fn get_process(
+    ppid: sysinfo::Pid,
+    child_created: u64,
+    hash_executable: bool,
+) -> Result<CallerProcess> {
+    // the executable would be read by its path, which may have changed since the process started
+    if hash_executable {
+        return Err(anyhow!(CALLER_HASH_UNSUPPORTED));
+    }
+    let handle = ProcessHandle::open(ppid as u32)?;
+    if get_creation_time(handle.0)? > child_created {
+        return Err(anyhow!("Caller process exited during verification"));
+    }
+    let path = handle.image_path()?;
+    Ok(CallerProcess {
+        pid: ppid,
+        path,
+        sha256: None,
+        by_command_line: false,
+        handle,
+    })
+}
+
+#[cfg(windows)]
+pub fn get_caller_process(hash_executable: bool) -> Result<CallerProcess> {
+    use sysinfo::{get_current_pid, ProcessExt, System, SystemExt};
+    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+    let pid = get_current_pid().map_err(|s| anyhow!("Failed to retrieve current PID: {}", s))?;
+    detail!("PID: {}", pid);
+    let system = System::new_all();
+    let ppid = system
+        .get_process(pid)
+        .and_then(|proc| proc.parent())
+        .ok_or_else(|| anyhow!("Failed to retrieve parent PID"))?;
+    let created = get_creation_time(unsafe { GetCurrentProcess() })?;
+    get_process(ppid, created, hash_executable)
+}
+
+#[cfg(windows)]
+pub fn get_parent_process(child: &CallerProcess, hash_executable: bool) -> Result<CallerProcess> {
+    use sysinfo::{ProcessExt, System, SystemExt};
+    let system = System::new_all();
+    let ppid = system
+        .get_process(child.pid)
+        .and_then(|proc| proc.parent())
+        .ok_or_else(|| anyhow!("Failed to retrieve parent PID of process {}", child.pid))?;
+    get_process(ppid, get_creation_time(child.handle.0)?, hash_executable)
+}
+
+/// sysinfo doesn't support the BSDs, so ask the kernel directly
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+pub fn get_caller_process(hash_executable: bool) -> Result<CallerProcess> {
+    detail!("PID: {}", std::process::id());
+    let ppid = unsafe { libc::getppid() };
+    let (uid, gid) = get_current_user();
+    let path = get_process_path(ppid)?;
+    let sha256 = if hash_executable {
+        Some(sha256_hex(std::fs::File::open(&path)?)?)
+    } else {
+        None
+    };
+    Ok(CallerProcess {
+        pid: ppid as usize,
+        path,
+        sha256,
+        by_command_line: false,
+        uid,
+        gid,
+    })
+}
+
+/// Only the parent of the current process can be looked up without parsing kinfo_proc, which
+/// differs for every BSD
+#[cfg(any(
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+pub fn get_parent_process(_child: &CallerProcess, _hash_executable: bool) -> Result<CallerProcess> {
+    Err(anyhow!(
+        "Wrapper caller profiles are not supported on this platform"
+    ))
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd"))]
+fn get_process_path(pid: libc::pid_t) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    let mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC,
+        libc::KERN_PROC_PATHNAME,
+        pid,
+    ];
+    #[cfg(target_os = "netbsd")]
+    let mib = [
+        libc::CTL_KERN,
+        libc::KERN_PROC_ARGS,
+        pid,
+        libc::KERN_PROC_PATHNAME,
+    ];
+    let mut path = vec![0u8; libc::PATH_MAX as usize];
+    let mut length = path.len();
+    let result = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            path.as_mut_ptr() as *mut libc::c_void,
+            &mut length,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if result != 0 {
+        return Err(
+            Error::from(std::io::Error::last_os_error()).context(format!(
+                "Failed to retrieve executable path of process {}",
+                pid
+            )),
+        );
+    }
+    path.truncate(length);
+    // the length includes the terminating NUL
+    if let Some(nul) = path.iter().position(|b| *b == 0) {
+        path.truncate(nul);
+    }
+    Ok(PathBuf::from(OsString::from_vec(path)))
+}
+
+/// OpenBSD only exposes the arguments of processes, which can't be trusted to verify callers
+#[cfg(target_os = "openbsd")]
+fn get_process_path(_pid: libc::pid_t) -> Result<PathBuf> {
+    Err(anyhow!(
+        "Caller verification is not supported on OpenBSD as it doesn't expose executable paths of processes"
+    ))
 }
-impl std::error::Error for CryptionError {}
 
 #[cfg(unix)]
-fn get_stream() -> Result<Rc<RefCell<UnixStream>>> {
-    thread_local!(static STREAM: OnceCell<Rc<RefCell<UnixStream>>> = OnceCell::new());
-    Ok(STREAM.with(|s| -> Result<_> {
-        Ok(s.get_or_try_init(|| -> Result<_> {
-            let path = get_socket_path()?;
-            Ok(Rc::new(RefCell::new(
-                UnixStream::connect(&path).with_context(|| {
-                    format!(
-                        "Failed to connect to Unix socket {}",
-                        path.to_string_lossy()
-                    )
-                })?,
-            )))
-        })?
-        .clone())
-    })?)
+type Stream = UnixStream;
+#[cfg(windows)]
+type Stream = PipeClient;
+
+thread_local!(static STREAM: RefCell<Option<Rc<RefCell<Stream>>>> = RefCell::new(None));
+
+/// Socket not being there or nobody listening on it almost always means KeePassXC isn't running or
+/// browser integration is disabled, so tell the user what to do instead of a bare IO error
+fn explain_connection_error(error: std::io::Error, description: String) -> Error {
+    use std::io::ErrorKind;
+    match error.kind() {
+        ErrorKind::NotFound | ErrorKind::ConnectionRefused => Error::from(error).context(format!(
+            "{} is unavailable. {}",
+            description, BROWSER_INTEGRATION_HINT
+        )),
+        _ => Error::from(error).context(format!("Failed to connect to {}", description)),
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "dbus-activation"))]
+fn connect_stream() -> Result<Stream> {
+    let path = get_socket_path()?;
+    UnixStream::connect(&path).or_else(|e| recover_connection(&path, e))
+}
+
+#[cfg(all(unix, not(all(target_os = "linux", feature = "dbus-activation"))))]
+fn connect_stream() -> Result<Stream> {
+    let path = get_socket_path()?;
+    UnixStream::connect(&path)
+        .map_err(|e| explain_connection_error(e, format!("Unix socket {}", path.to_string_lossy())))
 }
 
 #[cfg(windows)]
-fn get_stream() -> Result<Rc<RefCell<PipeClient>>> {
-    thread_local!(static STREAM: OnceCell<Rc<RefCell<PipeClient>>> = OnceCell::new());
-    Ok(STREAM.with(|s| -> Result<_> {
-        Ok(s.get_or_try_init(|| -> Result<_> {
-            let path = get_socket_path()?;
-            Ok(Rc::new(RefCell::new(
-                PipeClient::connect(&path).with_context(|| {
-                    format!("Failed to connect to named pipe {}", path.to_string_lossy())
-                })?,
-            )))
-        })?
-        .clone())
-    })?)
+fn connect_stream() -> Result<Stream> {
+    let path = get_socket_path()?;
+    PipeClient::connect(&path)
+        .map_err(|e| explain_connection_error(e, format!("named pipe {}", path.to_string_lossy())))
+}
+
+fn get_stream() -> Result<Rc<RefCell<Stream>>> {
+    STREAM.with(|s| -> Result<_> {
+        let mut stream = s.borrow_mut();
+        if stream.is_none() {
+            *stream = Some(Rc::new(RefCell::new(connect_stream()?)));
+        }
+        Ok(stream.as_ref().unwrap().clone())
+    })
+}
+
+/// Drops the current connection so that the next message is sent through a new one
+pub fn reset_stream() {
+    STREAM.with(|s| {
+        s.borrow_mut().take();
+    });
+}
+
+/// Whether the error is caused by a (probably) temporary transport issue, in which case the
+/// request is worth retrying using a new connection
+pub fn is_transient_error(error: &Error) -> bool {
+    use std::io::ErrorKind;
+    error.chain().any(|e| {
+        if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+            matches!(
+                io_error.kind(),
+                ErrorKind::BrokenPipe
+                    | ErrorKind::WouldBlock
+                    | ErrorKind::Interrupted
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::TimedOut
+                    | ErrorKind::UnexpectedEof
+            )
+        } else {
+            false
+        }
+    })
 }
 
 pub trait MessengingUtilsTrait {
@@ -179,6 +2029,7 @@ impl MessengingUtilsTrait for MessengingUtils {
 
     fn send_message(request: String) -> Result<()> {
         debug!("SEND: {}", request);
+        trace_message("SEND", &request);
         let stream_rc = get_stream()?;
         let mut stream = stream_rc.borrow_mut();
         stream.write_all(request.as_bytes())?;
@@ -203,22 +2054,140 @@ impl MessengingUtilsTrait for MessengingUtils {
     }
 }
 
+/// How long to wait for the rest of a response once KeePassXC has started sending it. There's no
+/// limit on the first byte though as KeePassXC may be waiting for the user to confirm access.
+#[cfg(unix)]
+const RESPONSE_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 impl MessengingUtilsInternalTrait for MessengingUtils {
     fn read_to_end() -> Result<String> {
         let stream_rc = get_stream()?;
         let mut stream = stream_rc.borrow_mut();
-        let mut response = String::new();
-        const BUF_SIZE: usize = 128;
-        let mut buf = [0u8; BUF_SIZE];
-        loop {
-            let len = stream.read(&mut buf)?;
-            response.push_str(str::from_utf8(&buf[0..len]).unwrap());
-            if len < BUF_SIZE {
-                break;
+        read_json(&mut stream)
+    }
+}
+
+/// Reads until the data holds a complete JSON, waiting for the first byte as long as the read
+/// timeout of the stream allows
+fn read_json(stream: &mut Stream) -> Result<String> {
+    let mut response = Vec::new();
+    const BUF_SIZE: usize = 4096;
+    let mut buf = [0u8; BUF_SIZE];
+    // large responses (e.g. get-logins of big databases) may arrive in several reads, so keep
+    // reading until the buffer holds a complete JSON
+    let result = loop {
+        let len = match stream.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => break Err(Error::from(e).context("Failed to read response")),
+        };
+        if len == 0 {
+            let message = if response.is_empty() {
+                "Connection closed by KeePassXC"
+            } else {
+                "Connection closed by KeePassXC before the response is complete"
+            };
+            break Err(Error::from(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                message,
+            )));
+        }
+        response.extend_from_slice(&buf[0..len]);
+        if !is_incomplete_json(&response) {
+            break Ok(());
+        }
+        debug!(
+            "Incomplete response ({} bytes so far), reading on",
+            response.len()
+        );
+        #[cfg(unix)]
+        {
+            if let Err(e) = stream.set_read_timeout(Some(RESPONSE_READ_TIMEOUT)) {
+                break Err(Error::from(e).context("Failed to set read timeout"));
+            }
+        }
+    };
+    #[cfg(unix)]
+    stream.set_read_timeout(None)?;
+    result?;
+    let response =
+        String::from_utf8(response).context("Response from KeePassXC is not valid UTF-8")?;
+    debug!("RECV: {}", response);
+    trace_message("RECV", &response);
+    Ok(response)
+}
+
+#[cfg(unix)]
+fn set_stream_read_timeout(
+    stream: &mut Stream,
+    timeout: Option<std::time::Duration>,
+) -> Result<()> {
+    stream.set_read_timeout(timeout)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_stream_read_timeout(
+    stream: &mut Stream,
+    timeout: Option<std::time::Duration>,
+) -> Result<()> {
+    stream.set_read_timeout(timeout);
+    Ok(())
+}
+
+/// Whether KeePassXC has broadcast that a database is unlocked in the message(s), which it sends to
+/// all connected clients unsolicited
+fn is_unlock_signal(message: &str) -> bool {
+    cut_jsons(message).iter().any(|json| {
+        serde_json::from_str::<serde_json::Value>(json)
+            .map(|value| value["action"] == "database-unlocked")
+            .unwrap_or(false)
+    })
+}
+
+/// Waits up to the timeout for KeePassXC to signal that a database has been unlocked, so that the
+/// request can go ahead the moment it is instead of at the next poll. Returns whether it did
+pub fn wait_for_unlock_signal(timeout: std::time::Duration) -> Result<bool> {
+    let _guard = InterruptGuard::new();
+    let stream_rc = get_stream()?;
+    let mut stream = stream_rc.borrow_mut();
+    let deadline = std::time::Instant::now() + timeout;
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        if remaining == std::time::Duration::from_secs(0) {
+            break Ok(false);
+        }
+        if let Err(e) = set_stream_read_timeout(&mut stream, Some(remaining)) {
+            break Err(e);
+        }
+        match read_json(&mut stream) {
+            Ok(message) if is_unlock_signal(&message) => break Ok(true),
+            Ok(message) => {
+                debug!("Ignored message while waiting for unlock: {}", message);
             }
+            Err(e) if is_read_timeout(&e) => break Ok(false),
+            Err(e) => break Err(e),
         }
-        debug!("RECV: {}", response);
-        Ok(response)
+    };
+    set_stream_read_timeout(&mut stream, None)?;
+    check_interrupted()?;
+    result
+}
+
+fn is_read_timeout(error: &Error) -> bool {
+    use std::io::ErrorKind;
+    error.chain().any(|e| {
+        e.downcast_ref::<std::io::Error>()
+            .map(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut))
+            .unwrap_or(false)
+    })
+}
+
+/// Whether the data ends before the (first) JSON in it does, i.e. more is to be read. Responses that
+/// are malformed otherwise are deemed complete and left to be reported by the parser.
+fn is_incomplete_json(response: &[u8]) -> bool {
+    match serde_json::from_slice::<serde_json::Value>(response) {
+        Ok(_) => false,
+        Err(e) => e.is_eof(),
     }
 }
 
@@ -305,26 +2274,73 @@ pub fn generate_secret_key() -> SecretKey {
     SecretKey::generate(&mut rng)
 }
 
+/// Fixed key of `rclone obscure`, which only prevents passwords from being read at a glance
+const RCLONE_OBSCURE_KEY: [u8; 32] = [
+    0x9c, 0x93, 0x5b, 0x48, 0x73, 0x0a, 0x55, 0x4d, 0x6b, 0xfd, 0x7c, 0x63, 0xc8, 0x86, 0xa9, 0x2b,
+    0xd3, 0x90, 0x19, 0x8e, 0xb8, 0x12, 0x8a, 0xfb, 0xf4, 0xde, 0x16, 0x2b, 0x8b, 0x95, 0xf6, 0x38,
+];
+
+/// Encodes the password like `rclone obscure`, i.e. AES-256-CTR with a random IV, base64url-encoded
+/// without padding
+pub fn rclone_obscure<T: AsRef<str>>(password: T) -> String {
+    use rand::RngCore;
+    let mut iv = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut iv);
+    rclone_obscure_with_iv(password.as_ref(), iv)
+}
+
+fn rclone_obscure_with_iv(password: &str, iv: [u8; 16]) -> String {
+    use aes::block_cipher::generic_array::GenericArray;
+    use aes::{Aes256, BlockCipher, NewBlockCipher};
+    let cipher = Aes256::new(GenericArray::from_slice(&RCLONE_OBSCURE_KEY));
+    let mut counter = iv;
+    let mut result = iv.to_vec();
+    for chunk in password.as_bytes().chunks(16) {
+        let mut keystream = GenericArray::clone_from_slice(&counter);
+        cipher.encrypt_block(&mut keystream);
+        result.extend(chunk.iter().zip(keystream.iter()).map(|(b, k)| b ^ k));
+        // the whole IV is a big-endian counter, as in Go's cipher.NewCTR
+        for byte in counter.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+    base64::encode_config(&result, base64::URL_SAFE_NO_PAD)
+}
+
+thread_local!(static CLIENT_BOX: RefCell<Option<Rc<SalsaBox>>> = RefCell::new(None));
+
 pub fn get_client_box(
     host_public_key: Option<&PublicKey>,
     client_secret_key: Option<&SecretKey>,
 ) -> Result<Rc<SalsaBox>> {
-    thread_local!(static CLIENT_BOX: OnceCell<Rc<SalsaBox>> = OnceCell::new());
-    Ok(CLIENT_BOX.with(|cb| -> Result<_> {
-        Ok(cb.get_or_try_init(|| -> Result<_> {
+    CLIENT_BOX.with(|cb| -> Result<_> {
+        let mut client_box = cb.borrow_mut();
+        if client_box.is_none() {
             let client_secret_key = client_secret_key.ok_or_else(||
                 anyhow!("get_client_box() is called before client secret key is available, this shouldn't happen")
             )?;
             let host_public_key = host_public_key.ok_or_else(||
                 anyhow!("get_client_box() is called before host public key is available, this shouldn't happen")
             )?;
-            Ok(Rc::new(SalsaBox::new(host_public_key, client_secret_key)))
-        })?.clone())
-    })?)
+            *client_box = Some(Rc::new(SalsaBox::new(host_public_key, client_secret_key)));
+        }
+        Ok(client_box.as_ref().unwrap().clone())
+    })
+}
+
+/// Discards the current session keys, get_client_box() must then be called with new keys
+pub fn reset_client_box() {
+    CLIENT_BOX.with(|cb| {
+        cb.borrow_mut().take();
+    });
 }
 
 type NaClNonce = generic_array::GenericArray<u8, generic_array::typenum::U24>;
 
+/// Generates a random nonce, which is also returned in base64
 pub fn nacl_nonce() -> (NaClNonce, String) {
     let mut rng = rand::thread_rng();
     let nonce = crypto_box::generate_nonce(&mut rng);
@@ -332,9 +2348,11 @@ pub fn nacl_nonce() -> (NaClNonce, String) {
     (nonce, nonce_b64)
 }
 
+/// Encrypts the message as JSON with the keys of the current session, and returns it in base64
 pub fn to_encrypted_json<M: serde::Serialize>(request: &M, nonce: &NaClNonce) -> Result<String> {
     let json = serde_json::to_string(request)?;
     debug!("ENC : {}", json);
+    trace_message("ENC ", &json);
     let client_box = get_client_box(None, None)?;
     let encrypted = client_box
         .encrypt(&nonce, json.as_bytes())
@@ -343,6 +2361,7 @@ pub fn to_encrypted_json<M: serde::Serialize>(request: &M, nonce: &NaClNonce) ->
     Ok(encrypted)
 }
 
+/// Decrypts a base64 message from KeePassXC with the keys of the current session
 pub fn to_decrypted_json<T: AsRef<str>>(encrypted_b64: T, nonce: T) -> Result<String> {
     let bytes = base64::decode(encrypted_b64.as_ref())?;
     let client_box = get_client_box(None, None)?;
@@ -354,6 +2373,7 @@ pub fn to_decrypted_json<T: AsRef<str>>(encrypted_b64: T, nonce: T) -> Result<St
         .map_err(|_| CryptionError(false))?;
     let json = String::from_utf8(decrypted_json)?;
     debug!("DEC : {}", json);
+    trace_message("DEC ", &json);
     Ok(json)
 }
 
@@ -588,4 +2608,238 @@ mod tests {
             "Decrypted string differs from original JSON"
         );
     }
+
+    #[test]
+    fn test_06_transient_errors() {
+        use std::io::{Error as IoError, ErrorKind};
+        let broken_pipe = Error::from(IoError::new(ErrorKind::BrokenPipe, "mock"));
+        assert!(is_transient_error(&broken_pipe));
+        let with_context = Error::from(IoError::new(ErrorKind::WouldBlock, "mock"))
+            .context("Failed to read from socket");
+        assert!(is_transient_error(&with_context));
+        let not_found = Error::from(IoError::new(ErrorKind::NotFound, "mock"));
+        assert!(!is_transient_error(&not_found));
+        assert!(!is_transient_error(&anyhow!("mock")));
+    }
+
+    #[test]
+    fn test_07_expand_path() {
+        std::env::set_var("GIT_CREDENTIAL_KEEPASSXC_TEST_DIR", "/run/user/1000");
+        assert_eq!(
+            expand_path("$GIT_CREDENTIAL_KEEPASSXC_TEST_DIR/app/kpxc_server").unwrap(),
+            PathBuf::from("/run/user/1000/app/kpxc_server")
+        );
+        assert_eq!(
+            expand_path("${GIT_CREDENTIAL_KEEPASSXC_TEST_DIR}_x/$").unwrap(),
+            PathBuf::from("/run/user/1000_x/$")
+        );
+        let home = directories_next::BaseDirs::new()
+            .unwrap()
+            .home_dir()
+            .to_owned();
+        assert_eq!(
+            expand_path("~/Library/kpxc_server").unwrap(),
+            home.join("Library/kpxc_server")
+        );
+        assert_eq!(
+            expand_path("/tmp/~/kpxc_server").unwrap(),
+            PathBuf::from("/tmp/~/kpxc_server")
+        );
+        assert!(expand_path("$GIT_CREDENTIAL_KEEPASSXC_TEST_UNSET/kpxc_server").is_err());
+        assert!(expand_path("${GIT_CREDENTIAL_KEEPASSXC_TEST_DIR").is_err());
+    }
+
+    #[test]
+    fn test_08_redact_message() {
+        let mut message: serde_json::Value = serde_json::from_str(
+            r#"{"action":"get-logins","nonce":"foo","count":1,"entries":[{"login":"bar","password":"baz","stringFields":[{"KPH: token":"qux"}]}],"error":null}"#,
+        )
+        .unwrap();
+        redact_message(&mut message);
+        assert_eq!(
+            message.to_string(),
+            r#"{"action":"get-logins","count":1,"entries":[{"login":"bar","password":"<redacted>","stringFields":[{"KPH: token":"<redacted>"}]}],"error":null,"nonce":"<redacted>"}"#
+        );
+    }
+
+    #[test]
+    fn test_09_incomplete_json() {
+        // cut anywhere, including in the middle of the multi-byte é
+        let response = r#"{"action":"get-logins","name":"café","entries":[{"a":1}]}"#;
+        let bytes = response.as_bytes();
+        assert!(!is_incomplete_json(bytes));
+        for end in 1..bytes.len() {
+            assert!(is_incomplete_json(&bytes[..end]), "{} bytes", end);
+        }
+        assert!(!is_incomplete_json(br#"{"a":1}{"b":2}"#));
+        assert!(!is_incomplete_json(br#"{"a":}"#));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_10_request_lock() {
+        let url = format!(
+            "https://example.com/test_10_request_lock/{}",
+            std::process::id()
+        );
+        let timeout = std::time::Duration::from_millis(200);
+        let lock = lock_request(&url, timeout);
+        assert!(lock.is_some());
+        assert!(lock_request(&url, timeout).is_none());
+        assert!(lock_request(format!("{}/other", url), timeout).is_some());
+        drop(lock);
+        assert!(lock_request(&url, timeout).is_some());
+    }
+
+    #[test]
+    fn test_11_sha256_hex() {
+        assert_eq!(
+            sha256_hex(&b"abc"[..]).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_12_get_ppid() {
+        let pid = std::process::id() as libc::pid_t;
+        assert_eq!(get_ppid(pid).unwrap(), unsafe { libc::getppid() });
+    }
+
+    #[test]
+    fn test_13_rclone_obscure() {
+        // test vectors from rclone
+        assert_eq!(
+            rclone_obscure_with_iv("", *b"aaaaaaaaaaaaaaaa"),
+            "YWFhYWFhYWFhYWFhYWFhYQ"
+        );
+        assert_eq!(
+            rclone_obscure_with_iv("potato", *b"aaaaaaaaaaaaaaaa"),
+            "YWFhYWFhYWFhYWFhYWFhYXMaGgIlEQ"
+        );
+        assert_eq!(
+            rclone_obscure_with_iv("potato", *b"bbbbbbbbbbbbbbbb"),
+            "YmJiYmJiYmJiYmJiYmJiYp3gcEWbAw"
+        );
+    }
+
+    #[test]
+    fn test_14_parse_app_exec_link() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&0x8000_001bu32.to_le_bytes());
+        buffer.extend_from_slice(&[0u8; 4]);
+        buffer.extend_from_slice(&3u32.to_le_bytes());
+        for s in &[
+            "PythonSoftwareFoundation.Python.3.11_qbz5n2kfra8p0",
+            "PythonSoftwareFoundation.Python.3.11_qbz5n2kfra8p0!Python",
+            r"C:\Program Files\WindowsApps\Python\python3.11.exe",
+            "0",
+        ] {
+            for c in s.encode_utf16().chain(std::iter::once(0)) {
+                buffer.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+        assert_eq!(
+            parse_app_exec_link(&buffer),
+            Some(PathBuf::from(
+                r"C:\Program Files\WindowsApps\Python\python3.11.exe"
+            ))
+        );
+        // symbolic link
+        buffer[..4].copy_from_slice(&0xa000_000cu32.to_le_bytes());
+        assert_eq!(parse_app_exec_link(&buffer), None);
+    }
+
+    #[test]
+    fn test_15_caller_path_matches() {
+        if cfg!(windows) {
+            assert!(caller_path_matches(
+                "C:/Program Files/Git/cmd/git.exe",
+                r"c:\program files\git\cmd\GIT.EXE"
+            ));
+        } else {
+            assert!(caller_path_matches("/usr/bin/git", "/usr/bin/git"));
+            assert!(!caller_path_matches("/usr/bin/git", "/usr/bin/Git"));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_16_canonicalize_caller_path() {
+        let dir = std::env::temp_dir().join(format!(
+            "{}.test_16_{}",
+            clap::crate_name!(),
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("git");
+        let link = dir.join("git-link");
+        std::fs::write(&target, b"").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let canonical_target = std::fs::canonicalize(&target).unwrap();
+        assert_eq!(canonicalize_caller_path(&link), canonical_target);
+        assert_eq!(
+            canonicalize_caller_path(dir.join("missing")),
+            dir.join("missing")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_17_no_match_cache() {
+        let key = format!("test_17\nhttps://example.com/{}", std::process::id());
+        let ttl = std::time::Duration::from_secs(60);
+        assert!(!is_no_match_cached(&key, "foo", ttl));
+        cache_no_match(&key, "foo").unwrap();
+        assert!(is_no_match_cached(&key, "foo", ttl));
+        assert!(!is_no_match_cached(&key, "bar", ttl));
+        assert!(!is_no_match_cached(
+            &key,
+            "foo",
+            std::time::Duration::from_secs(0)
+        ));
+        clear_no_match(&key);
+        assert!(!is_no_match_cached(&key, "foo", ttl));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_18_resolve_executable() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!(
+            "{}.test_18_{}",
+            clap::crate_name!(),
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let executable = dir.join("git");
+        let data = dir.join("data");
+        std::fs::write(&executable, b"").unwrap();
+        std::fs::write(&data, b"").unwrap();
+        std::fs::set_permissions(&executable, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::set_permissions(&data, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            resolve_executable(dir.join("..").join(dir.file_name().unwrap()).join("git")).unwrap(),
+            executable
+        );
+        assert!(resolve_executable(&data).is_err());
+        assert!(resolve_executable(dir.join("missing")).is_err());
+        assert!(resolve_executable("sh").unwrap().is_absolute());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_19_unlock_signal() {
+        assert!(is_unlock_signal(r#"{"action":"database-unlocked"}"#));
+        assert!(is_unlock_signal(
+            r#"{"action":"database-locked"}{"action":"database-unlocked"}"#
+        ));
+        assert!(!is_unlock_signal(r#"{"action":"database-locked"}"#));
+        assert!(!is_unlock_signal(
+            r#"{"action":"get-logins","message":"database-unlocked"}"#
+        ));
+        assert!(is_read_timeout(&Error::from(std::io::Error::from(
+            std::io::ErrorKind::WouldBlock
+        ))));
+    }
 }